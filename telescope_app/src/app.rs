@@ -1,29 +1,122 @@
-use std::{net::SocketAddr, path::PathBuf, sync::{Arc, RwLock}};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::{Arc, RwLock}};
 
-use egui::{Color32, Id, Modal, Rect, ScrollArea};
+use egui::{Color32, Id, Modal, ScrollArea};
 use egui_commonmark::{commonmark, commonmark_str, CommonMarkCache};
 use egui_file_dialog::FileDialog;
 use egui_taffy::{taffy::Style, tui, virtual_tui::{VirtualGridRowHelper, VirtualGridRowHelperParams}, Tui, TuiBuilderLogic};
 use egui_taffy::taffy::prelude::*;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use telescope_core::{certs::CertDerivable, config::Config, resource::{Flow, FlowContent, RequestMeta}};
+use telescope_core::{certs::CertDerivable, codegen::{request_to_code, CodeLanguage}, config::{BodyStorageConfig, ClientCertRule, Config, OutOfScopeAction, ScopeRule, UpstreamProxy, UpstreamProxyKind, UpstreamRule}, connection::ConnectionInfo, database::load_websocket_messages, intercept::InterceptQueue, matching::optional_wildcard_match, resource::{get_current_time, headers_to_string, FileResource, Flow, FlowContent, MemoryResource, RequestMeta, Resource, WebSocketDirection, WebSocketMessage}, timing::FlowTimings};
 use tokio::{runtime::Runtime, sync::watch};
-use crate::{config, oobe::OOBEStep, settings::{self, resolve_user_data_directory}, states::DialogUiState, utils::color_for_status};
+use crate::{config, flow_files::{export_flows, import_flows, EXPORT_FORMATS, IMPORT_FORMATS}, intercept::{InterceptEditor, WebSocketComposer, WebSocketInterceptEditor}, oobe::OOBEStep, settings::{self, resolve_user_data_directory}, states::DialogUiState, utils::{color_for_status, format_bytes, payload_preview, payload_text}};
+
+// bodies can be hundreds of megabytes on disk, only show the start
+const BODY_PREVIEW_LEN: usize = 256 * 1024;
+
+// the start of the selected flow's bodies, reading those can mean going to disk or the project database
+#[derive(Default)]
+pub struct BodyPreviews {
+    flow_id: String,
+    // body size each was read at, a body that's still streaming keeps growing
+    request: Option<(u64, Vec<u8>)>,
+    response: Option<(u64, Vec<u8>)>,
+}
+
+impl BodyPreviews {
+    fn is_fresh(preview: &Option<(u64, Vec<u8>)>, body: Option<&Resource>) -> bool {
+        match (preview, body) {
+            (Some((size, _)), Some(body)) => *size == body.size(),
+            (None, None) => true,
+            _ => false
+        }
+    }
+
+    // takes the storage lock only to copy out the bodies that need reading, the reading happens after
+    fn refresh(&mut self, flow_storage: &RwLock<telescope_core::proxy::FlowStorage>, flow_id: &str) {
+        let stale = {
+            let flow_storage = flow_storage.read().unwrap();
+            let Some(flow) = flow_storage.get_flow(flow_id) else {
+                return;
+            };
+            let http_pair = flow.content.http_pair();
+            let request = Some(&http_pair.request.body);
+            let response = http_pair.response.as_ref().map(|response| &response.body);
+            // bodies in memory are cheap to cut down right away, the others are only a path to read from
+            let copy = |body: &Resource| (body.size(), match body {
+                Resource::Memory(_) | Resource::String(_) => Resource::Memory(MemoryResource::new(body.prefix(BODY_PREVIEW_LEN))),
+                body => body.clone()
+            });
+            let same_flow = self.flow_id == flow_id;
+            let request = match same_flow && Self::is_fresh(&self.request, request) {
+                true => None,
+                false => Some(request.map(copy))
+            };
+            let response = match same_flow && Self::is_fresh(&self.response, response) {
+                true => None,
+                false => Some(response.map(copy))
+            };
+            (request, response)
+        };
+        let read = |body: Option<(u64, Resource)>| body.map(|(size, body)| (size, body.prefix(BODY_PREVIEW_LEN)));
+        self.flow_id = flow_id.to_string();
+        if let Some(request) = stale.0 {
+            self.request = read(request);
+        }
+        if let Some(response) = stale.1 {
+            self.response = read(response);
+        }
+    }
+
+    fn request(&self) -> &[u8] {
+        self.request.as_ref().map(|(_, preview)| preview.as_slice()).unwrap_or_default()
+    }
+
+    fn response(&self) -> &[u8] {
+        self.response.as_ref().map(|(_, preview)| preview.as_slice()).unwrap_or_default()
+    }
+}
 
 pub struct ProxyUiState {
+    pub intercept_editor: Option<InterceptEditor>,
+    pub websocket_intercept_editor: Option<WebSocketInterceptEditor>,
+    pub websocket_composer: WebSocketComposer,
+    pub selected_flow: Option<String>,
+    // index into the selected websocket flow's messages
+    pub selected_websocket_message: Option<usize>,
+    pub detail_tab: DetailTab,
+    // flow list filters, wildcard on the client address and an exact connection id
+    pub client_filter: String,
+    pub connection_filter: String,
+    pub body_previews: BodyPreviews,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetailTab {
+    Messages,
+    Connection,
+    Timing,
 }
 
 impl Default for ProxyUiState {
     fn default() -> Self {
         Self {
-
+            intercept_editor: None,
+            websocket_intercept_editor: None,
+            websocket_composer: WebSocketComposer::default(),
+            selected_flow: None,
+            selected_websocket_message: None,
+            detail_tab: DetailTab::Messages,
+            client_filter: String::new(),
+            connection_filter: String::new(),
+            body_previews: BodyPreviews::default()
         }
     }
 }
 
 pub enum UiState {
     OOBE(OOBEStep),
-    Proxy(ProxyUiState),
+    Proxy(Box<ProxyUiState>),
 }
 
 #[derive(Default, serde::Deserialize, serde::Serialize)]
@@ -35,7 +128,11 @@ pub struct AppFlags {
 pub enum PaneState {
     OOBE,
     Blank,
-    FlowList
+    FlowList,
+    FlowDetail,
+    Intercept,
+    Scope,
+    Upstream
 }
 
 impl Default for PaneState {
@@ -67,6 +164,8 @@ pub struct AppState {
     pub proxy: Option<telescope_core::proxy::TelescopeProxyRef>,
     #[serde(skip)]
     pub flow_storage: Option<Arc<RwLock<telescope_core::proxy::FlowStorage>>>,
+    #[serde(skip)]
+    pub intercept_queue: Option<Arc<RwLock<InterceptQueue>>>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -76,6 +175,8 @@ pub enum FlowDetail {
     Path,
     Host,
     StatusCode,
+    Client,
+    Connection,
 }
 
 impl FlowDetail {
@@ -86,11 +187,13 @@ impl FlowDetail {
             FlowDetail::Path => "Path",
             FlowDetail::Host => "Host",
             FlowDetail::StatusCode => "Status Code",
+            FlowDetail::Client => "Client",
+            FlowDetail::Connection => "Conn",
         }
     }
 }
 
-pub const FLOW_DETAILS_ORDER_DEFAULT: [FlowDetail; 4] = [FlowDetail::Path, FlowDetail::Method, FlowDetail::Host, FlowDetail::StatusCode];
+pub const FLOW_DETAILS_ORDER_DEFAULT: [FlowDetail; 6] = [FlowDetail::Path, FlowDetail::Method, FlowDetail::Host, FlowDetail::StatusCode, FlowDetail::Client, FlowDetail::Connection];
 
 impl Default for AppState {
     fn default() -> Self {
@@ -104,7 +207,8 @@ impl Default for AppState {
             runtime: None,
             flags: AppFlags::default(),
             proxy: None,
-            flow_storage: None
+            flow_storage: None,
+            intercept_queue: None
         }
     }
 }
@@ -128,66 +232,106 @@ impl AppState {
             tui.colored_label(Color32::from_rgb(100, 100, 100), "Pending...")
         };
 
-        if let FlowContent::RequestResponse(httppair) = &flow.content {
-            let meta = &httppair.request.meta;
-            let request: &RequestMeta = meta.unwrap_request_ref();
-            let is_proxy_internal = request.is_proxy_client_connection();
-            let label_resp = match flow_detail {
-                FlowDetail::URL => {
-                    tui.label(request.url.as_str(), )
-                },
-                FlowDetail::Method => {
-                    tui.label(request.method.as_str())
-                },
-                FlowDetail::Path => {
-                    tui.label(request.url.path())
-                },
-                FlowDetail::Host => {
-                    if is_proxy_internal {
-                        not_applicable(tui)
-                    } else {
-                        tui.label(request.url.host_str().unwrap_or("undefined"))
-                    }
-                },
-                _ => {
-                    if let Some(response) = &httppair.response {
-                        match flow_detail {
-                            FlowDetail::StatusCode => {
-                                let resp_meta = response.meta.unwrap_response_ref();
-                                match flow_detail {
-                                    FlowDetail::StatusCode => {
-                                        tui.colored_label(color_for_status(resp_meta.status), format!("{}", resp_meta.status))
-                                    },
-                                    _ => {
-                                        not_applicable(tui)
-                                    }
-                                }
-                            },
-                            _ => {
-                                not_applicable(tui)
-                            }
-                        }
-                    } else {
-                        if !is_proxy_internal {
+        match flow_detail {
+            FlowDetail::Client => {
+                match flow.connection.client_addr {
+                    Some(client_addr) => tui.label(client_addr.to_string()),
+                    None => not_applicable(tui)
+                };
+                return;
+            },
+            FlowDetail::Connection => {
+                match flow.connection.connection_id {
+                    Some(connection_id) => tui.label(format!("#{}", connection_id)),
+                    None => not_applicable(tui)
+                };
+                return;
+            },
+            _ => {}
+        }
+
+        match (&flow.content, flow_detail) {
+            (FlowContent::WebSocket(websocket), FlowDetail::StatusCode) => {
+                tui.colored_label(Color32::from_rgb(0, 155, 255), format!("WS ({})", websocket.message_count()));
+                return;
+            },
+            (FlowContent::Tunnel(tunnel), FlowDetail::StatusCode) => {
+                let color = if tunnel.error.is_some() { Color32::from_rgb(255, 0, 0) } else { Color32::from_rgb(155, 100, 255) };
+                tui.colored_label(color, format!("TCP ({})", format_bytes(tunnel.bytes_sent + tunnel.bytes_received)));
+                return;
+            },
+            (FlowContent::Tunnel(tunnel), FlowDetail::Host) => {
+                tui.label(tunnel.target.as_str());
+                return;
+            },
+            (FlowContent::RequestResponse(http_pair), FlowDetail::StatusCode) if http_pair.response.is_none() && flow.connection.error.is_some() => {
+                tui.colored_label(Color32::from_rgb(255, 0, 0), "Failed");
+                return;
+            },
+            _ => {}
+        }
+
+        let httppair = flow.content.http_pair();
+        let meta = &httppair.request.meta;
+        let request: &RequestMeta = meta.unwrap_request_ref();
+        let is_proxy_internal = request.is_proxy_client_connection();
+        let label_resp = match flow_detail {
+            FlowDetail::URL => {
+                tui.label(request.url.as_str(), )
+            },
+            FlowDetail::Method => {
+                tui.label(request.method.as_str())
+            },
+            FlowDetail::Path => {
+                tui.label(request.url.path())
+            },
+            FlowDetail::Host => {
+                if is_proxy_internal {
+                    not_applicable(tui)
+                } else {
+                    tui.label(request.url.host_str().unwrap_or("undefined"))
+                }
+            },
+            _ => {
+                if let Some(response) = &httppair.response {
+                    match flow_detail {
+                        FlowDetail::StatusCode => {
+                            let resp_meta = response.meta.unwrap_response_ref();
                             match flow_detail {
                                 FlowDetail::StatusCode => {
-                                    respond_pending(tui)
+                                    if flow.is_active {
+                                        // response body is still streaming in
+                                        tui.colored_label(color_for_status(resp_meta.status), format!("{} ...", resp_meta.status))
+                                    } else {
+                                        tui.colored_label(color_for_status(resp_meta.status), format!("{}", resp_meta.status))
+                                    }
                                 },
                                 _ => {
                                     not_applicable(tui)
                                 }
                             }
-                        } else {
-                            // for now
+                        },
+                        _ => {
                             not_applicable(tui)
                         }
                     }
+                } else {
+                    if !is_proxy_internal {
+                        match flow_detail {
+                            FlowDetail::StatusCode => {
+                                respond_pending(tui)
+                            },
+                            _ => {
+                                not_applicable(tui)
+                            }
+                        }
+                    } else {
+                        // for now
+                        not_applicable(tui)
+                    }
                 }
-            };
-        }else{
-            // futureproofing
-            tui.label("NA");
-        }
+            }
+        };
     }
 
     pub fn ui(&mut self, ui: &mut egui::Ui, pane: &mut PaneState) {
@@ -197,8 +341,29 @@ impl AppState {
                 // ui.label(format!("avali width: {}", ui.available_width()));
                 ui.set_width(ui.available_width());
                 if let UiState::Proxy(proxy_ui_state) = &mut self.state {
+                    ui.horizontal(|ui| {
+                        ui.label("Client:");
+                        ui.add(egui::TextEdit::singleline(&mut proxy_ui_state.client_filter).hint_text("*").desired_width(140.0));
+                        ui.label("Connection:");
+                        ui.add(egui::TextEdit::singleline(&mut proxy_ui_state.connection_filter).hint_text("any").desired_width(60.0));
+                    });
+                }
+                if let UiState::Proxy(proxy_ui_state) = &self.state {
+                    let selected_flow = proxy_ui_state.selected_flow.clone();
+                    let mut clicked_flow = None;
                     if let Some(flow_storage) = &self.flow_storage {
                         let flow_storage = flow_storage.read().unwrap();
+                        // out of scope flows recorded in hide mode are skipped, rule changes apply to old flows too
+                        let visible_flows: Vec<usize> = match &self.config_watch {
+                            Some((_, config)) if config.borrow().scope.enabled => {
+                                let scope = config.borrow().scope.clone();
+                                (0..flow_storage.len())
+                                    .filter(|idx| flow_storage.flow_by_index(*idx).is_some_and(|flow| scope.should_show(flow.content.http_pair().request.meta.unwrap_request_ref())))
+                                    .collect()
+                            },
+                            _ => (0..flow_storage.len()).collect()
+                        };
+                        let visible_flows = filter_by_connection(visible_flows, &flow_storage, &proxy_ui_state.client_filter, &proxy_ui_state.connection_filter);
                         /*if flow_storage.len() == 0 {
                             ui.label("No flows recorded yet. Connect the proxy to see flows..");
                         }*/
@@ -238,7 +403,7 @@ impl AppState {
                                 // println!("rect: {}", tui.root_rect());
                                 tui.style(Style {
                                     display: egui_taffy::taffy::Display::Grid,
-                                    grid_template_columns: vec![fr(4.), fr(1.), fr(2.), fr(0.5)], // update this when you change the cols
+                                    grid_template_columns: vec![fr(4.), fr(1.), fr(2.), fr(0.5), fr(1.2), fr(0.4)], // update this when you change the cols
                                     // gap: length(8.),
                                     overflow: egui_taffy::taffy::Point {
                                         x: egui_taffy::taffy::Overflow::Clip,
@@ -254,13 +419,14 @@ impl AppState {
                                 }).add_with_border(|tui| {
                                     VirtualGridRowHelper::show(VirtualGridRowHelperParams {
                                         header_row_count: 1,
-                                        row_count: flow_storage.len(),
+                                        row_count: visible_flows.len(),
                                     }, tui, |tui, info| {
                                         let mut idgen = info.id_gen();
                                         let mut_grid_row_param = info.grid_row_setter();
-                                        let flow = flow_storage.flow_by_index(info.idx).unwrap();
+                                        let flow = flow_storage.flow_by_index(visible_flows[info.idx]).unwrap();
+                                        let selected = selected_flow.as_ref().is_some_and(|selected_flow| *selected_flow == flow.id);
                                         for flow_detail in FLOW_DETAILS_ORDER_DEFAULT.iter() {
-                                            let _ = tui
+                                            let cell = tui
                                                 .id(idgen())
                                                 .wrap_mode(egui::TextWrapMode::Truncate)
                                                 .mut_style(&mut_grid_row_param)
@@ -275,9 +441,13 @@ impl AppState {
                                                         y: egui_taffy::taffy::Overflow::Scroll,
                                                     };*/
                                                 })
-                                                .button(|tui| {
+                                                .selectable(selected, |tui| {
                                                     self.ui_for_grid(tui, flow_detail, flow);
                                                 });
+                                            if cell.clicked() {
+                                                clicked_flow = Some(flow.get_id());
+                                            }
+                                            cell.context_menu(|ui| flow_context_menu(ui, flow));
                                         }
                                         
                                     });
@@ -301,14 +471,653 @@ impl AppState {
                     } else {
                         ui.label("Flow storage not loaded");
                     }
+                    if let (Some(clicked_flow), UiState::Proxy(proxy_ui_state)) = (clicked_flow, &mut self.state) {
+                        if proxy_ui_state.selected_flow.as_ref() != Some(&clicked_flow) {
+                            proxy_ui_state.selected_websocket_message = None;
+                        }
+                        proxy_ui_state.selected_flow = Some(clicked_flow);
+                    }
                 }
             },
+            PaneState::FlowDetail => {
+                self.flow_detail_ui(ui);
+            },
+            PaneState::Intercept => {
+                self.intercept_ui(ui);
+            },
+            PaneState::Scope => {
+                self.scope_ui(ui);
+            },
+            PaneState::Upstream => {
+                self.upstream_ui(ui);
+            },
             _ => {
 
             }
         }
     }
 
+    pub fn flow_detail_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(flow_storage) = &self.flow_storage else {
+            ui.label("Proxy not started");
+            return;
+        };
+        let UiState::Proxy(proxy_ui_state) = &mut self.state else {
+            return;
+        };
+        let Some(selected_flow) = &proxy_ui_state.selected_flow else {
+            ui.label("Select a flow to see its details.");
+            return;
+        };
+        let stored_messages = flow_storage.read().unwrap().get_flow(selected_flow).is_some_and(|flow| match &flow.content {
+            FlowContent::WebSocket(websocket) => websocket.stored_messages.as_ref().is_some_and(|stored| !stored.loading),
+            _ => false
+        });
+        if stored_messages {
+            load_websocket_messages(flow_storage.clone(), selected_flow.clone());
+        }
+        proxy_ui_state.body_previews.refresh(flow_storage, selected_flow);
+        let previews = &proxy_ui_state.body_previews;
+        let flow_storage = flow_storage.read().unwrap();
+        let Some(flow) = flow_storage.get_flow(selected_flow) else {
+            ui.label("This flow no longer exists.");
+            return;
+        };
+
+        let http_pair = flow.content.http_pair();
+        let request = &http_pair.request;
+        let request_meta = request.meta.unwrap_request_ref();
+        ui.label(format!("{} {} {}", request_meta.method, request_meta.url, request_meta.version));
+        if let Some(response) = &http_pair.response {
+            let response_meta = response.meta.unwrap_response_ref();
+            ui.colored_label(color_for_status(response_meta.status), format!("{} {}", response_meta.version, response_meta.status));
+            if flow.is_active && matches!(flow.content, FlowContent::RequestResponse(_)) {
+                ui.label("Response body is still streaming");
+            }
+        }
+
+        if let Some(error) = &flow.connection.error {
+            ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+        }
+
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut proxy_ui_state.detail_tab, DetailTab::Messages, "Messages");
+            ui.selectable_value(&mut proxy_ui_state.detail_tab, DetailTab::Connection, "Connection");
+            ui.selectable_value(&mut proxy_ui_state.detail_tab, DetailTab::Timing, "Timing");
+        });
+        ui.separator();
+        match proxy_ui_state.detail_tab {
+            DetailTab::Messages => {},
+            DetailTab::Connection => {
+                ScrollArea::vertical().id_salt("flow_connection").show(ui, |ui| {
+                    connection_ui(ui, &flow.connection);
+                });
+                return;
+            },
+            DetailTab::Timing => {
+                ScrollArea::vertical().id_salt("flow_timing").show(ui, |ui| {
+                    timing_ui(ui, &flow.timings);
+                });
+                return;
+            }
+        }
+
+        match &flow.content {
+            FlowContent::RequestResponse(_) => {
+                ScrollArea::vertical().id_salt("flow_detail").show(ui, |ui| {
+                    message_ui(ui, "Request", request, previews.request());
+                    if let Some(response) = &http_pair.response {
+                        message_ui(ui, "Response", response, previews.response());
+                    }
+                });
+            },
+            FlowContent::Tunnel(tunnel) => {
+                let duration = tunnel.closed_at.unwrap_or_else(get_current_time).saturating_sub(tunnel.opened_at());
+                ui.label(format!("Raw TCP tunnel ({}) to {}, {}", request_meta.method, tunnel.target, if flow.is_active { "open" } else { "closed" }));
+                egui::Grid::new("tunnel_detail").num_columns(2).show(ui, |ui| {
+                    ui.label("Sent");
+                    ui.label(format_bytes(tunnel.bytes_sent));
+                    ui.end_row();
+                    ui.label("Received");
+                    ui.label(format_bytes(tunnel.bytes_received));
+                    ui.end_row();
+                    ui.label("Duration");
+                    ui.label(format!("{:.1}s", duration as f64 / 1000.0));
+                    ui.end_row();
+                });
+                if let Some(error) = &tunnel.error {
+                    ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+                }
+            },
+            FlowContent::WebSocket(websocket) => {
+                egui::CollapsingHeader::new("Handshake").id_salt("websocket_handshake").show(ui, |ui| {
+                    message_ui(ui, "Request", request, previews.request());
+                    if let Some(response) = &http_pair.response {
+                        message_ui(ui, "Response", response, previews.response());
+                    }
+                });
+                let state = match (flow.is_active, websocket.closed_at) {
+                    (true, _) => "open",
+                    (false, Some(_)) => "closed",
+                    (false, None) => "never opened"
+                };
+                ui.label(format!("{} messages, {}", websocket.message_count(), state));
+                if websocket.stored_messages.is_some() {
+                    ui.label("Loading messages from the project...");
+                }
+                ui.separator();
+
+                let started_at = request_meta.created_at;
+                let row_height = ui.text_style_height(&egui::TextStyle::Body);
+                if flow.is_active {
+                    let composer = &mut proxy_ui_state.websocket_composer;
+                    ui.horizontal(|ui| {
+                        ui.selectable_value(&mut composer.direction, WebSocketDirection::ClientToServer, "To server");
+                        ui.selectable_value(&mut composer.direction, WebSocketDirection::ServerToClient, "To client");
+                        if ui.button("Send").clicked() {
+                            let sent = composer.payload.to_payload().and_then(|payload| {
+                                let message = WebSocketMessage::new(composer.direction, composer.payload.opcode, payload).to_message();
+                                match &self.proxy {
+                                    Some(proxy) => proxy.proxy.read().unwrap().inject_websocket_message(&flow.id, composer.direction, message),
+                                    None => Err("proxy not started".to_string())
+                                }
+                            });
+                            composer.error = sent.err();
+                        }
+                        if let Some(error) = &composer.error {
+                            ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+                        }
+                    });
+                    composer.payload.ui(ui, "websocket_composer_opcode");
+                    ui.separator();
+                }
+
+                ScrollArea::vertical().id_salt("websocket_messages").max_height(ui.available_height() / 2.0).auto_shrink([false, true]).show_rows(ui, row_height, websocket.messages.len(), |ui, rows| {
+                    for index in rows {
+                        let message = &websocket.messages[index];
+                        let arrow = match message.direction {
+                            WebSocketDirection::ClientToServer => "↑",
+                            WebSocketDirection::ServerToClient => "↓"
+                        };
+                        let marker = match (message.injected, message.dropped) {
+                            (true, _) => "[injected] ",
+                            (_, true) => "[dropped] ",
+                            _ => ""
+                        };
+                        let label = format!("{} {:>8.3}s {:<6} {:>7}B  {}{}",
+                            arrow,
+                            message.timestamp.saturating_sub(started_at) as f64 / 1000.0,
+                            message.opcode.as_str(),
+                            message.payload.len(),
+                            marker,
+                            payload_preview(&message.payload)
+                        );
+                        let mut text = egui::RichText::new(label).monospace();
+                        if message.dropped {
+                            text = text.color(Color32::from_rgb(100, 100, 100));
+                        }
+                        let selected = proxy_ui_state.selected_websocket_message == Some(index);
+                        if ui.add(egui::SelectableLabel::new(selected, text)).clicked() {
+                            proxy_ui_state.selected_websocket_message = Some(index);
+                        }
+                    }
+                });
+                ui.separator();
+
+                match proxy_ui_state.selected_websocket_message.and_then(|index| websocket.messages.get(index)) {
+                    Some(message) => {
+                        ScrollArea::vertical().id_salt("websocket_payload").show(ui, |ui| {
+                            let mut payload = payload_text(&message.payload);
+                            ui.add(egui::TextEdit::multiline(&mut payload).code_editor().interactive(false).desired_width(f32::INFINITY));
+                        });
+                    },
+                    None => {
+                        ui.label("Select a message to see its payload.");
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn scope_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(config_watch) = &self.config_watch else {
+            ui.label("Proxy not started");
+            return;
+        };
+
+        let mut scope_config = config_watch.1.borrow().scope.clone();
+        let mut changed = false;
+        ui.horizontal_wrapped(|ui| {
+            changed |= ui.checkbox(&mut scope_config.enabled, "Limit recording to scope").changed();
+            ui.label("Out of scope traffic:");
+            egui::ComboBox::from_id_salt("out_of_scope_action")
+                .selected_text(scope_config.out_of_scope.as_str())
+                .show_ui(ui, |ui| {
+                    for action in [OutOfScopeAction::Forward, OutOfScopeAction::Hide] {
+                        changed |= ui.selectable_value(&mut scope_config.out_of_scope, action, action.as_str()).changed();
+                    }
+                });
+            if ui.button("Save").clicked() {
+                match config_watch.1.borrow().save() {
+                    Ok(()) => info!("saved proxy config"),
+                    Err(e) => error!("{}", e)
+                }
+            }
+        });
+        ui.label("Patterns use * and ?, empty fields match anything. With no include rules everything not excluded is in scope.");
+        ui.separator();
+
+        let mut tls_passthrough = config_watch.1.borrow().tls_passthrough.join("\n");
+        egui::CollapsingHeader::new("TLS passthrough").id_salt("tls_passthrough").show(ui, |ui| {
+            ui.label("Host patterns, one per line. Tunnels to these hosts are relayed without being decrypted.");
+            if ui.add(egui::TextEdit::multiline(&mut tls_passthrough).code_editor().hint_text("*.pinned.example.com").desired_rows(3).desired_width(f32::INFINITY)).changed() {
+                // split instead of lines() so a fresh empty line survives the round trip
+                let patterns = tls_passthrough.split('\n').map(|pattern| pattern.to_string()).collect();
+                config_watch.0.send_modify(|config| {
+                    config.tls_passthrough = patterns;
+                });
+            }
+        });
+        ui.separator();
+
+        ScrollArea::vertical().id_salt("scope_rules").show(ui, |ui| {
+            for (title, rules) in [("Include", &mut scope_config.include), ("Exclude", &mut scope_config.exclude)] {
+                ui.horizontal(|ui| {
+                    ui.strong(title);
+                    if ui.button("Add rule").clicked() {
+                        rules.push(ScopeRule::default());
+                        changed = true;
+                    }
+                });
+                let mut removed = None;
+                egui::Grid::new(title).num_columns(6).striped(true).show(ui, |ui| {
+                    ui.label("");
+                    ui.label("Scheme");
+                    ui.label("Host");
+                    ui.label("Port");
+                    ui.label("Path");
+                    ui.end_row();
+                    for (idx, rule) in rules.iter_mut().enumerate() {
+                        changed |= ui.checkbox(&mut rule.enabled, "").changed();
+                        changed |= ui.add(egui::TextEdit::singleline(&mut rule.scheme).hint_text("*").desired_width(60.0)).changed();
+                        changed |= ui.add(egui::TextEdit::singleline(&mut rule.host).hint_text("*").desired_width(160.0)).changed();
+                        changed |= ui.add(egui::TextEdit::singleline(&mut rule.port).hint_text("*").desired_width(50.0)).changed();
+                        changed |= ui.add(egui::TextEdit::singleline(&mut rule.path).hint_text("*").desired_width(160.0)).changed();
+                        if ui.button("Remove").clicked() {
+                            removed = Some(idx);
+                        }
+                        ui.end_row();
+                    }
+                });
+                if let Some(idx) = removed {
+                    rules.remove(idx);
+                    changed = true;
+                }
+                ui.add_space(8.0);
+            }
+        });
+
+        if changed {
+            config_watch.0.send_modify(|config| {
+                config.scope = scope_config;
+            });
+        }
+    }
+
+    pub fn upstream_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(config_watch) = &self.config_watch else {
+            ui.label("Proxy not started");
+            return;
+        };
+
+        let mut upstream_config = config_watch.1.borrow().upstream.clone();
+        let mut client_certs = config_watch.1.borrow().client_certs.clone();
+        let mut upstream_tls = config_watch.1.borrow().upstream_tls.clone();
+        let mut changed = false;
+        let mut certs_changed = false;
+        let mut tls_changed = false;
+        ui.horizontal_wrapped(|ui| {
+            changed |= ui.checkbox(&mut upstream_config.enabled, "Use upstream proxies").changed();
+            if ui.button("Save").clicked() {
+                match config_watch.1.borrow().save() {
+                    Ok(()) => info!("saved proxy config"),
+                    Err(e) => error!("{}", e)
+                }
+            }
+        });
+        ui.label("Applies to new connections.");
+        ui.separator();
+
+        ScrollArea::vertical().id_salt("upstream_rules").show(ui, |ui| {
+            ui.strong("Default");
+            changed |= upstream_proxy_ui(ui, "upstream_default", &mut upstream_config.proxy);
+            ui.add_space(8.0);
+
+            ui.horizontal(|ui| {
+                ui.strong("Per host rules");
+                if ui.button("Add rule").clicked() {
+                    upstream_config.rules.push(UpstreamRule::default());
+                    changed = true;
+                }
+            });
+            ui.label("The first enabled rule matching the host wins, hosts without a match use the default.");
+            let mut removed = None;
+            for (idx, rule) in upstream_config.rules.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    changed |= ui.checkbox(&mut rule.enabled, "").changed();
+                    ui.label("Host:");
+                    changed |= ui.add(egui::TextEdit::singleline(&mut rule.host).hint_text("*").desired_width(160.0)).changed();
+                    if ui.button("Remove").clicked() {
+                        removed = Some(idx);
+                    }
+                });
+                changed |= upstream_proxy_ui(ui, ("upstream_rule", idx), &mut rule.proxy);
+                ui.separator();
+            }
+            if let Some(idx) = removed {
+                upstream_config.rules.remove(idx);
+                changed = true;
+            }
+            ui.add_space(8.0);
+
+            ui.horizontal(|ui| {
+                ui.strong("Client certificates");
+                if ui.button("Add certificate").clicked() {
+                    client_certs.push(ClientCertRule::default());
+                    certs_changed = true;
+                }
+            });
+            ui.label("Presented to upstreams that ask for one. PEM files, relative paths are inside the data directory. An empty host matches nothing.");
+            let mut removed = None;
+            for (idx, rule) in client_certs.iter_mut().enumerate() {
+                ui.horizontal_wrapped(|ui| {
+                    certs_changed |= ui.checkbox(&mut rule.enabled, "").changed();
+                    ui.label("Host:");
+                    certs_changed |= ui.add(egui::TextEdit::singleline(&mut rule.host).hint_text("api.internal").desired_width(140.0)).changed();
+                    ui.label("Certificate:");
+                    certs_changed |= resource_path_ui(ui, &mut rule.certificate);
+                    ui.label("Key:");
+                    certs_changed |= resource_path_ui(ui, &mut rule.key);
+                    if ui.button("Remove").clicked() {
+                        removed = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = removed {
+                client_certs.remove(idx);
+                certs_changed = true;
+            }
+            ui.add_space(8.0);
+
+            ui.horizontal(|ui| {
+                ui.strong("Certificate verification");
+                tls_changed |= ui.checkbox(&mut upstream_tls.system_roots, "Trust system roots").changed();
+                if ui.button("Add CA").clicked() {
+                    upstream_tls.extra_cas.push(Resource::File(FileResource::new("ca.pem")));
+                    tls_changed = true;
+                }
+            });
+            ui.label("Extra CAs are PEM files trusted on top of the system roots, relative paths are inside the data directory.");
+            let mut removed = None;
+            for (idx, ca) in upstream_tls.extra_cas.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    tls_changed |= resource_path_ui(ui, ca);
+                    if ui.button("Remove").clicked() {
+                        removed = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = removed {
+                upstream_tls.extra_cas.remove(idx);
+                tls_changed = true;
+            }
+            ui.label("Hosts whose certificates aren't verified at all, one pattern per line.");
+            let mut insecure_hosts = upstream_tls.insecure_hosts.join("\n");
+            if ui.add(egui::TextEdit::multiline(&mut insecure_hosts).code_editor().hint_text("*.staging.example.com").desired_rows(3).desired_width(f32::INFINITY)).changed() {
+                // split instead of lines() so a fresh empty line survives the round trip
+                upstream_tls.insecure_hosts = insecure_hosts.split('\n').map(|pattern| pattern.to_string()).collect();
+                tls_changed = true;
+            }
+        });
+
+        if changed {
+            config_watch.0.send_modify(|config| {
+                config.upstream = upstream_config;
+            });
+        }
+        if certs_changed {
+            config_watch.0.send_modify(|config| {
+                config.client_certs = client_certs;
+            });
+        }
+        if tls_changed {
+            config_watch.0.send_modify(|config| {
+                config.upstream_tls = upstream_tls;
+            });
+        }
+    }
+
+    pub fn intercept_ui(&mut self, ui: &mut egui::Ui) {
+        let (Some(intercept_queue), Some(config_watch)) = (&self.intercept_queue, &self.config_watch) else {
+            ui.label("Proxy not started");
+            return;
+        };
+        let UiState::Proxy(proxy_ui_state) = &mut self.state else {
+            return;
+        };
+
+        let mut intercept_config = config_watch.1.borrow().intercept.clone();
+        let was_intercepting = intercept_config.is_intercepting();
+        let mut changed = false;
+        ui.horizontal_wrapped(|ui| {
+            changed |= ui.checkbox(&mut intercept_config.intercept_requests, "Intercept requests").changed();
+            changed |= ui.checkbox(&mut intercept_config.intercept_responses, "Intercept responses").changed();
+            changed |= ui.checkbox(&mut intercept_config.intercept_websocket_messages, "Intercept WebSocket messages").changed();
+            ui.label("Host:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut intercept_config.host_filter).hint_text("*").desired_width(120.0)).changed();
+            ui.label("Path:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut intercept_config.path_filter).hint_text("*").desired_width(120.0)).changed();
+            ui.label("Method:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut intercept_config.method_filter).hint_text("*").desired_width(60.0)).changed();
+            if ui.button("Forward all").clicked() {
+                intercept_queue.write().unwrap().forward_all();
+                proxy_ui_state.intercept_editor = None;
+                proxy_ui_state.websocket_intercept_editor = None;
+            }
+        });
+        if changed {
+            if was_intercepting && !intercept_config.is_intercepting() {
+                // nothing will be able to release these once the pane stops caring
+                intercept_queue.write().unwrap().forward_all();
+                proxy_ui_state.intercept_editor = None;
+                proxy_ui_state.websocket_intercept_editor = None;
+            }
+            config_watch.0.send_modify(|config| {
+                config.intercept = intercept_config;
+            });
+        }
+        ui.separator();
+
+        // copied out so the proxy can queue and record messages while the pane draws
+        let (pending, response_flows, websocket_pending) = {
+            let intercept_queue = intercept_queue.read().unwrap();
+            let pending: Vec<(String, String)> = intercept_queue.pending.iter()
+                .map(|intercepted| {
+                    let label = match intercepted.message.is_response {
+                        true => format!("Response {}", intercepted.message.meta.unwrap_response_ref().status),
+                        false => {
+                            let request = intercepted.message.meta.unwrap_request_ref();
+                            format!("{} {}", request.method, request.url)
+                        }
+                    };
+                    (intercepted.id.clone(), label)
+                })
+                .collect();
+            let response_flows: Vec<(String, String)> = intercept_queue.pending.iter()
+                .filter(|intercepted| intercepted.message.is_response)
+                .filter_map(|intercepted| Some((intercepted.id.clone(), intercepted.flow_id.clone()?)))
+                .collect();
+            let websocket_pending: Vec<(String, String)> = intercept_queue.websocket_pending.iter()
+                .map(|intercepted| {
+                    let message = &intercepted.message;
+                    let arrow = match message.direction {
+                        WebSocketDirection::ClientToServer => "↑",
+                        WebSocketDirection::ServerToClient => "↓"
+                    };
+                    (intercepted.id.clone(), format!("WS {} {} {}", arrow, message.opcode.as_str(), payload_preview(&message.payload)))
+                })
+                .collect();
+            (pending, response_flows, websocket_pending)
+        };
+        if pending.is_empty() && websocket_pending.is_empty() {
+            proxy_ui_state.intercept_editor = None;
+            proxy_ui_state.websocket_intercept_editor = None;
+            ui.label("Nothing intercepted.");
+            return;
+        }
+        // show which request a response is answering
+        let request_urls: HashMap<String, String> = match &self.flow_storage {
+            Some(flow_storage) => {
+                let flow_storage = flow_storage.read().unwrap();
+                response_flows.into_iter()
+                    .filter_map(|(id, flow_id)| {
+                        let flow = flow_storage.get_flow(&flow_id)?;
+                        Some((id, flow.content.http_pair().request.meta.unwrap_request_ref().url.to_string()))
+                    })
+                    .collect()
+            },
+            None => HashMap::new()
+        };
+
+        // drop the editor if its message got resolved elsewhere
+        if let Some(editor) = &proxy_ui_state.intercept_editor {
+            if !pending.iter().any(|(id, _)| *id == editor.message_id) {
+                proxy_ui_state.intercept_editor = None;
+            }
+        }
+        if let Some(editor) = &proxy_ui_state.websocket_intercept_editor {
+            if !websocket_pending.iter().any(|(id, _)| *id == editor.message_id) {
+                proxy_ui_state.websocket_intercept_editor = None;
+            }
+        }
+
+        ui.label(format!("{} pending", pending.len() + websocket_pending.len()));
+        ScrollArea::vertical().id_salt("intercept_pending").max_height(120.0).show(ui, |ui| {
+            for (id, label) in &pending {
+                let label = match request_urls.get(id) {
+                    Some(url) => format!("{} {}", label, url),
+                    None => label.clone()
+                };
+                let selected = proxy_ui_state.intercept_editor.as_ref().is_some_and(|editor| editor.message_id == *id);
+                if ui.selectable_label(selected, label).clicked() {
+                    if let Some(intercepted) = intercept_queue.read().unwrap().get(id) {
+                        proxy_ui_state.intercept_editor = Some(InterceptEditor::from_message(intercepted));
+                        proxy_ui_state.websocket_intercept_editor = None;
+                    }
+                }
+            }
+            for (id, label) in &websocket_pending {
+                let selected = proxy_ui_state.websocket_intercept_editor.as_ref().is_some_and(|editor| editor.message_id == *id);
+                if ui.selectable_label(selected, label).clicked() {
+                    if let Some(intercepted) = intercept_queue.read().unwrap().get_websocket(id) {
+                        proxy_ui_state.websocket_intercept_editor = Some(WebSocketInterceptEditor::from_message(intercepted));
+                        proxy_ui_state.intercept_editor = None;
+                    }
+                }
+            }
+        });
+        ui.separator();
+
+        if let Some(editor) = &mut proxy_ui_state.websocket_intercept_editor {
+            let mut resolved = false;
+            ui.horizontal(|ui| {
+                if ui.button("Forward").clicked() {
+                    let original = intercept_queue.read().unwrap().get_websocket(&editor.message_id).map(|intercepted| intercepted.message.clone());
+                    if let Some(original) = original {
+                        match editor.to_message(&original) {
+                            Ok(message) => {
+                                intercept_queue.write().unwrap().forward_websocket(&editor.message_id, message);
+                                resolved = true;
+                            },
+                            Err(e) => {
+                                editor.error = Some(e);
+                            }
+                        }
+                    }
+                }
+                if ui.button("Drop").clicked() {
+                    intercept_queue.write().unwrap().drop_websocket(&editor.message_id);
+                    resolved = true;
+                }
+                if let Some(error) = &editor.error {
+                    ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+                }
+            });
+            if resolved {
+                proxy_ui_state.websocket_intercept_editor = None;
+                return;
+            }
+            ScrollArea::vertical().id_salt("websocket_intercept_editor").show(ui, |ui| {
+                editor.payload.ui(ui, "websocket_intercept_opcode");
+            });
+            return;
+        }
+
+        let Some(editor) = &mut proxy_ui_state.intercept_editor else {
+            ui.label("Select an intercepted message to edit it.");
+            return;
+        };
+
+        let mut resolved = false;
+        ui.horizontal(|ui| {
+            if ui.button("Forward").clicked() {
+                let original = intercept_queue.read().unwrap().get(&editor.message_id).map(|intercepted| intercepted.message.clone());
+                if let Some(original) = original {
+                    match editor.to_message(&original) {
+                        Ok(message) => {
+                            intercept_queue.write().unwrap().forward(&editor.message_id, message);
+                            resolved = true;
+                        },
+                        Err(e) => {
+                            editor.error = Some(e);
+                        }
+                    }
+                }
+            }
+            if ui.button("Drop").clicked() {
+                intercept_queue.write().unwrap().drop_message(&editor.message_id);
+                resolved = true;
+            }
+            if let Some(error) = &editor.error {
+                ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+            }
+        });
+        if resolved {
+            proxy_ui_state.intercept_editor = None;
+            return;
+        }
+
+        ui.horizontal(|ui| {
+            if editor.is_response {
+                ui.label("Status:");
+                ui.add(egui::TextEdit::singleline(&mut editor.status).desired_width(50.0));
+            } else {
+                ui.add(egui::TextEdit::singleline(&mut editor.method).desired_width(70.0));
+                ui.add(egui::TextEdit::singleline(&mut editor.url).desired_width(f32::INFINITY));
+            }
+        });
+        ScrollArea::vertical().id_salt("intercept_editor").show(ui, |ui| {
+            ui.label("Headers");
+            ui.add(egui::TextEdit::multiline(&mut editor.headers).code_editor().desired_width(f32::INFINITY));
+            ui.label("Body");
+            if editor.body_editable {
+                ui.add(egui::TextEdit::multiline(&mut editor.body).code_editor().desired_width(f32::INFINITY));
+            } else {
+                ui.colored_label(Color32::from_rgb(100, 100, 100), "Binary body, forwarded unchanged");
+            }
+        });
+    }
+
     pub fn is_server_running(&self) -> bool {
         self.config_watch.is_some() && matches!(self.state, UiState::Proxy(_))
     }
@@ -320,7 +1129,7 @@ impl AppState {
         if self.should_run_full_oobe() {
             self.state = UiState::OOBE(OOBEStep::Welcome);
         } else {
-            self.state = UiState::Proxy(ProxyUiState::default());
+            self.state = UiState::Proxy(Box::default());
         }
     }
 
@@ -355,7 +1164,11 @@ impl egui_tiles::Behavior<PaneState> for AppState {
         match pane {
             PaneState::OOBE => "Out of box experience".into(),
             PaneState::Blank => "Blank Test Pane".into(),
-            PaneState::FlowList => "Flows".into()
+            PaneState::FlowList => "Flows".into(),
+            PaneState::FlowDetail => "Flow".into(),
+            PaneState::Intercept => "Intercept".into(),
+            PaneState::Scope => "Scope".into(),
+            PaneState::Upstream => "Upstream".into()
         }
     }
 
@@ -394,9 +1207,12 @@ impl TelescopeApp {
 
         let mut tabs = vec![];
         tabs.push({
-            let cells = vec![tiles.insert_pane(PaneState::FlowList), tiles.insert_pane(PaneState::OOBE)];
+            let cells = vec![tiles.insert_pane(PaneState::FlowList), tiles.insert_pane(PaneState::FlowDetail), tiles.insert_pane(PaneState::OOBE)];
             tiles.insert_grid_tile(cells)
         });
+        tabs.push(tiles.insert_pane(PaneState::Intercept));
+        tabs.push(tiles.insert_pane(PaneState::Scope));
+        tabs.push(tiles.insert_pane(PaneState::Upstream));
         tabs.push(tiles.insert_pane(PaneState::Blank));
         let root = tiles.insert_tab_tile(tabs);
 
@@ -526,7 +1342,10 @@ impl TelescopeApp {
                                         let config_recv_copy = recv.clone();
                                         let proxy = telescope_core::proxy::TelescopeProxy::new(config_recv_copy);
                                         let proxy_wrapper = telescope_core::proxy::TelescopeProxyRef::wrap(proxy);
-                                        let flow_storage = proxy_wrapper.proxy.read().unwrap().storage.clone();
+                                        let (flow_storage, intercept_queue) = {
+                                            let proxy = proxy_wrapper.proxy.read().unwrap();
+                                            (proxy.storage.clone(), proxy.intercept_queue.clone())
+                                        };
                                         let proxy_wrapper_clone = proxy_wrapper.clone();
                                         let handle = runtime.spawn(async move {
                                             proxy_wrapper_clone.start().await.unwrap();
@@ -534,8 +1353,9 @@ impl TelescopeApp {
                                         });
 
                                         self.app_state.flow_storage = Some(flow_storage);
+                                        self.app_state.intercept_queue = Some(intercept_queue);
                                         self.app_state.proxy = Some(proxy_wrapper);
-                                        self.app_state.state = UiState::Proxy(ProxyUiState::default());
+                                        self.app_state.state = UiState::Proxy(Box::default());
                                         ctx.request_repaint();
                                     }
                                 });
@@ -619,6 +1439,11 @@ impl eframe::App for TelescopeApp {
 
         // ensure OOBE is not displayed in proxy mode
         if matches!(self.app_state.state, UiState::Proxy(_)) {
+            // held messages show up from the proxy threads, not from input
+            let intercepting = self.app_state.config_watch.as_ref().is_some_and(|(_, recv)| recv.borrow().intercept.is_intercepting());
+            if intercepting {
+                ctx.request_repaint_after(std::time::Duration::from_millis(250));
+            }
             for tile_id in self.tree.active_tiles() {
                 let mut remove = false;
                 if let Some(pane) = self.tree.tiles.get_pane(&tile_id) {
@@ -655,6 +1480,26 @@ impl eframe::App for TelescopeApp {
             } else {
                 egui::menu::bar(ui, |ui| {
                     ui.menu_button("File", |ui| {
+                        ui.menu_button("Import", |ui| {
+                            for format in IMPORT_FORMATS {
+                                if ui.button(format!("{}...", format.name())).clicked() {
+                                    self.app_state.dialog_ui_state = DialogUiState::ImportFlows(*format);
+                                    self.app_state.file_dialog.pick_file();
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                        ui.menu_button("Export", |ui| {
+                            for format in EXPORT_FORMATS {
+                                if ui.button(format!("{}...", format.name())).clicked() {
+                                    self.app_state.dialog_ui_state = DialogUiState::ExportFlows(*format);
+                                    self.app_state.file_dialog.config_mut().default_file_name = format.default_file_name().to_string();
+                                    self.app_state.file_dialog.save_file();
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                        ui.separator();
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
@@ -692,6 +1537,24 @@ impl eframe::App for TelescopeApp {
                 }
             }
 
+            if let DialogUiState::ImportFlows(format) | DialogUiState::ExportFlows(format) = self.app_state.dialog_ui_state {
+                if let Some(path) = self.app_state.file_dialog.take_picked() {
+                    if let (Some(runtime), Some(flow_storage)) = (&self.app_state.runtime, &self.app_state.flow_storage) {
+                        match self.app_state.dialog_ui_state {
+                            DialogUiState::ImportFlows(_) => import_flows(runtime, flow_storage.clone(), format, path),
+                            _ => {
+                                let max_body_size = match &self.app_state.config_watch {
+                                    Some(config_watch) => config_watch.1.borrow().body_storage.max_recorded_size,
+                                    None => BodyStorageConfig::default().max_recorded_size
+                                };
+                                export_flows(runtime, flow_storage, format, path, max_body_size)
+                            }
+                        }
+                    }
+                    self.app_state.dialog_ui_state = DialogUiState::None;
+                }
+            }
+
 
             if self.app_state.flags.show_logs {
                 egui::Window::new("Log").show(ctx, |ui| {
@@ -703,6 +1566,213 @@ impl eframe::App for TelescopeApp {
     }
 }
 
+// direct or one upstream proxy with its settings, returns whether anything changed
+fn upstream_proxy_ui(ui: &mut egui::Ui, id_salt: impl std::hash::Hash, proxy: &mut Option<UpstreamProxy>) -> bool {
+    let mut changed = false;
+    ui.horizontal_wrapped(|ui| {
+        let selected_text = proxy.as_ref().map_or("Direct", |proxy| proxy.kind.as_str());
+        egui::ComboBox::from_id_salt(id_salt)
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                if ui.selectable_label(proxy.is_none(), "Direct").clicked() && proxy.is_some() {
+                    *proxy = None;
+                    changed = true;
+                }
+                for kind in [UpstreamProxyKind::Http, UpstreamProxyKind::Socks5] {
+                    let selected = proxy.as_ref().is_some_and(|proxy| proxy.kind == kind);
+                    if ui.selectable_label(selected, kind.as_str()).clicked() && !selected {
+                        proxy.get_or_insert_with(UpstreamProxy::default).kind = kind;
+                        changed = true;
+                    }
+                }
+            });
+        if let Some(proxy) = proxy {
+            ui.label("Address:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut proxy.address).hint_text("host:port").desired_width(140.0)).changed();
+            ui.label("Username:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut proxy.username).desired_width(80.0)).changed();
+            ui.label("Password:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut proxy.password).password(true).desired_width(80.0)).changed();
+        }
+    });
+    changed
+}
+
+// a client pattern matches the address with or without the port, so a device's ip finds all its flows
+fn filter_by_connection(flows: Vec<usize>, flow_storage: &telescope_core::proxy::FlowStorage, client_filter: &str, connection_filter: &str) -> Vec<usize> {
+    let client_filter = client_filter.trim();
+    let connection_filter = connection_filter.trim().trim_start_matches('#');
+    if client_filter.is_empty() && connection_filter.is_empty() {
+        return flows;
+    }
+    // anything that isn't a number matches nothing
+    let connection_id = connection_filter.parse::<u64>().ok();
+    flows.into_iter().filter(|idx| {
+        let Some(flow) = flow_storage.flow_by_index(*idx) else {
+            return false;
+        };
+        let client_matches = client_filter.is_empty() || flow.connection.client_addr.is_some_and(|client_addr| {
+            optional_wildcard_match(client_filter, &client_addr.to_string()) || optional_wildcard_match(client_filter, &client_addr.ip().to_string())
+        });
+        let connection_matches = connection_filter.is_empty() || (connection_id.is_some() && flow.connection.connection_id == connection_id);
+        client_matches && connection_matches
+    }).collect()
+}
+
+fn connection_ui(ui: &mut egui::Ui, connection: &ConnectionInfo) {
+    egui::Grid::new("connection_client").num_columns(2).show(ui, |ui| {
+        ui.label("Client");
+        ui.label(connection.client_addr.map_or("unknown".to_string(), |client_addr| client_addr.to_string()));
+        ui.end_row();
+        ui.label("Connection");
+        ui.label(connection.connection_id.map_or("unknown".to_string(), |connection_id| format!("#{}", connection_id)));
+        ui.end_row();
+    });
+    ui.add_space(8.0);
+    if let Some(error) = &connection.error {
+        ui.strong("Upstream error");
+        ui.colored_label(Color32::from_rgb(255, 0, 0), error);
+        ui.add_space(8.0);
+    }
+    let Some(tls) = &connection.tls else {
+        ui.label("No upstream TLS details recorded for this flow.");
+        return;
+    };
+    egui::Grid::new("connection_tls").num_columns(2).show(ui, |ui| {
+        ui.label("SNI");
+        ui.label(&tls.sni);
+        ui.end_row();
+        ui.label("Version");
+        ui.label(&tls.version);
+        ui.end_row();
+        ui.label("Cipher suite");
+        ui.label(&tls.cipher_suite);
+        ui.end_row();
+        ui.label("ALPN");
+        ui.label(tls.alpn.as_deref().unwrap_or("none"));
+        ui.end_row();
+    });
+    ui.add_space(8.0);
+    ui.strong(format!("Certificate chain ({})", tls.certificates.len()));
+    for (idx, certificate) in tls.certificates.iter().enumerate() {
+        egui::CollapsingHeader::new(format!("{}: {}", idx, certificate.subject)).id_salt(("connection_certificate", idx)).default_open(idx == 0).show(ui, |ui| {
+            egui::Grid::new(("connection_certificate_grid", idx)).num_columns(2).show(ui, |ui| {
+                ui.label("Subject");
+                ui.label(&certificate.subject);
+                ui.end_row();
+                ui.label("Issuer");
+                ui.label(&certificate.issuer);
+                ui.end_row();
+                ui.label("SANs");
+                ui.label(certificate.sans.join(", "));
+                ui.end_row();
+                ui.label("Not before");
+                ui.label(&certificate.not_before);
+                ui.end_row();
+                ui.label("Not after");
+                ui.label(&certificate.not_after);
+                ui.end_row();
+                ui.label("Serial");
+                ui.label(&certificate.serial);
+                ui.end_row();
+            });
+        });
+    }
+}
+
+fn flow_context_menu(ui: &mut egui::Ui, flow: &Flow) {
+    let request = &flow.content.http_pair().request;
+    // a CONNECT on its own isn't worth replaying
+    let enabled = !matches!(flow.content, FlowContent::Tunnel(_)) && !request.meta.unwrap_request_ref().is_proxy_client_connection();
+    ui.add_enabled_ui(enabled, |ui| {
+        ui.menu_button("Copy as", |ui| {
+            for language in CodeLanguage::ALL {
+                if ui.button(language.name()).clicked() {
+                    match request_to_code(request, language) {
+                        Ok(code) => ui.ctx().copy_text(code),
+                        Err(e) => warn!("could not copy request as {}: {}", language.name(), e)
+                    }
+                    ui.close_menu();
+                }
+            }
+        });
+    });
+}
+
+fn format_micros(micros: u64) -> String {
+    format!("{:.2} ms", micros as f64 / 1000.0)
+}
+
+fn timing_ui(ui: &mut egui::Ui, timings: &FlowTimings) {
+    let phases = timings.phases();
+    if phases.is_empty() {
+        ui.label("No timings recorded for this flow.");
+        return;
+    }
+    // still running flows are scaled to whatever has happened so far
+    let total = timings.total().unwrap_or_else(|| phases.iter().map(|(_, _, end)| *end).max().unwrap_or(0)).max(1);
+    match timings.total() {
+        Some(total) => ui.label(format!("Total {}", format_micros(total))),
+        None => ui.label("Still in progress")
+    };
+    ui.add_space(8.0);
+    egui::Grid::new("flow_timing_waterfall").num_columns(3).striped(true).show(ui, |ui| {
+        for (idx, (name, start, end)) in phases.iter().enumerate() {
+            ui.label(*name);
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(300.0, 14.0), egui::Sense::hover());
+            let x_for = |micros: u64| rect.left() + rect.width() * (micros as f32 / total as f32).min(1.0);
+            // zero length phases still get a sliver so they show up
+            let bar = egui::Rect::from_x_y_ranges(x_for(*start)..=x_for(*end).max(x_for(*start) + 1.0), rect.y_range());
+            ui.painter().rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+            ui.painter().rect_filled(bar, 2.0, Color32::from_rgb(80, 140 + (idx as u8 * 15), 220));
+            ui.label(format!("{} (at {})", format_micros(end - start), format_micros(*start)));
+            ui.end_row();
+        }
+    });
+}
+
+// file resources get a path field, anything stored inline in the config is left alone
+fn resource_path_ui(ui: &mut egui::Ui, resource: &mut Resource) -> bool {
+    match resource {
+        Resource::File(file) => ui.add(egui::TextEdit::singleline(&mut file.path).desired_width(140.0)).changed(),
+        _ => {
+            ui.label("(inline)");
+            false
+        }
+    }
+}
+
+fn message_ui(ui: &mut egui::Ui, title: &str, message: &telescope_core::resource::RequestOrResponse, preview: &[u8]) {
+    egui::CollapsingHeader::new(title).default_open(true).show(ui, |ui| {
+        let mut headers = headers_to_string(&message.headers);
+        ui.add(egui::TextEdit::multiline(&mut headers).code_editor().interactive(false).desired_width(f32::INFINITY));
+        let size = message.body.size();
+        if message.body.is_file() {
+            ui.label(format!("Body stored on disk ({} bytes)", size));
+        }
+        if message.body_truncated {
+            ui.colored_label(egui::Color32::YELLOW, "Body was too large and has been truncated");
+        }
+        if message.body_streamed {
+            ui.colored_label(egui::Color32::YELLOW, "Body was streamed through, plugins and intercept did not see it");
+        }
+        let mut body = preview.to_vec();
+        if !body.is_empty() {
+            if size > body.len() as u64 {
+                // don't let a utf-8 sequence cut in half turn the whole thing into a hex dump
+                if let Err(e) = std::str::from_utf8(&body) {
+                    if e.error_len().is_none() {
+                        body.truncate(e.valid_up_to());
+                    }
+                }
+                ui.label(format!("Showing the first {} bytes", body.len()));
+            }
+            let mut body = payload_text(&body);
+            ui.add(egui::TextEdit::multiline(&mut body).code_editor().interactive(false).desired_width(f32::INFINITY));
+        }
+    });
+}
+
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     ui.horizontal(|ui| {
         ui.spacing_mut().item_spacing.x = 0.0;
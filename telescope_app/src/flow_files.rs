@@ -0,0 +1,100 @@
+// importing and exporting flows from the file menu, the conversions themselves live in telescope_core
+use std::{path::PathBuf, sync::{Arc, RwLock}};
+
+use log::{error, info};
+use telescope_core::{burp, har, mitmproxy, proxy::FlowStorage, resource::{Flow, FlowContent}};
+use tokio::runtime::Runtime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowFileFormat {
+    Har,
+    // import only
+    Mitmproxy,
+    Burp,
+}
+
+pub const IMPORT_FORMATS: &[FlowFileFormat] = &[FlowFileFormat::Har, FlowFileFormat::Mitmproxy, FlowFileFormat::Burp];
+pub const EXPORT_FORMATS: &[FlowFileFormat] = &[FlowFileFormat::Har, FlowFileFormat::Burp];
+
+impl FlowFileFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlowFileFormat::Har => "HAR",
+            FlowFileFormat::Mitmproxy => "mitmproxy flows",
+            FlowFileFormat::Burp => "Burp XML"
+        }
+    }
+
+    pub fn default_file_name(&self) -> &'static str {
+        match self {
+            FlowFileFormat::Har => "flows.har",
+            FlowFileFormat::Mitmproxy => "flows.flow",
+            FlowFileFormat::Burp => "flows.xml"
+        }
+    }
+}
+
+pub fn import_flows(runtime: &Runtime, flow_storage: Arc<RwLock<FlowStorage>>, format: FlowFileFormat, path: PathBuf) {
+    runtime.spawn(async move {
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) => {
+                error!("failed to read {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let flows = match format {
+            FlowFileFormat::Har => har::import_har(&data).await,
+            FlowFileFormat::Mitmproxy => mitmproxy::import_mitmproxy(&data),
+            FlowFileFormat::Burp => burp::import_burp(&data)
+        };
+        match flows {
+            Ok(flows) => {
+                let count = flows.len();
+                let mut flow_storage = flow_storage.write().unwrap();
+                for flow in flows {
+                    flow_storage.add_flow(flow);
+                }
+                info!("imported {} flows from {}", count, path.display());
+            },
+            Err(e) => error!("failed to import {}: {}", path.display(), e)
+        }
+    });
+}
+
+pub fn export_flows(runtime: &Runtime, flow_storage: &RwLock<FlowStorage>, format: FlowFileFormat, path: PathBuf, max_body_size: u64) {
+    // copied out so the proxy isn't held up while bodies get decoded
+    let flows: Vec<Flow> = flow_storage.read().unwrap().iter_flow_timeline().cloned().collect();
+    runtime.spawn(async move {
+        // websocket messages of flows from the project may still be in the database
+        let flows = tokio::task::spawn_blocking(move || {
+            let mut flows = flows;
+            for flow in &mut flows {
+                if let FlowContent::WebSocket(websocket) = &mut flow.content {
+                    websocket.load_messages();
+                }
+            }
+            flows
+        }).await;
+        let flows = match flows {
+            Ok(flows) => flows,
+            Err(e) => {
+                error!("failed to export to {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let data = match format {
+            FlowFileFormat::Har => har::export_har(&flows, max_body_size).await,
+            FlowFileFormat::Mitmproxy => Err("mitmproxy flows can only be imported".to_string()),
+            FlowFileFormat::Burp => Ok(burp::export_burp(&flows))
+        };
+        let result = match data {
+            Ok(data) => tokio::fs::write(&path, data).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(_) => info!("exported {} flows to {}", flows.len(), path.display()),
+            Err(e) => error!("failed to export to {}: {}", path.display(), e)
+        }
+    });
+}
@@ -0,0 +1,174 @@
+use telescope_core::{intercept::InterceptedMessage, resource::{headers_to_string, parse_headers, MemoryResource, RequestOrResponse, Resource, WebSocketDirection, WebSocketMessage, WebSocketOpcode}};
+
+use crate::utils::{parse_hex, to_hex};
+
+// text buffers for the message currently being edited in the intercept pane
+pub struct InterceptEditor {
+    pub message_id: String,
+    pub is_response: bool,
+    // requests
+    pub method: String,
+    pub url: String,
+    // responses
+    pub status: String,
+    pub headers: String,
+    pub body: String,
+    // non utf-8 bodies are passed through untouched
+    pub body_editable: bool,
+    pub error: Option<String>,
+}
+
+impl InterceptEditor {
+    pub fn from_message(intercepted: &InterceptedMessage) -> Self {
+        let message = &intercepted.message;
+        let (method, url, status) = if message.is_response {
+            (String::new(), String::new(), message.meta.unwrap_response_ref().status.to_string())
+        } else {
+            let request = message.meta.unwrap_request_ref();
+            (request.method.clone(), request.url.to_string(), String::new())
+        };
+        let (body, body_editable) = match message.body.as_bytes().map(String::from_utf8) {
+            Ok(Ok(body)) => (body, true),
+            _ => (String::new(), false)
+        };
+        Self {
+            message_id: intercepted.get_id(),
+            is_response: message.is_response,
+            method,
+            url,
+            status,
+            headers: headers_to_string(&message.headers),
+            body,
+            body_editable,
+            error: None,
+        }
+    }
+
+    // apply the edits on top of the original message
+    pub fn to_message(&self, original: &RequestOrResponse) -> Result<RequestOrResponse, String> {
+        let mut message = original.clone();
+        message.headers = parse_headers(&self.headers)?;
+        if self.body_editable {
+            message.body = Resource::Memory(MemoryResource::new(self.body.clone().into_bytes()));
+        }
+        if message.is_response {
+            let status: u16 = self.status.trim().parse().map_err(|_| format!("invalid status code: {}", self.status))?;
+            if !(100..=999).contains(&status) {
+                return Err(format!("invalid status code: {}", status));
+            }
+            message.meta.unwrap_response_mut().status = status as u32;
+        } else {
+            let request = message.meta.unwrap_request_mut();
+            request.method = self.method.trim().to_string();
+            request.set_url(self.url.trim())?;
+        }
+        Ok(message)
+    }
+}
+
+// text frames are edited as text, everything else as hex
+pub struct WebSocketPayloadEditor {
+    pub opcode: WebSocketOpcode,
+    pub payload: String,
+}
+
+impl Default for WebSocketPayloadEditor {
+    fn default() -> Self {
+        Self {
+            opcode: WebSocketOpcode::Text,
+            payload: String::new()
+        }
+    }
+}
+
+impl WebSocketPayloadEditor {
+    pub fn from_message(message: &WebSocketMessage) -> Self {
+        let payload = match message.opcode {
+            WebSocketOpcode::Text => String::from_utf8_lossy(&message.payload).to_string(),
+            _ => to_hex(&message.payload)
+        };
+        Self {
+            opcode: message.opcode,
+            payload
+        }
+    }
+
+    pub fn is_hex(&self) -> bool {
+        self.opcode != WebSocketOpcode::Text
+    }
+
+    // keeps the bytes when flipping between text and hex, leaves the buffer alone if it doesn't parse
+    pub fn set_opcode(&mut self, opcode: WebSocketOpcode) {
+        if let Ok(payload) = self.to_payload() {
+            self.payload = match opcode {
+                WebSocketOpcode::Text => String::from_utf8_lossy(&payload).to_string(),
+                _ => to_hex(&payload)
+            };
+        }
+        self.opcode = opcode;
+    }
+
+    pub fn to_payload(&self) -> Result<Vec<u8>, String> {
+        if self.is_hex() {
+            parse_hex(&self.payload)
+        } else {
+            Ok(self.payload.clone().into_bytes())
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, id_salt: &str) {
+        let mut opcode = self.opcode;
+        egui::ComboBox::from_id_salt(id_salt)
+            .selected_text(opcode.as_str())
+            .show_ui(ui, |ui| {
+                for choice in [WebSocketOpcode::Text, WebSocketOpcode::Binary, WebSocketOpcode::Ping, WebSocketOpcode::Pong, WebSocketOpcode::Close] {
+                    ui.selectable_value(&mut opcode, choice, choice.as_str());
+                }
+            });
+        if opcode != self.opcode {
+            self.set_opcode(opcode);
+        }
+        let hint = if self.is_hex() { "hex bytes" } else { "message" };
+        ui.add(egui::TextEdit::multiline(&mut self.payload).code_editor().hint_text(hint).desired_width(f32::INFINITY));
+    }
+}
+
+pub struct WebSocketInterceptEditor {
+    pub message_id: String,
+    pub payload: WebSocketPayloadEditor,
+    pub error: Option<String>,
+}
+
+impl WebSocketInterceptEditor {
+    pub fn from_message(intercepted: &InterceptedMessage<WebSocketMessage>) -> Self {
+        Self {
+            message_id: intercepted.get_id(),
+            payload: WebSocketPayloadEditor::from_message(&intercepted.message),
+            error: None
+        }
+    }
+
+    pub fn to_message(&self, original: &WebSocketMessage) -> Result<WebSocketMessage, String> {
+        let mut message = original.clone();
+        message.opcode = self.payload.opcode;
+        message.payload = self.payload.to_payload()?;
+        Ok(message)
+    }
+}
+
+// new messages typed into a live websocket
+pub struct WebSocketComposer {
+    pub direction: WebSocketDirection,
+    pub payload: WebSocketPayloadEditor,
+    pub error: Option<String>,
+}
+
+impl Default for WebSocketComposer {
+    fn default() -> Self {
+        Self {
+            direction: WebSocketDirection::ClientToServer,
+            payload: WebSocketPayloadEditor::default(),
+            error: None
+        }
+    }
+}
@@ -6,5 +6,7 @@ pub mod states;
 pub mod settings;
 pub mod config;
 pub mod oobe;
+pub mod intercept;
+pub mod flow_files;
 pub use app::TelescopeApp;
 pub use app::AppState;
\ No newline at end of file
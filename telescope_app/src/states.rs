@@ -1,5 +1,9 @@
+use crate::flow_files::FlowFileFormat;
+
 pub enum DialogUiState {
     None,
     ChooseWorkspacePath,
-    ChooseBindAddress(String)
+    ChooseBindAddress(String),
+    ImportFlows(FlowFileFormat),
+    ExportFlows(FlowFileFormat)
 }
\ No newline at end of file
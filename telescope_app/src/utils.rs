@@ -1,3 +1,5 @@
+use std::fmt::Write;
+
 pub fn color_for_status(status: u32) -> egui::Color32 {
     match status {
         100..=199 => egui::Color32::from_rgb(0, 155, 0), // green
@@ -7,4 +9,108 @@ pub fn color_for_status(status: u32) -> egui::Color32 {
         500..=599 => egui::Color32::from_rgb(255, 0, 0), // red
         _ => egui::Color32::from_rgb(0, 0, 255), // blue, why is this here?
     }
-}
\ No newline at end of file
+}
+
+// text as is, anything else as a hex dump
+pub fn payload_text(payload: &[u8]) -> String {
+    match std::str::from_utf8(payload) {
+        Ok(text) => text.to_string(),
+        Err(_) => hex_dump(payload)
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// one line summary for lists
+pub fn payload_preview(payload: &[u8]) -> String {
+    const PREVIEW_LEN: usize = 120;
+    match std::str::from_utf8(payload) {
+        Ok(text) => text.chars().take(PREVIEW_LEN).map(|c| if c.is_control() { ' ' } else { c }).collect(),
+        Err(_) => payload.iter().take(PREVIEW_LEN / 3).fold(String::new(), |mut out, byte| {
+            let _ = write!(out, "{:02x} ", byte);
+            out
+        })
+    }
+}
+
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}  ", line * 16);
+        for index in 0..16 {
+            match chunk.get(index) {
+                Some(byte) => {
+                    let _ = write!(out, "{:02x} ", byte);
+                },
+                None => out.push_str("   ")
+            }
+        }
+        out.push(' ');
+        out.extend(chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }));
+        out.push('\n');
+    }
+    out
+}
+
+// "de ad be ef", what binary websocket payloads are edited as
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (index, byte) in bytes.iter().enumerate() {
+        if index > 0 {
+            out.push(if index % 16 == 0 { '\n' } else { ' ' });
+        }
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+// whitespace is ignored
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err("hex payload has an odd number of digits".to_string());
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            // from_str_radix would also take "+f"
+            match pair.iter().all(|c| c.is_ascii_hexdigit()) {
+                true => u8::from_str_radix(&byte, 16).map_err(|_| format!("invalid hex byte: {}", byte)),
+                false => Err(format!("invalid hex byte: {}", byte))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips_and_ignores_whitespace() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(parse_hex(&to_hex(&bytes)).unwrap(), bytes);
+        assert_eq!(parse_hex(" DE ad\n\tbe EF ").unwrap(), vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_hex("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn bad_hex_is_an_error() {
+        assert_eq!(parse_hex("abc").unwrap_err(), "hex payload has an odd number of digits");
+        assert_eq!(parse_hex("zz").unwrap_err(), "invalid hex byte: zz");
+        assert!(parse_hex("+f").is_err());
+        assert!(parse_hex("éé").is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use std::{collections::HashSet, ffi::OsString, path::{Path, PathBuf}, pin::Pin, sync::RwLock, task::{ready, Context, Poll}, time::{Duration, Instant, SystemTime}};
+
+use futures::StreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hudsucker::Body;
+use hyper::{body::{Bytes, Frame, SizeHint}, header::CONTENT_TYPE, HeaderMap};
+use log::{info, warn};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{config::BodyStorageConfig, proxy::FlowStorage, resource::{FileResource, Flow, MemoryResource, Resource}};
+
+// under data_dir, where bodies over the spill threshold get written
+const SPILL_DIR: &str = "bodies";
+
+// why a body wasn't buffered, none of these get seen by plugins or intercept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingReason {
+    // declared as a stream by its content type
+    Streaming,
+    TooLarge,
+    Stalled,
+}
+
+impl PendingReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PendingReason::Streaming => "it is a stream",
+            PendingReason::TooLarge => "it is over the spill threshold",
+            PendingReason::Stalled => "it stalled"
+        }
+    }
+}
+
+// what's left of a body that was too big to buffer, it gets teed through a BodyRecorder instead
+pub struct PendingBody {
+    pub prefix: Bytes,
+    pub rest: Body,
+    pub reason: PendingReason,
+}
+
+impl PendingBody {
+    // puts the body back together without recording anything
+    pub fn into_body(self) -> Body {
+        let prefix = futures::stream::iter([Ok::<_, hudsucker::Error>(Frame::data(self.prefix))]);
+        let rest = futures::stream::unfold(self.rest, |mut rest| async move {
+            rest.frame().await.map(|frame| (frame, rest))
+        });
+        Body::from(BoxBody::new(StreamBody::new(prefix.chain(rest))))
+    }
+}
+
+pub enum CapturedBody {
+    Complete(Bytes),
+    Pending(PendingBody),
+}
+
+// content types that are meant to be read as they arrive and may never end
+const STREAMING_CONTENT_TYPES: &[&str] = &[
+    "text/event-stream",
+    "application/x-ndjson",
+    "application/stream+json",
+    "application/jsonl",
+    "multipart/x-mixed-replace"
+];
+
+pub fn is_streaming_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    STREAMING_CONTENT_TYPES.contains(&mime.as_str())
+}
+
+// reads the body until it ends, goes over the threshold or stalls
+// a stalled body is most likely a stream (chunked, long polling) so it gets passed through as it arrives
+pub async fn capture_body(mut body: Body, headers: &HeaderMap, limits: &BodyStorageConfig) -> CapturedBody {
+    if is_streaming_content_type(headers) {
+        return CapturedBody::Pending(PendingBody {
+            prefix: Bytes::new(),
+            rest: body,
+            reason: PendingReason::Streaming
+        });
+    }
+
+    let idle_timeout = Duration::from_millis(limits.stream_idle_timeout_ms);
+    // held up to what would be recorded anyway when plugins and intercept should get to see big bodies too
+    let threshold = match limits.hold_large_bodies {
+        true => limits.max_recorded_size.try_into().unwrap_or(usize::MAX),
+        false => limits.spill_threshold
+    };
+    let mut buffer = Vec::new();
+    let mut reason = PendingReason::TooLarge;
+    while buffer.len() <= threshold {
+        let frame = match tokio::time::timeout(idle_timeout, body.frame()).await {
+            Ok(frame) => frame,
+            Err(_) => {
+                reason = PendingReason::Stalled;
+                break;
+            }
+        };
+        match frame {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    buffer.extend_from_slice(&data);
+                }
+            },
+            Some(Err(e)) => {
+                // same as collect().unwrap_or_default() used to do, keep what we got
+                warn!("error reading body: {}", e);
+                return CapturedBody::Complete(Bytes::from(buffer));
+            },
+            None => return CapturedBody::Complete(Bytes::from(buffer))
+        }
+    }
+    CapturedBody::Pending(PendingBody {
+        prefix: Bytes::from(buffer),
+        rest: body,
+        reason
+    })
+}
+
+pub fn spill_path(data_dir: &Path, flow_id: &str, side: &str) -> PathBuf {
+    data_dir.join(SPILL_DIR).join(format!("{}-{}", flow_id, side))
+}
+
+// the spill files this flow made, other file bodies aren't ours to delete
+fn spilled_files(flow: &Flow) -> Vec<PathBuf> {
+    let http_pair = flow.content.http_pair();
+    [("request", Some(&http_pair.request)), ("response", http_pair.response.as_ref())].into_iter()
+        .filter_map(|(side, message)| match &message?.body {
+            Resource::File(file) => Some((side, PathBuf::from(&file.path))),
+            _ => None
+        })
+        .filter(|(side, path)| path.file_name().is_some_and(|name| *name == *format!("{}-{}", flow.id, side)))
+        .map(|(_, path)| path)
+        .collect()
+}
+
+pub fn remove_spilled_bodies(flow: &Flow) {
+    for path in spilled_files(flow) {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("could not remove body file {}: {}", path.display(), e);
+        }
+    }
+}
+
+// spill files from earlier runs that no flow in the storage points at, they'd pile up forever otherwise
+// only files from before cutoff go, anything newer may belong to a body that is spilling right now
+pub fn remove_stale_spills(data_dir: &Path, cutoff: SystemTime, flow_storage: &RwLock<FlowStorage>) {
+    let Ok(entries) = std::fs::read_dir(data_dir.join(SPILL_DIR)) else {
+        return;
+    };
+    let referenced: HashSet<OsString> = flow_storage.read().unwrap().iter_flow_timeline()
+        .flat_map(spilled_files)
+        .filter_map(|path| path.file_name().map(|name| name.to_os_string()))
+        .collect();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let stale = entry.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified < cutoff);
+        if !stale || referenced.contains(&entry.file_name()) {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(_) => removed += 1,
+            Err(e) => warn!("could not remove body file {}: {}", entry.path().display(), e)
+        }
+    }
+    if removed > 0 {
+        info!("removed {} body files no flow uses anymore", removed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedBody {
+    pub body: Resource,
+    pub truncated: bool,
+}
+
+// how often a body that is still streaming gets copied into its flow
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// keeps a body in memory until it passes the spill threshold, then moves it to a file
+// anything past max_recorded_size is counted as truncated and thrown away
+// file writes go through tokio, the body is forwarded from an async task and the disk shouldn't stall it
+pub struct BodyRecorder {
+    spill_path: PathBuf,
+    limits: BodyStorageConfig,
+    memory: Vec<u8>,
+    file: Option<File>,
+    recorded: u64,
+    truncated: bool,
+}
+
+impl BodyRecorder {
+    pub fn new(spill_path: PathBuf, limits: BodyStorageConfig) -> Self {
+        Self {
+            spill_path,
+            limits,
+            memory: Vec::new(),
+            file: None,
+            recorded: 0,
+            truncated: false
+        }
+    }
+
+    async fn spill(&mut self) -> std::io::Result<()> {
+        if let Some(parent) = self.spill_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = File::create(&self.spill_path).await?;
+        file.write_all(&self.memory).await?;
+        self.memory = Vec::new();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub async fn write(&mut self, data: &[u8]) {
+        if self.truncated {
+            return;
+        }
+        let remaining = self.limits.max_recorded_size.saturating_sub(self.recorded);
+        let keep = &data[..data.len().min(remaining.try_into().unwrap_or(usize::MAX))];
+        if keep.len() < data.len() {
+            self.truncated = true;
+        }
+        if keep.is_empty() {
+            return;
+        }
+
+        if self.file.is_none() && self.memory.len() + keep.len() > self.limits.spill_threshold {
+            if let Err(e) = self.spill().await {
+                // better a cut off recording than holding the whole thing in memory
+                warn!("could not spill body to {}: {}", self.spill_path.display(), e);
+                self.truncated = true;
+                return;
+            }
+        }
+        match &mut self.file {
+            Some(file) => {
+                if let Err(e) = file.write_all(keep).await {
+                    warn!("could not write body to {}: {}", self.spill_path.display(), e);
+                    self.truncated = true;
+                    return;
+                }
+            },
+            None => self.memory.extend_from_slice(keep)
+        }
+        self.recorded += keep.len() as u64;
+    }
+
+    // waits for the last write to reach the file
+    pub async fn flush(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.flush().await {
+                warn!("could not flush body to {}: {}", self.spill_path.display(), e);
+            }
+        }
+    }
+
+    // what has been recorded so far, for bodies that are still streaming
+    // the last write to a file may still be on its way
+    pub fn snapshot(&self) -> RecordedBody {
+        let body = match &self.file {
+            Some(_) => Resource::File(FileResource::new(&self.spill_path.to_string_lossy())),
+            None => Resource::Memory(MemoryResource::new(self.memory.clone()))
+        };
+        RecordedBody {
+            body,
+            truncated: self.truncated
+        }
+    }
+
+    // flush first when there's a chance to, a file dropped mid-write still gets the write finished by tokio
+    pub fn finish(self) -> RecordedBody {
+        let body = match self.file {
+            Some(_) => Resource::File(FileResource::new(&self.spill_path.to_string_lossy())),
+            None => Resource::Memory(MemoryResource::new(self.memory))
+        };
+        RecordedBody {
+            body,
+            truncated: self.truncated
+        }
+    }
+}
+
+// called with the body recorded so far, the flag is set once the body is done
+type OnRecorded = Box<dyn Fn(RecordedBody, bool) + Send + Sync>;
+
+struct TeeState {
+    // recorded before anything else, it's already on its way to the other side
+    prefix: Option<Bytes>,
+    rest: Body,
+    recorder: Option<BodyRecorder>,
+    on_recorded: OnRecorded,
+    last_progress: Instant,
+}
+
+impl TeeState {
+    fn progress(&mut self) {
+        if self.last_progress.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        if let Some(recorder) = &self.recorder {
+            (self.on_recorded)(recorder.snapshot(), false);
+        }
+        self.last_progress = Instant::now();
+    }
+
+    async fn flush_and_finish(&mut self) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.flush().await;
+        }
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            (self.on_recorded)(recorder.finish(), true);
+        }
+    }
+}
+
+impl Drop for TeeState {
+    // the other side hung up halfway, keep what made it through
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// forwards the body as it arrives while recording it
+// on_recorded gets progress every so often and a final call once the body ends or is dropped
+pub fn tee_body(pending: PendingBody, recorder: BodyRecorder, on_recorded: impl Fn(RecordedBody, bool) + Send + Sync + 'static) -> Body {
+    let state = TeeState {
+        prefix: Some(pending.prefix.clone()),
+        rest: pending.rest,
+        recorder: Some(recorder),
+        on_recorded: Box::new(on_recorded),
+        last_progress: Instant::now()
+    };
+
+    let prefix = futures::stream::iter([Ok::<_, hudsucker::Error>(Frame::data(pending.prefix))]);
+    let rest = futures::stream::unfold(state, |mut state| async move {
+        if let (Some(prefix), Some(recorder)) = (state.prefix.take(), state.recorder.as_mut()) {
+            recorder.write(&prefix).await;
+        }
+        match state.rest.frame().await {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(recorder)) = (frame.data_ref(), state.recorder.as_mut()) {
+                    recorder.write(data).await;
+                }
+                state.progress();
+                Some((Ok(frame), state))
+            },
+            Some(Err(e)) => {
+                state.flush_and_finish().await;
+                Some((Err(e), state))
+            },
+            None => {
+                state.flush_and_finish().await;
+                None
+            }
+        }
+    });
+    Body::from(BoxBody::new(StreamBody::new(prefix.chain(rest))))
+}
+
+struct NotifyOnEnd {
+    inner: Body,
+    on_end: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl hyper::body::Body for NotifyOnEnd {
+    type Data = Bytes;
+    type Error = hudsucker::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        // hyper stops polling once the body says it's done, so the last frame counts as the end too
+        if frame.is_none() || self.inner.is_end_stream() {
+            if let Some(on_end) = self.on_end.take() {
+                on_end();
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// passes body through untouched and calls on_end once it has been read to the end
+pub fn on_body_end(body: Body, on_end: impl FnOnce() + Send + Sync + 'static) -> Body {
+    Body::from(BoxBody::new(NotifyOnEnd {
+        inner: body,
+        on_end: Some(Box::new(on_end))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(hold_large_bodies: bool) -> BodyStorageConfig {
+        BodyStorageConfig {
+            spill_threshold: 4,
+            hold_large_bodies,
+            ..BodyStorageConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn large_bodies_stream_unless_held() {
+        let body = || Body::from(http_body_util::Full::new(Bytes::from_static(b"more than four bytes")));
+        match capture_body(body(), &HeaderMap::new(), &limits(false)).await {
+            CapturedBody::Pending(pending) => assert_eq!(pending.reason, PendingReason::TooLarge),
+            CapturedBody::Complete(_) => panic!("body over the threshold was buffered")
+        }
+        match capture_body(body(), &HeaderMap::new(), &limits(true)).await {
+            CapturedBody::Complete(data) => assert_eq!(&data[..], b"more than four bytes"),
+            CapturedBody::Pending(_) => panic!("held body was streamed")
+        }
+    }
+
+    #[tokio::test]
+    async fn teed_body_spills_to_file() {
+        let spill_path = std::env::temp_dir().join(format!("telescope-test-{}", std::process::id())).join("body");
+        let pending = match capture_body(Body::from(http_body_util::Full::new(Bytes::from_static(b"0123456789"))), &HeaderMap::new(), &limits(false)).await {
+            CapturedBody::Pending(pending) => pending,
+            CapturedBody::Complete(_) => panic!("body over the threshold was buffered")
+        };
+        let recorded = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let on_recorded = recorded.clone();
+        let body = tee_body(pending, BodyRecorder::new(spill_path.clone(), limits(false)), move |body, done| {
+            if done {
+                *on_recorded.lock().unwrap() = Some(body);
+            }
+        });
+        assert_eq!(&body.collect().await.unwrap().to_bytes()[..], b"0123456789");
+
+        let recorded = recorded.lock().unwrap().take().expect("body never finished");
+        assert!(recorded.body.is_file());
+        assert!(!recorded.truncated);
+        assert_eq!(std::fs::read(&spill_path).unwrap(), b"0123456789");
+        let _ = std::fs::remove_dir_all(spill_path.parent().unwrap());
+    }
+
+    #[test]
+    fn stale_spills_are_removed_unless_a_flow_uses_them() {
+        use crate::resource::{FlowContent, HTTPPair, RequestMeta, RequestOrResponse};
+
+        let data_dir = std::env::temp_dir().join(format!("telescope-test-spills-{}", std::process::id()));
+        std::fs::create_dir_all(data_dir.join(SPILL_DIR)).unwrap();
+        let kept = spill_path(&data_dir, "kept", "request");
+        let stale = spill_path(&data_dir, "gone", "request");
+        std::fs::write(&kept, b"body").unwrap();
+        std::fs::write(&stale, b"body").unwrap();
+        let cutoff = SystemTime::now() + Duration::from_secs(60);
+
+        let request = RequestOrResponse::new_request(Resource::File(FileResource::new(&kept.to_string_lossy())), HeaderMap::new(), RequestMeta::new("http://example.com/", "GET", "HTTP/1.1"));
+        let storage = RwLock::new(FlowStorage::new());
+        storage.write().unwrap().add_flow(Flow::new_with_id("kept".to_string(), FlowContent::RequestResponse(HTTPPair::new_request(request))));
+        remove_stale_spills(&data_dir, cutoff, &storage);
+        assert!(kept.exists());
+        assert!(!stale.exists());
+
+        storage.write().unwrap().remove_flow("kept");
+        assert!(!kept.exists());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn streaming_content_types_are_never_held() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "text/event-stream; charset=utf-8".parse().unwrap());
+        match capture_body(Body::empty(), &headers, &limits(true)).await {
+            CapturedBody::Pending(pending) => assert_eq!(pending.reason, PendingReason::Streaming),
+            CapturedBody::Complete(_) => panic!("stream was buffered")
+        }
+    }
+}
@@ -0,0 +1,413 @@
+// Burp Suite's "save items" xml, every item is a raw request and response plus where it went
+// burp keeps messages exactly as sent so chunked bodies are chunked in there, ours are stored without the framing
+use base64::Engine;
+use hyper::{header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST, TRANSFER_ENCODING}, HeaderMap, StatusCode};
+use log::warn;
+use quick_xml::{events::Event, Reader};
+use time::{format_description::{self, OwnedFormatItem}, OffsetDateTime, PrimitiveDateTime};
+
+use crate::resource::{get_current_time, Flow, FlowContent, HTTPPair, MemoryResource, RequestMeta, RequestOrResponse, Resource, ResponseMeta};
+
+const DOCTYPE: &str = r#"<!DOCTYPE items [
+<!ELEMENT items (item*)>
+<!ATTLIST items burpVersion CDATA "">
+<!ATTLIST items exportTime CDATA "">
+<!ELEMENT item (time, url, host, port, protocol, method, path, extension, request, status, responselength, mimetype, response, comment)>
+<!ELEMENT time (#PCDATA)>
+<!ELEMENT url (#PCDATA)>
+<!ELEMENT host (#PCDATA)>
+<!ATTLIST host ip CDATA "">
+<!ELEMENT port (#PCDATA)>
+<!ELEMENT protocol (#PCDATA)>
+<!ELEMENT method (#PCDATA)>
+<!ELEMENT path (#PCDATA)>
+<!ELEMENT extension (#PCDATA)>
+<!ELEMENT request (#PCDATA)>
+<!ATTLIST request base64 (true|false) "false">
+<!ELEMENT status (#PCDATA)>
+<!ELEMENT responselength (#PCDATA)>
+<!ELEMENT mimetype (#PCDATA)>
+<!ELEMENT response (#PCDATA)>
+<!ATTLIST response base64 (true|false) "false">
+<!ELEMENT comment (#PCDATA)>
+]>"#;
+
+// one <item>, fields burp leaves out stay empty
+#[derive(Debug, Clone, Default)]
+pub struct BurpItem {
+    pub time: String,
+    pub url: String,
+    pub host: String,
+    pub ip: String,
+    pub port: String,
+    pub protocol: String,
+    pub method: String,
+    pub path: String,
+    pub extension: String,
+    pub request: Vec<u8>,
+    pub status: String,
+    pub mime_type: String,
+    pub response: Option<Vec<u8>>,
+    pub comment: String,
+}
+
+// java's Date.toString, like "Mon Jan 01 12:00:00 UTC 2024"
+fn time_format(with_zone: bool) -> OwnedFormatItem {
+    let description = match with_zone {
+        true => "[weekday repr:short] [month repr:short] [day] [hour]:[minute]:[second] UTC [year]",
+        false => "[weekday repr:short] [month repr:short] [day] [hour]:[minute]:[second] [year]"
+    };
+    format_description::parse_owned::<2>(description).expect("burp time format is valid")
+}
+
+fn format_time(millis: u128) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000).ok()
+        .and_then(|time| time.format(&time_format(true)).ok())
+        .unwrap_or_default()
+}
+
+// the zone is whatever the exporting machine had, there's no table of those names so it's read as utc
+fn parse_time(text: &str) -> Option<u128> {
+    let mut parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 6 {
+        return None;
+    }
+    parts.remove(4);
+    let time = PrimitiveDateTime::parse(&parts.join(" "), &time_format(false)).ok()?.assume_utc();
+    u128::try_from(time.unix_timestamp_nanos() / 1_000_000).ok()
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    headers.get_all(TRANSFER_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("chunked"))
+}
+
+fn chunk(body: &[u8]) -> Vec<u8> {
+    let mut chunked = Vec::with_capacity(body.len() + 16);
+    if !body.is_empty() {
+        chunked.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        chunked.extend_from_slice(body);
+        chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(b"0\r\n\r\n");
+    chunked
+}
+
+// a cut off body keeps whatever chunks made it
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(line_end) = chunked.windows(2).position(|window| window == b"\r\n") {
+        let size = std::str::from_utf8(&chunked[..line_end]).ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16).ok());
+        let Some(size) = size.filter(|size| *size > 0) else {
+            break;
+        };
+        let start = line_end + 2;
+        // the size comes from the file, anything past the end is a truncated body rather than an overflow
+        let end = match start.checked_add(size) {
+            Some(end) if end <= chunked.len() => end,
+            _ => {
+                body.extend_from_slice(&chunked[start..]);
+                break;
+            }
+        };
+        body.extend_from_slice(&chunked[start..end]);
+        chunked = chunked.get(end + 2..).unwrap_or_default();
+    }
+    body
+}
+
+fn raw_message(first_line: String, message: &RequestOrResponse) -> Vec<u8> {
+    let mut raw = first_line.into_bytes();
+    raw.extend_from_slice(b"\r\n");
+    for (name, value) in message.headers.iter() {
+        raw.extend_from_slice(name.as_str().as_bytes());
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(value.as_bytes());
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw.extend_from_slice(b"\r\n");
+    // a body that went missing from disk still leaves the rest of the message worth exporting
+    let body = message.body.as_bytes().unwrap_or_else(|e| {
+        warn!("exporting without body: {}", e);
+        Vec::new()
+    });
+    match is_chunked(&message.headers) {
+        true => raw.extend_from_slice(&chunk(&body)),
+        false => raw.extend_from_slice(&body)
+    }
+    raw
+}
+
+// the first line split in three, then headers, then the body with any chunked framing taken off
+fn parse_raw_message(raw: &[u8]) -> Result<([String; 3], HeaderMap, Vec<u8>), String> {
+    let (head, body) = match raw.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => (&raw[..end], &raw[end + 4..]),
+        None => match raw.windows(2).position(|window| window == b"\n\n") {
+            Some(end) => (&raw[..end], &raw[end + 2..]),
+            None => (raw, &[][..])
+        }
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let mut first_line = lines.next().unwrap_or_default().splitn(3, ' ').map(|part| part.trim().to_string());
+    let first_line = [first_line.next().unwrap_or_default(), first_line.next().unwrap_or_default(), first_line.next().unwrap_or_default()];
+    if first_line[1].is_empty() {
+        return Err("message has no start line".to_string());
+    }
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match (HeaderName::from_bytes(name.trim().as_bytes()), HeaderValue::from_str(value.trim())) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            },
+            _ => warn!("skipping invalid header {}", line)
+        }
+    }
+    let body = match is_chunked(&headers) {
+        true => dechunk(body),
+        false => body.to_vec()
+    };
+    Ok((first_line, headers, body))
+}
+
+// burp's own categories, close enough from the content type
+fn mime_type(response: Option<&RequestOrResponse>) -> &'static str {
+    let Some(content_type) = response.and_then(|response| response.headers.get(CONTENT_TYPE)).and_then(|value| value.to_str().ok()) else {
+        return "";
+    };
+    let content_type = content_type.to_ascii_lowercase();
+    let categories: [(&[&str], &str); 5] = [
+        (&["html"], "HTML"),
+        (&["json"], "JSON"),
+        (&["javascript", "ecmascript"], "script"),
+        (&["css"], "CSS"),
+        (&["xml"], "XML")
+    ];
+    if let Some((_, category)) = categories.iter().find(|(needles, _)| needles.iter().any(|needle| content_type.contains(needle))) {
+        return category;
+    }
+    if content_type.starts_with("image/") {
+        "image"
+    } else if content_type.starts_with("text/") {
+        "text"
+    } else {
+        "app"
+    }
+}
+
+fn cdata(text: &str) -> String {
+    // the only thing that can't go in a cdata section is its own end
+    format!("<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+fn base64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+pub fn burp_item(flow: &Flow) -> Option<BurpItem> {
+    let http_pair = match &flow.content {
+        FlowContent::RequestResponse(http_pair) => http_pair,
+        FlowContent::WebSocket(websocket) => &websocket.handshake,
+        FlowContent::Tunnel(_) => return None
+    };
+    let request_meta = http_pair.request.meta.unwrap_request_ref();
+    let url = &request_meta.url;
+    let target = match request_meta.method.as_str() {
+        "CONNECT" => format!("{}:{}", url.host_str().unwrap_or_default(), url.port_or_known_default().unwrap_or(443)),
+        _ => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string()
+        }
+    };
+    let mut request = http_pair.request.clone();
+    // http/2 has :authority instead, burp wants a host to make sense of the request
+    if !request.headers.contains_key(HOST) {
+        if let Ok(host) = HeaderValue::from_str(&url[url::Position::BeforeHost..url::Position::AfterPort]) {
+            request.headers.insert(HOST, host);
+        }
+    }
+    let response = http_pair.response.as_ref().map(|response| {
+        let response_meta = response.meta.unwrap_response_ref();
+        let reason = StatusCode::from_u16(response_meta.status as u16).ok().and_then(|status| status.canonical_reason()).unwrap_or_default();
+        raw_message(format!("{} {} {}", response_meta.version, response_meta.status, reason), response)
+    });
+    let extension = url.path_segments().and_then(|mut segments| segments.next_back())
+        .and_then(|name| name.rsplit_once('.')).map(|(_, extension)| extension.to_string())
+        .unwrap_or_else(|| "null".to_string());
+    Some(BurpItem {
+        time: format_time(request_meta.created_at),
+        url: url.to_string(),
+        host: url.host_str().unwrap_or_default().to_string(),
+        ip: String::new(),
+        port: url.port_or_known_default().unwrap_or_default().to_string(),
+        protocol: url.scheme().to_string(),
+        method: request_meta.method.clone(),
+        path: target.clone(),
+        extension,
+        request: raw_message(format!("{} {} {}", request_meta.method, target, request_meta.version), &request),
+        status: http_pair.response.as_ref().map(|response| response.meta.unwrap_response_ref().status.to_string()).unwrap_or_default(),
+        mime_type: mime_type(http_pair.response.as_ref()).to_string(),
+        response,
+        comment: String::new()
+    })
+}
+
+pub fn export_burp(flows: &[Flow]) -> Vec<u8> {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n");
+    xml.push_str(DOCTYPE);
+    xml.push_str(&format!("\n<items burpVersion=\"Telescope {}\" exportTime=\"{}\">\n", env!("CARGO_PKG_VERSION"), format_time(get_current_time())));
+    for item in flows.iter().filter_map(burp_item) {
+        let response = item.response.as_deref().unwrap_or_default();
+        xml.push_str("  <item>\n");
+        xml.push_str(&format!("    <time>{}</time>\n", item.time));
+        xml.push_str(&format!("    <url>{}</url>\n", cdata(&item.url)));
+        xml.push_str(&format!("    <host ip=\"{}\">{}</host>\n", quick_xml::escape::escape(&item.ip), quick_xml::escape::escape(&item.host)));
+        xml.push_str(&format!("    <port>{}</port>\n", item.port));
+        xml.push_str(&format!("    <protocol>{}</protocol>\n", item.protocol));
+        xml.push_str(&format!("    <method>{}</method>\n", cdata(&item.method)));
+        xml.push_str(&format!("    <path>{}</path>\n", cdata(&item.path)));
+        xml.push_str(&format!("    <extension>{}</extension>\n", quick_xml::escape::escape(&item.extension)));
+        xml.push_str(&format!("    <request base64=\"true\">{}</request>\n", cdata(&base64(&item.request))));
+        xml.push_str(&format!("    <status>{}</status>\n", item.status));
+        xml.push_str(&format!("    <responselength>{}</responselength>\n", response.len()));
+        xml.push_str(&format!("    <mimetype>{}</mimetype>\n", item.mime_type));
+        xml.push_str(&format!("    <response base64=\"true\">{}</response>\n", cdata(&base64(response))));
+        xml.push_str(&format!("    <comment>{}</comment>\n", quick_xml::escape::escape(&item.comment)));
+        xml.push_str("  </item>\n");
+    }
+    xml.push_str("</items>\n");
+    xml.into_bytes()
+}
+
+pub fn flow_from_item(item: &BurpItem) -> Result<Flow, String> {
+    let created_at = parse_time(&item.time).unwrap_or_else(get_current_time);
+    let ([method, target, version], request_headers, request_body) = parse_raw_message(&item.request)?;
+    let url = match item.url.is_empty() {
+        false => item.url.clone(),
+        true if method == "CONNECT" => target.clone(),
+        true => format!("{}://{}:{}{}", item.protocol, item.host, item.port, target)
+    };
+    let mut request_meta = RequestMeta::new_checked(&url, &method, &version)?;
+    request_meta.created_at = created_at;
+    let mut http_pair = HTTPPair::new_request(RequestOrResponse::new_request(Resource::Memory(MemoryResource::new(request_body)), request_headers, request_meta));
+
+    if let Some(response) = item.response.as_ref().filter(|response| !response.is_empty()) {
+        let ([version, status, _], response_headers, response_body) = parse_raw_message(response)?;
+        let status = status.parse().map_err(|_| format!("invalid status {}", status))?;
+        let mut response_meta = ResponseMeta::new(status, &version);
+        response_meta.created_at = created_at;
+        http_pair.add_response(RequestOrResponse::new_response(Resource::Memory(MemoryResource::new(response_body)), response_headers, response_meta));
+    }
+
+    let mut flow = Flow::new(FlowContent::RequestResponse(http_pair));
+    flow.is_active = false;
+    flow.timings.started_at = created_at;
+    Ok(flow)
+}
+
+fn decode_message(text: &str, is_base64: bool) -> Result<Vec<u8>, String> {
+    match is_base64 {
+        true => base64::engine::general_purpose::STANDARD.decode(text.trim()).map_err(|e| format!("invalid base64 message: {}", e)),
+        false => Ok(text.as_bytes().to_vec())
+    }
+}
+
+pub fn parse_burp(data: &[u8]) -> Result<Vec<BurpItem>, String> {
+    let mut reader = Reader::from_reader(data);
+    let mut items = Vec::new();
+    let mut item: Option<BurpItem> = None;
+    // the element we're inside of and whether its text is base64
+    let mut field = (String::new(), false);
+    let mut text = String::new();
+    loop {
+        let event = reader.read_event().map_err(|e| format!("invalid xml at {}: {}", reader.buffer_position(), e))?;
+        match event {
+            Event::Start(start) => {
+                let name = String::from_utf8_lossy(start.name().as_ref()).to_string();
+                match name.as_str() {
+                    "item" => item = Some(BurpItem::default()),
+                    "host" => {
+                        if let (Some(item), Ok(Some(ip))) = (&mut item, start.try_get_attribute("ip")) {
+                            item.ip = String::from_utf8_lossy(&ip.value).to_string();
+                        }
+                    },
+                    _ => {}
+                }
+                let is_base64 = matches!(start.try_get_attribute("base64"), Ok(Some(attribute)) if attribute.value.as_ref() == b"true");
+                field = (name, is_base64);
+                text.clear();
+            },
+            Event::Text(content) => text.push_str(&content.unescape().map_err(|e| e.to_string())?),
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content.into_inner())),
+            Event::End(end) => {
+                let name = String::from_utf8_lossy(end.name().as_ref()).to_string();
+                if name == "item" {
+                    items.extend(item.take());
+                } else if let Some(item) = &mut item {
+                    let value = text.trim().to_string();
+                    match name.as_str() {
+                        "time" => item.time = value,
+                        "url" => item.url = value,
+                        "host" => item.host = value,
+                        "port" => item.port = value,
+                        "protocol" => item.protocol = value,
+                        "method" => item.method = value,
+                        "path" => item.path = value,
+                        "extension" => item.extension = value,
+                        "status" => item.status = value,
+                        "mimetype" => item.mime_type = value,
+                        "comment" => item.comment = value,
+                        "request" => item.request = decode_message(&text, field.1)?,
+                        "response" => item.response = Some(decode_message(&text, field.1)?),
+                        _ => {}
+                    }
+                }
+                text.clear();
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(items)
+}
+
+// items that can't be converted are skipped with a warning
+pub fn import_burp(data: &[u8]) -> Result<Vec<Flow>, String> {
+    let items = parse_burp(data)?;
+    let mut flows = Vec::with_capacity(items.len());
+    for item in &items {
+        match flow_from_item(item) {
+            Ok(flow) => flows.push(flow),
+            Err(e) => warn!("skipping burp item {}: {}", item.url, e)
+        }
+    }
+    Ok(flows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dechunk_joins_chunks() {
+        assert_eq!(dechunk(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n"), b"hello world");
+        assert_eq!(dechunk(&chunk(b"round trip")), b"round trip");
+    }
+
+    #[test]
+    fn dechunk_keeps_truncated_chunk() {
+        assert_eq!(dechunk(b"a\r\nhello"), b"hello");
+    }
+
+    #[test]
+    fn dechunk_oversized_chunk_header() {
+        assert_eq!(dechunk(b"ffffffffffffffff\r\nhello\r\n0\r\n\r\n"), b"hello\r\n0\r\n\r\n");
+        // too big for a usize at all, read as the end of the body
+        assert_eq!(dechunk(b"3\r\nabc\r\nfffffffffffffffffffff\r\nhello"), b"abc");
+    }
+}
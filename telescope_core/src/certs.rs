@@ -1,9 +1,10 @@
 use std::path::PathBuf;
 
+use hudsucker::{certificate_authority::RcgenAuthority, rustls::crypto::aws_lc_rs};
 use log::info;
 use rcgen::{generate_simple_self_signed, BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyUsagePurpose};
 
-use crate::{config::Config, resource::{FileResource, Resource}};
+use crate::{config::Config, resource::{FileResource, ResolveString, Resource}};
 
 pub trait CertDerivable {
     fn derive_cert(&mut self) -> Result<(PathBuf, PathBuf), std::io::Error>;
@@ -48,4 +49,12 @@ impl CertDerivable for Config {
             (cert_path, key_path)
         )
     }
-}
\ No newline at end of file
+}
+
+// the authority that mints leaf certificates, the http listener and the reverse proxy each get one
+pub fn build_authority(config: &Config) -> Result<RcgenAuthority, hudsucker::rcgen::Error> {
+    let key_pair = hudsucker::rcgen::KeyPair::from_pem(&config.resolve_string(&config.ca.key_pair))?;
+    let ca_cert = hudsucker::rcgen::CertificateParams::from_ca_cert_pem(&config.resolve_string(&config.ca.certificate))?
+        .self_signed(&key_pair)?;
+    Ok(RcgenAuthority::new(key_pair, ca_cert, 1_000, aws_lc_rs::default_provider()))
+}
@@ -0,0 +1,483 @@
+// "copy as code", turns a recorded request into something that sends it again outside the proxy
+// bodies are replayed byte for byte, so anything that isn't text gets escaped rather than mangled
+use hyper::header::{HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, COOKIE, HOST, TRANSFER_ENCODING};
+use reqwest::Url;
+
+use crate::resource::RequestOrResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeLanguage {
+    Curl,
+    PythonRequests,
+    JavaScriptFetch,
+    GoNetHttp,
+    RustReqwest,
+}
+
+impl CodeLanguage {
+    pub const ALL: [CodeLanguage; 5] = [CodeLanguage::Curl, CodeLanguage::PythonRequests, CodeLanguage::JavaScriptFetch, CodeLanguage::GoNetHttp, CodeLanguage::RustReqwest];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodeLanguage::Curl => "curl",
+            CodeLanguage::PythonRequests => "Python requests",
+            CodeLanguage::JavaScriptFetch => "JavaScript fetch",
+            CodeLanguage::GoNetHttp => "Go net/http",
+            CodeLanguage::RustReqwest => "Rust reqwest"
+        }
+    }
+}
+
+// everything the generators need, pulled out of the request once
+struct CodeRequest<'a> {
+    method: &'a str,
+    url: &'a Url,
+    // in order, duplicates kept, minus what the client works out for itself
+    headers: Vec<(&'a HeaderName, &'a HeaderValue)>,
+    body: Vec<u8>,
+    truncated: bool,
+}
+
+impl<'a> CodeRequest<'a> {
+    fn new(request: &'a RequestOrResponse) -> std::io::Result<Self> {
+        let meta = request.meta.unwrap_request_ref();
+        let headers = request.headers.iter()
+            .filter(|(name, value)| match *name {
+                // framing is up to the client, the body might not even be the same length when truncated
+                name if *name == CONTENT_LENGTH || *name == TRANSFER_ENCODING => false,
+                // the url already says where to go, only keep a host that doesn't match it
+                name if *name == HOST => value.as_bytes() != url_authority(&meta.url).as_bytes(),
+                _ => true
+            })
+            .collect();
+        Ok(Self {
+            method: &meta.method,
+            url: &meta.url,
+            headers,
+            body: request.body.as_bytes()?,
+            truncated: request.body_truncated
+        })
+    }
+
+    // the Host header if it was kept
+    fn host(&self) -> Option<&'a HeaderValue> {
+        self.headers.iter().find(|(name, _)| **name == HOST).map(|(_, value)| *value)
+    }
+}
+
+fn url_authority(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string()
+    }
+}
+
+pub fn request_to_code(request: &RequestOrResponse, language: CodeLanguage) -> std::io::Result<String> {
+    let request = CodeRequest::new(request)?;
+    Ok(match language {
+        CodeLanguage::Curl => curl(&request),
+        CodeLanguage::PythonRequests => python_requests(&request),
+        CodeLanguage::JavaScriptFetch => javascript_fetch(&request),
+        CodeLanguage::GoNetHttp => go_net_http(&request),
+        CodeLanguage::RustReqwest => rust_reqwest(&request)
+    })
+}
+
+fn truncated_note(request: &CodeRequest, comment: &str) -> String {
+    match request.truncated {
+        true => format!("{} the recorded body was truncated, this only sends the part that was kept\n", comment),
+        false => String::new()
+    }
+}
+
+// bidi overrides and isolates, rustc refuses them in literals and nobody wants them unescaped anyway
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+// a double quoted literal for languages with c style escapes, `escape` writes whatever has no short form
+fn escape_text(text: &str, escape: fn(char) -> String) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() || is_bidi_control(c) => escaped.push_str(&escape(c)),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// the inside of a byte string, python b"", rust b"" and go "" all read \xHH as a raw byte
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len() + 2);
+    escaped.push('"');
+    for byte in bytes {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            b' '..=b'~' => escaped.push(*byte as char),
+            byte => escaped.push_str(&format!("\\x{:02x}", byte))
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// shell words, plain single quotes unless there's something in there a terminal shouldn't see raw
+fn shell_quote(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(|c| (c.is_control() && c != '\n' && c != '\t') || is_bidi_control(c)) => {
+            format!("'{}'", text.replace('\'', "'\\''"))
+        },
+        // ansi-c quoting, bash and zsh both have it
+        _ => {
+            let mut quoted = String::from("$'");
+            for byte in bytes {
+                match byte {
+                    b'\\' => quoted.push_str("\\\\"),
+                    b'\'' => quoted.push_str("\\'"),
+                    b'\n' => quoted.push_str("\\n"),
+                    b'\r' => quoted.push_str("\\r"),
+                    b'\t' => quoted.push_str("\\t"),
+                    b' '..=b'~' => quoted.push(*byte as char),
+                    byte => quoted.push_str(&format!("\\x{:02x}", byte))
+                }
+            }
+            quoted.push('\'');
+            quoted
+        }
+    }
+}
+
+// a printf format that writes the bytes back out, the only way to get a nul into curl from a shell
+fn printf_format(bytes: &[u8]) -> String {
+    let mut format = String::from("'");
+    for byte in bytes {
+        match byte {
+            b'%' => format.push_str("%%"),
+            byte @ b' '..=b'~' if *byte != b'\\' && *byte != b'\'' => format.push(*byte as char),
+            // octal is all posix printf promises
+            byte => format.push_str(&format!("\\{:03o}", byte))
+        }
+    }
+    format.push('\'');
+    format
+}
+
+fn curl(request: &CodeRequest) -> String {
+    let mut args = vec![shell_quote(request.url.as_str().as_bytes())];
+    // without it curl treats [] and {} in the url as ranges
+    if request.url.as_str().contains(['[', ']', '{', '}']) {
+        args.push("--globoff".to_string());
+    }
+    match (request.method, request.body.is_empty()) {
+        ("GET", true) | ("POST", false) => {},
+        // -X HEAD would sit there waiting for a body
+        ("HEAD", _) => args.push("--head".to_string()),
+        (method, _) => args.push(format!("-X {}", shell_quote(method.as_bytes())))
+    }
+    for (name, value) in &request.headers {
+        let mut header = name.as_str().as_bytes().to_vec();
+        // "name:" would tell curl to leave the header out
+        match value.is_empty() {
+            true => header.push(b';'),
+            false => {
+                header.extend_from_slice(b": ");
+                header.extend_from_slice(value.as_bytes());
+            }
+        }
+        args.push(format!("-H {}", shell_quote(&header)));
+    }
+    if request.headers.iter().any(|(name, _)| **name == ACCEPT_ENCODING) {
+        args.push("--compressed".to_string());
+    }
+    let mut code = truncated_note(request, "#");
+    if !request.body.is_empty() {
+        match request.body.contains(&0) {
+            true => {
+                code.push_str(&format!("printf {} | \\\n  ", printf_format(&request.body)));
+                args.push("--data-binary @-".to_string());
+            },
+            false => args.push(format!("--data-binary {}", shell_quote(&request.body)))
+        }
+    }
+    code.push_str("curl ");
+    code.push_str(&args.join(" \\\n  "));
+    code.push('\n');
+    code
+}
+
+fn python_text(text: &str) -> String {
+    escape_text(text, |c| match c as u32 {
+        code @ 0..=0xff => format!("\\x{:02x}", code),
+        code => format!("\\u{:04x}", code)
+    })
+}
+
+// str when it decodes, bytes otherwise, requests takes either
+fn python_value(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => python_text(text),
+        Err(_) => format!("b{}", escape_bytes(bytes))
+    }
+}
+
+fn python_requests(request: &CodeRequest) -> String {
+    let mut code = truncated_note(request, "#");
+    code.push_str("import requests\n\n");
+    code.push_str(&format!("url = {}\n", python_text(request.url.as_str())));
+    if !request.headers.is_empty() {
+        // a dict can't hold a header twice, repeats get folded the way they'd be folded on the wire
+        let mut headers: Vec<(&HeaderName, Vec<u8>)> = Vec::new();
+        for (name, value) in &request.headers {
+            match headers.iter_mut().find(|(existing, _)| **existing == **name) {
+                Some((_, existing)) => {
+                    existing.extend_from_slice(if **name == COOKIE { b"; " } else { b", " });
+                    existing.extend_from_slice(value.as_bytes());
+                },
+                None => headers.push((*name, value.as_bytes().to_vec()))
+            }
+        }
+        code.push_str("headers = {\n");
+        for (name, value) in &headers {
+            code.push_str(&format!("    {}: {},\n", python_text(name.as_str()), python_value(value)));
+        }
+        code.push_str("}\n");
+    }
+    if !request.body.is_empty() {
+        // always bytes, requests would send a str as latin-1
+        match std::str::from_utf8(&request.body) {
+            Ok(text) => code.push_str(&format!("data = {}.encode()\n", python_text(text))),
+            Err(_) => code.push_str(&format!("data = b{}\n", escape_bytes(&request.body)))
+        }
+    }
+    code.push_str(&format!("\nresponse = requests.request({}, url", python_text(request.method)));
+    if !request.headers.is_empty() {
+        code.push_str(", headers=headers");
+    }
+    if !request.body.is_empty() {
+        code.push_str(", data=data");
+    }
+    code.push_str(")\n");
+    code.push_str("print(response.status_code)\nprint(response.text)\n");
+    code
+}
+
+fn javascript_text(text: &str) -> String {
+    escape_text(text, |c| match c as u32 {
+        code @ 0..=0xffff => format!("\\u{:04x}", code),
+        code => format!("\\u{{{:x}}}", code)
+    })
+}
+
+// header values are byte strings to fetch, one char per byte
+fn javascript_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            b' '..=b'~' => escaped.push(*byte as char),
+            byte => escaped.push_str(&format!("\\x{:02x}", byte))
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn javascript_fetch(request: &CodeRequest) -> String {
+    let mut code = truncated_note(request, "//");
+    code.push_str(&format!("const response = await fetch({}, {{\n", javascript_text(request.url.as_str())));
+    code.push_str(&format!("  method: {},\n", javascript_text(request.method)));
+    if !request.headers.is_empty() {
+        // pairs rather than an object so repeated headers survive
+        code.push_str("  headers: [\n");
+        for (name, value) in &request.headers {
+            code.push_str(&format!("    [{}, {}],\n", javascript_text(name.as_str()), javascript_bytes(value.as_bytes())));
+        }
+        code.push_str("  ],\n");
+    }
+    if !request.body.is_empty() {
+        match (request.method, std::str::from_utf8(&request.body)) {
+            ("GET" | "HEAD", _) => code.push_str("  // fetch refuses to send a body with GET or HEAD, it was left out\n"),
+            (_, Ok(text)) => code.push_str(&format!("  body: {},\n", javascript_text(text))),
+            (_, Err(_)) => {
+                let bytes: Vec<String> = request.body.iter().map(|byte| byte.to_string()).collect();
+                code.push_str(&format!("  body: new Uint8Array([{}]),\n", bytes.join(", ")));
+            }
+        }
+    }
+    code.push_str("});\n");
+    code.push_str("console.log(response.status);\nconsole.log(await response.text());\n");
+    code
+}
+
+// go strings are just bytes, but the source has to be valid utf-8
+fn go_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => escape_text(text, |c| format!("\\u{:04x}", c as u32)),
+        Err(_) => escape_bytes(bytes)
+    }
+}
+
+fn go_net_http(request: &CodeRequest) -> String {
+    let mut code = truncated_note(request, "//");
+    code.push_str("package main\n\nimport (\n\t\"fmt\"\n\t\"io\"\n\t\"net/http\"\n");
+    if !request.body.is_empty() {
+        code.push_str("\t\"strings\"\n");
+    }
+    code.push_str(")\n\nfunc main() {\n");
+    let body = match request.body.is_empty() {
+        true => "nil",
+        false => {
+            code.push_str(&format!("\tbody := strings.NewReader({})\n", go_string(&request.body)));
+            "body"
+        }
+    };
+    code.push_str(&format!("\treq, err := http.NewRequest({}, {}, {})\n", go_string(request.method.as_bytes()), go_string(request.url.as_str().as_bytes()), body));
+    code.push_str("\tif err != nil {\n\t\tpanic(err)\n\t}\n");
+    for (name, value) in &request.headers {
+        // net/http ignores a Host header, it only goes out through req.Host
+        if **name == HOST {
+            continue;
+        }
+        code.push_str(&format!("\treq.Header.Add({}, {})\n", go_string(name.as_str().as_bytes()), go_string(value.as_bytes())));
+    }
+    if let Some(host) = request.host() {
+        code.push_str(&format!("\treq.Host = {}\n", go_string(host.as_bytes())));
+    }
+    code.push_str("\tresp, err := http.DefaultClient.Do(req)\n\tif err != nil {\n\t\tpanic(err)\n\t}\n\tdefer resp.Body.Close()\n");
+    code.push_str("\trespBody, err := io.ReadAll(resp.Body)\n\tif err != nil {\n\t\tpanic(err)\n\t}\n");
+    code.push_str("\tfmt.Println(resp.Status)\n\tfmt.Println(string(respBody))\n}\n");
+    code
+}
+
+fn rust_text(text: &str) -> String {
+    escape_text(text, |c| format!("\\u{{{:x}}}", c as u32))
+}
+
+const STANDARD_METHODS: [&str; 9] = ["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "PATCH", "TRACE"];
+
+fn rust_reqwest(request: &CodeRequest) -> String {
+    let mut code = truncated_note(request, "//");
+    code.push_str("#[tokio::main]\nasync fn main() -> Result<(), Box<dyn std::error::Error>> {\n");
+    code.push_str("    let client = reqwest::Client::new();\n");
+    let method = match STANDARD_METHODS.contains(&request.method) {
+        true => format!("reqwest::Method::{}", request.method),
+        false => format!("reqwest::Method::from_bytes(b{})?", escape_bytes(request.method.as_bytes()))
+    };
+    code.push_str(&format!("    let response = client\n        .request({}, {})\n", method, rust_text(request.url.as_str())));
+    for (name, value) in &request.headers {
+        // a &str header value has to be visible ascii, anything else goes in as bytes
+        let value = match value.to_str() {
+            Ok(text) => rust_text(text),
+            Err(_) => format!("reqwest::header::HeaderValue::from_bytes(b{})?", escape_bytes(value.as_bytes()))
+        };
+        code.push_str(&format!("        .header({}, {})\n", rust_text(name.as_str()), value));
+    }
+    if !request.body.is_empty() {
+        match std::str::from_utf8(&request.body) {
+            Ok(text) => code.push_str(&format!("        .body({})\n", rust_text(text))),
+            Err(_) => code.push_str(&format!("        .body(b{}.to_vec())\n", escape_bytes(&request.body)))
+        }
+    }
+    code.push_str("        .send()\n        .await?;\n");
+    code.push_str("    println!(\"{}\", response.status());\n    println!(\"{}\", response.text().await?);\n    Ok(())\n}\n");
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+
+    use crate::resource::{MemoryResource, RequestMeta, Resource};
+
+    use super::*;
+
+    fn request(method: &str, url: &str, headers: &[(&str, &[u8])], body: &[u8]) -> RequestOrResponse {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_bytes(value).unwrap());
+        }
+        RequestOrResponse::new_request(Resource::Memory(MemoryResource::new(body.to_vec())), map, RequestMeta::new(url, method, "HTTP/1.1"))
+    }
+
+    #[test]
+    fn text_literals_escape_quotes_controls_and_bidi() {
+        assert_eq!(python_text("a\"b\\c\nd\u{0}\u{202e}"), "\"a\\\"b\\\\c\\nd\\x00\\u202e\"");
+        assert_eq!(javascript_text("\u{1b}\u{2066}é"), "\"\\u001b\\u2066é\"");
+        assert_eq!(rust_text("\u{7f}\u{202a}\t"), "\"\\u{7f}\\u{202a}\\t\"");
+        assert_eq!(go_string("\u{0}\"".as_bytes()), "\"\\u0000\\\"\"");
+    }
+
+    #[test]
+    fn invalid_utf8_falls_back_to_byte_escapes() {
+        assert_eq!(escape_bytes(b"ok\xff\x00\""), "\"ok\\xff\\x00\\\"\"");
+        assert_eq!(go_string(b"\xc3("), "\"\\xc3(\"");
+        assert_eq!(python_value(b"\xfe"), "b\"\\xfe\"");
+        assert_eq!(javascript_bytes(b"a\x80\""), "\"a\\x80\\\"\"");
+    }
+
+    #[test]
+    fn shell_quote_switches_to_ansi_c_for_unprintables() {
+        assert_eq!(shell_quote(b"it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(b"line\nnext"), "'line\nnext'");
+        assert_eq!(shell_quote(b"a\x1b[31m'"), "$'a\\x1b[31m\\''");
+        assert_eq!(shell_quote("\u{202e}".as_bytes()), "$'\\xe2\\x80\\xae'");
+        assert_eq!(shell_quote(b"\xff"), "$'\\xff'");
+    }
+
+    #[test]
+    fn printf_format_escapes_percent_and_quotes() {
+        assert_eq!(printf_format(b"100%\x00'\\"), "'100%%\\000\\047\\134'");
+    }
+
+    #[test]
+    fn curl_pipes_bodies_with_nul_through_printf() {
+        let code = request_to_code(&request("PUT", "http://example.com/[a]", &[("x-empty", b"")], b"a\x00b"), CodeLanguage::Curl).unwrap();
+        assert!(code.starts_with("printf 'a\\000b' | \\\n  curl 'http://example.com/[a]'"));
+        assert!(code.contains("--globoff"));
+        assert!(code.contains("-X 'PUT'"));
+        assert!(code.contains("-H 'x-empty;'"));
+        assert!(code.contains("--data-binary @-"));
+    }
+
+    #[test]
+    fn client_managed_headers_are_left_out() {
+        let request = request("POST", "http://example.com/", &[("host", b"example.com"), ("content-length", b"2"), ("accept", b"*/*")], b"hi");
+        let code = request_to_code(&request, CodeLanguage::Curl).unwrap();
+        assert!(!code.contains("host"));
+        assert!(!code.contains("content-length"));
+        assert!(!code.contains("-X"));
+        assert!(code.contains("-H 'accept: */*'"));
+    }
+
+    #[test]
+    fn python_folds_repeated_headers() {
+        let request = request("GET", "http://example.com/", &[("cookie", b"a=1"), ("cookie", b"b=2"), ("accept", b"x"), ("accept", b"y")], b"");
+        let code = request_to_code(&request, CodeLanguage::PythonRequests).unwrap();
+        assert!(code.contains("\"cookie\": \"a=1; b=2\","));
+        assert!(code.contains("\"accept\": \"x, y\","));
+    }
+
+    #[test]
+    fn fetch_drops_get_bodies_and_rust_handles_custom_methods() {
+        let code = request_to_code(&request("GET", "http://example.com/", &[], b"body"), CodeLanguage::JavaScriptFetch).unwrap();
+        assert!(!code.contains("body:"));
+        let code = request_to_code(&request("PURGE", "http://example.com/", &[], b""), CodeLanguage::RustReqwest).unwrap();
+        assert!(code.contains("reqwest::Method::from_bytes(b\"PURGE\")?"));
+        let code = request_to_code(&request("GET", "http://example.com/", &[("host", b"other.test")], b""), CodeLanguage::GoNetHttp).unwrap();
+        assert!(code.contains("req.Host = \"other.test\""));
+        assert!(!code.contains("req.Header.Add(\"host\""));
+    }
+}
@@ -3,7 +3,7 @@ use std::{net::SocketAddr, path::PathBuf};
 use log::error;
 use serde::{Deserialize, Serialize};
 
-use crate::resource::{FileResource, Resource};
+use crate::{matching::{optional_wildcard_match, wildcard_match}, resource::{FileResource, RequestMeta, Resource}};
 
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -12,11 +12,414 @@ pub struct CertificateAuthority {
     pub certificate: Resource
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct InterceptConfig {
+    pub intercept_requests: bool,
+    pub intercept_responses: bool,
+    pub intercept_websocket_messages: bool,
+    // wildcard patterns, empty means match everything
+    pub host_filter: String,
+    pub path_filter: String,
+    pub method_filter: String,
+}
+
+impl InterceptConfig {
+    pub fn matches_request(&self, request: &RequestMeta) -> bool {
+        optional_wildcard_match(&self.host_filter, request.url.host_str().unwrap_or(""))
+            && optional_wildcard_match(&self.path_filter, request.url.path())
+            && optional_wildcard_match(&self.method_filter, &request.method)
+    }
+
+    pub fn should_intercept_request(&self, request: &RequestMeta) -> bool {
+        // CONNECT requests are proxy plumbing, holding them would just stall the tunnel
+        self.intercept_requests && !request.is_proxy_client_connection() && self.matches_request(request)
+    }
+
+    // responses are matched by the request that produced them
+    pub fn should_intercept_response(&self, request: &RequestMeta) -> bool {
+        self.intercept_responses && !request.is_proxy_client_connection() && self.matches_request(request)
+    }
+
+    // frames are matched by the upgrade request of their websocket
+    pub fn should_intercept_websocket_message(&self, handshake: &RequestMeta) -> bool {
+        self.intercept_websocket_messages && self.matches_request(handshake)
+    }
+
+    pub fn is_intercepting(&self) -> bool {
+        self.intercept_requests || self.intercept_responses || self.intercept_websocket_messages
+    }
+}
+
+// one include/exclude entry, every non empty pattern has to match
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ScopeRule {
+    pub enabled: bool,
+    // wildcard patterns, empty means match everything
+    pub scheme: String,
+    pub host: String,
+    pub port: String,
+    pub path: String,
+}
+
+impl Default for ScopeRule {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            scheme: String::new(),
+            host: String::new(),
+            port: String::new(),
+            path: String::new()
+        }
+    }
+}
+
+impl ScopeRule {
+    pub fn matches(&self, target: &ScopeTarget) -> bool {
+        self.enabled
+            && optional_wildcard_match(&self.scheme, &target.scheme)
+            && optional_wildcard_match(&self.host, &target.host)
+            && optional_wildcard_match(&self.port, &target.port.map(|port| port.to_string()).unwrap_or_default())
+            && optional_wildcard_match(&self.path, &target.path)
+    }
+}
+
+// the parts of a request scope rules look at
+#[derive(Debug, Clone, Default)]
+pub struct ScopeTarget {
+    pub scheme: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl ScopeTarget {
+    pub fn from_uri(uri: &hyper::Uri) -> Self {
+        // CONNECT targets only have an authority
+        if uri.scheme().is_none() {
+            return Self::connect(uri.host().unwrap_or_default(), uri.port_u16());
+        }
+        let scheme = uri.scheme_str().unwrap_or_default().to_string();
+        let port = uri.port_u16().or(match scheme.as_str() {
+            "http" | "ws" => Some(80),
+            "https" | "wss" => Some(443),
+            _ => None
+        });
+        Self {
+            scheme,
+            host: uri.host().unwrap_or_default().to_string(),
+            port,
+            path: uri.path().to_string()
+        }
+    }
+
+    pub fn from_request(request: &RequestMeta) -> Self {
+        if request.is_proxy_client_connection() {
+            // new_checked keeps host:port as is when it parses, otherwise it becomes https://host:port/
+            let uri = request.url_str().parse::<hyper::Uri>().unwrap_or_default();
+            return match uri.scheme().is_none() {
+                true => Self::from_uri(&uri),
+                false => Self::connect(uri.host().unwrap_or_default(), request.url.port_or_known_default())
+            };
+        }
+        Self {
+            scheme: request.url.scheme().to_string(),
+            host: request.url.host_str().unwrap_or_default().to_string(),
+            port: request.url.port_or_known_default(),
+            path: request.url.path().to_string()
+        }
+    }
+
+    // a tunnel has no path, the scheme is a guess from the port
+    fn connect(host: &str, port: Option<u16>) -> Self {
+        let scheme = match port {
+            Some(80) => "http",
+            Some(443) => "https",
+            _ => ""
+        };
+        Self {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            port,
+            path: String::new()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfScopeAction {
+    // passes through the proxy without being recorded, plugins and intercept don't see it either
+    #[default]
+    Forward,
+    // recorded but left out of the flow list, plugins and intercept skip it too
+    Hide,
+}
+
+impl OutOfScopeAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutOfScopeAction::Forward => "Forward without recording",
+            OutOfScopeAction::Hide => "Record but hide"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ScopeConfig {
+    pub enabled: bool,
+    // no enabled include rules means everything is included
+    pub include: Vec<ScopeRule>,
+    pub exclude: Vec<ScopeRule>,
+    pub out_of_scope: OutOfScopeAction,
+}
+
+impl ScopeConfig {
+    pub fn is_in_scope(&self, target: &ScopeTarget) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let included = !self.include.iter().any(|rule| rule.enabled) || self.include.iter().any(|rule| rule.matches(target));
+        included && !self.exclude.iter().any(|rule| rule.matches(target))
+    }
+
+    pub fn should_record(&self, uri: &hyper::Uri) -> bool {
+        self.out_of_scope != OutOfScopeAction::Forward || self.is_in_scope(&ScopeTarget::from_uri(uri))
+    }
+
+    pub fn should_show(&self, request: &RequestMeta) -> bool {
+        self.out_of_scope != OutOfScopeAction::Hide || self.is_in_scope(&ScopeTarget::from_request(request))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpstreamProxyKind {
+    // tunnels everything with CONNECT, plain http included
+    #[default]
+    Http,
+    Socks5,
+}
+
+impl UpstreamProxyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamProxyKind::Http => "HTTP",
+            UpstreamProxyKind::Socks5 => "SOCKS5"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct UpstreamProxy {
+    pub kind: UpstreamProxyKind,
+    // host:port of the upstream proxy
+    pub address: String,
+    // empty username means no credentials
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UpstreamRule {
+    pub enabled: bool,
+    // wildcard pattern for the destination host
+    pub host: String,
+    // None connects directly
+    pub proxy: Option<UpstreamProxy>,
+}
+
+impl Default for UpstreamRule {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: String::new(),
+            proxy: None
+        }
+    }
+}
+
+// used for everything the proxy connects out to, requests, websocket upgrades and tunnels alike
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    pub enabled: bool,
+    // used for hosts no rule matches, None connects directly
+    pub proxy: Option<UpstreamProxy>,
+    // first enabled match wins
+    pub rules: Vec<UpstreamRule>,
+}
+
+impl UpstreamConfig {
+    pub fn proxy_for(&self, host: &str) -> Option<&UpstreamProxy> {
+        if !self.enabled {
+            return None;
+        }
+        match self.rules.iter().find(|rule| rule.enabled && optional_wildcard_match(&rule.host, host)) {
+            Some(rule) => rule.proxy.as_ref(),
+            None => self.proxy.as_ref()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TlsVerificationConfig {
+    // trust the operating system's roots, the bundled mozilla ones are used if it has none
+    pub system_roots: bool,
+    // pem ca certificates trusted on top, for staging environments with their own ca
+    pub extra_cas: Vec<Resource>,
+    // wildcard host patterns whose certificates aren't checked at all
+    pub insecure_hosts: Vec<String>,
+}
+
+impl Default for TlsVerificationConfig {
+    fn default() -> Self {
+        Self {
+            system_roots: true,
+            extra_cas: Vec::new(),
+            insecure_hosts: Vec::new()
+        }
+    }
+}
+
+impl TlsVerificationConfig {
+    pub fn is_insecure(&self, host: &str) -> bool {
+        self.insecure_hosts.iter().any(|pattern| wildcard_match(pattern, host))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ClientCertRule {
+    pub enabled: bool,
+    // wildcard pattern for the upstream host, unlike other rules an empty one matches nothing so certs don't leak everywhere
+    pub host: String,
+    // pem certificate chain and private key, file paths are relative to data_dir
+    pub certificate: Resource,
+    pub key: Resource,
+}
+
+impl Default for ClientCertRule {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: String::new(),
+            certificate: Resource::File(FileResource::new("client.pem")),
+            key: Resource::File(FileResource::new("client-key.pem"))
+        }
+    }
+}
+
+impl ClientCertRule {
+    pub fn matches(&self, host: &str) -> bool {
+        self.enabled && !self.host.is_empty() && wildcard_match(&self.host, host)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReverseProxyConfig {
+    // off when unset
+    pub listen_addr: Option<SocketAddr>,
+    // origin everything gets forwarded to, like http://localhost:3000, a path in it is prepended to every request
+    pub upstream: String,
+    // serve https with a certificate from our ca instead of plain http
+    pub tls: bool,
+    // keep the Host header the client sent instead of the upstream's
+    pub preserve_host: bool,
+}
+
+impl Default for ReverseProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            upstream: String::from("http://localhost:3000"),
+            tls: false,
+            preserve_host: false
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BodyStorageConfig {
+    // bodies bigger than this stream through the proxy and get written to <data_dir>/bodies
+    pub spill_threshold: usize,
+    // past this only a prefix of the body is kept
+    pub max_recorded_size: u64,
+    // a body that goes quiet for this long is treated as a stream and forwarded as it arrives
+    pub stream_idle_timeout_ms: u64,
+    // hold bodies over spill_threshold in memory, up to max_recorded_size, so plugins and intercept still see them
+    // streams and stalled bodies always go straight through
+    pub hold_large_bodies: bool,
+}
+
+impl Default for BodyStorageConfig {
+    fn default() -> Self {
+        Self {
+            spill_threshold: 8 * 1024 * 1024,
+            max_recorded_size: 512 * 1024 * 1024,
+            stream_idle_timeout_ms: 1000,
+            hold_large_bodies: false
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ProjectConfig {
+    // keep captured flows in a sqlite file so they survive restarts and crashes
+    pub enabled: bool,
+    // relative to data_dir
+    pub path: String,
+    // how often captured changes get written out, at most this much is lost in a crash
+    pub flush_interval_ms: u64,
+}
+
+impl Default for ProjectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "telescope_flows.sqlite".to_string(),
+            flush_interval_ms: 1000
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub ca: CertificateAuthority,
     pub addr: SocketAddr,
+    // socks4a/socks5 listener next to the http one, off when unset
+    #[serde(default)]
+    pub socks_addr: Option<SocketAddr>,
+    // listener for iptables/nftables redirected traffic, off when unset
+    #[serde(default)]
+    pub transparent_addr: Option<SocketAddr>,
     pub data_dir: PathBuf,
+    #[serde(default)]
+    pub intercept: InterceptConfig,
+    #[serde(default)]
+    pub body_storage: BodyStorageConfig,
+    #[serde(default)]
+    pub scope: ScopeConfig,
+    // host wildcard patterns whose CONNECT tunnels are relayed without mitm, for pinned or mutual tls hosts
+    #[serde(default)]
+    pub tls_passthrough: Vec<String>,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub reverse: ReverseProxyConfig,
+    // client certificates presented to upstreams that ask for one, first match wins
+    #[serde(default)]
+    pub client_certs: Vec<ClientCertRule>,
+    #[serde(default)]
+    pub upstream_tls: TlsVerificationConfig,
+    #[serde(default)]
+    pub project: ProjectConfig,
     #[serde(skip)]
     // default to false
     #[serde(default)]
@@ -32,7 +435,18 @@ impl Default for Config {
                 // String::from("certificate.pem")
             },
             addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            socks_addr: None,
+            transparent_addr: None,
             data_dir: std::env::current_dir().unwrap(),
+            intercept: InterceptConfig::default(),
+            body_storage: BodyStorageConfig::default(),
+            scope: ScopeConfig::default(),
+            tls_passthrough: Vec::new(),
+            upstream: UpstreamConfig::default(),
+            reverse: ReverseProxyConfig::default(),
+            client_certs: Vec::new(),
+            upstream_tls: TlsVerificationConfig::default(),
+            project: ProjectConfig::default(),
             loaded: false
         }
     }
@@ -67,8 +481,58 @@ impl Config {
     pub fn update_data_dir(&mut self, data_dir: PathBuf) {
         self.data_dir = data_dir;
     }
+
+    pub fn is_tls_passthrough(&self, host: &str) -> bool {
+        self.tls_passthrough.iter().any(|pattern| !pattern.trim().is_empty() && wildcard_match(pattern.trim(), host))
+    }
+
+    pub fn client_cert_for(&self, host: &str) -> Option<&ClientCertRule> {
+        self.client_certs.iter().find(|rule| rule.matches(host))
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let config_str = toml::to_string_pretty(self).map_err(|e| format!("could not serialize config: {}", e))?;
+        std::fs::write(self.data_dir.join("telescope_proxy.toml"), config_str).map_err(|e| format!("could not write config: {}", e))
+    }
 }
 
 impl Config {
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target_fields(target: ScopeTarget) -> (String, String, Option<u16>, String) {
+        (target.scheme, target.host, target.port, target.path)
+    }
+
+    #[test]
+    fn connect_targets_match_before_and_after_recording() {
+        for (authority, scheme, host, port) in [
+            ("example.com:443", "https", "example.com", 443),
+            ("127.0.0.1:443", "https", "127.0.0.1", 443),
+            ("example.com:80", "http", "example.com", 80),
+            ("127.0.0.1:8443", "", "127.0.0.1", 8443),
+        ] {
+            let expected = (scheme.to_string(), host.to_string(), Some(port), String::new());
+            let uri: hyper::Uri = authority.parse().unwrap();
+            assert_eq!(target_fields(ScopeTarget::from_uri(&uri)), expected);
+            let request = RequestMeta::new_checked(authority, "CONNECT", "HTTP/1.1").unwrap();
+            assert_eq!(target_fields(ScopeTarget::from_request(&request)), expected, "{}", authority);
+        }
+    }
+
+    #[test]
+    fn path_rules_leave_tunnels_alone() {
+        let scope = ScopeConfig {
+            enabled: true,
+            exclude: vec![ScopeRule { path: "/static/*".to_string(), ..Default::default() }],
+            ..Default::default()
+        };
+        let uri: hyper::Uri = "example.com:443".parse().unwrap();
+        assert!(scope.is_in_scope(&ScopeTarget::from_uri(&uri)));
+        let uri: hyper::Uri = "https://example.com/static/app.js".parse().unwrap();
+        assert!(!scope.is_in_scope(&ScopeTarget::from_uri(&uri)));
+    }
+}
@@ -0,0 +1,141 @@
+// details about the connections a flow went over, shown in the connection tab
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use hudsucker::rustls::ClientConnection;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+// hudsucker doesn't say when a client connection opens or closes, so one that's been quiet this long is assumed gone
+// in case the client reuses the port, a keep-alive connection idle for longer shows up as a new one
+const CONNECTION_IDLE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    // address our other listeners connect to the http listener from, and the client they're doing it for
+    forwarded: HashMap<SocketAddr, SocketAddr>,
+    // client address as the http listener sees it, and its connection id and when it was last used
+    connections: HashMap<SocketAddr, (u64, Instant)>,
+    next_id: u64,
+}
+
+impl ConnectionTracker {
+    // the real client and connection id for a client_addr from HttpContext
+    pub fn identify(&mut self, client_addr: SocketAddr) -> (SocketAddr, u64) {
+        let now = Instant::now();
+        self.connections.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < CONNECTION_IDLE);
+        let next_id = &mut self.next_id;
+        let (id, last_seen) = self.connections.entry(client_addr).or_insert_with(|| {
+            *next_id += 1;
+            (*next_id, now)
+        });
+        *last_seen = now;
+        (self.forwarded.get(&client_addr).copied().unwrap_or(client_addr), *id)
+    }
+}
+
+// registers a connection to the http listener made on behalf of client, until dropped
+pub struct ForwardedConnection {
+    tracker: Arc<Mutex<ConnectionTracker>>,
+    local_addr: SocketAddr,
+}
+
+impl ForwardedConnection {
+    pub fn new(tracker: Arc<Mutex<ConnectionTracker>>, local_addr: SocketAddr, client: SocketAddr) -> Self {
+        tracker.lock().unwrap().forwarded.insert(local_addr, client);
+        Self {
+            tracker,
+            local_addr
+        }
+    }
+}
+
+impl Drop for ForwardedConnection {
+    fn drop(&mut self) {
+        let mut tracker = self.tracker.lock().unwrap();
+        tracker.forwarded.remove(&self.local_addr);
+        // the port is free for reuse now, the next connection from it is a different one
+        tracker.connections.remove(&self.local_addr);
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConnectionInfo {
+    // who made the request, clients of the socks, transparent and reverse listeners are followed back to themselves
+    // None for flows that weren't proxied, like imported ones
+    pub client_addr: Option<SocketAddr>,
+    // requests sharing a keep-alive or http/2 connection, including everything inside one CONNECT tunnel, share this
+    pub connection_id: Option<u64>,
+    // None for plain http, and for tunnels and websockets which hudsucker connects itself
+    pub tls: Option<TlsInfo>,
+    // why the request never got a response, certificate verification failures end up here
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsInfo {
+    pub sni: String,
+    pub version: String,
+    pub cipher_suite: String,
+    pub alpn: Option<String>,
+    // as the server sent it, leaf first
+    pub certificates: Vec<CertificateInfo>,
+}
+
+impl TlsInfo {
+    // after the handshake is done, everything here is settled by then
+    pub fn from_connection(sni: &str, connection: &ClientConnection) -> Self {
+        Self {
+            sni: sni.to_string(),
+            version: connection.protocol_version().map(|version| format!("{:?}", version)).unwrap_or_default(),
+            cipher_suite: connection.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite())).unwrap_or_default(),
+            alpn: connection.alpn_protocol().map(|alpn| String::from_utf8_lossy(alpn).to_string()),
+            certificates: connection.peer_certificates().unwrap_or_default().iter()
+                .filter_map(|certificate| match CertificateInfo::from_der(certificate) {
+                    Ok(info) => Some(info),
+                    Err(e) => {
+                        warn!("failed to parse certificate from {}: {}", sni, e);
+                        None
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    // dns names and ip addresses from the subject alt name extension
+    pub sans: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    pub serial: String,
+}
+
+impl CertificateInfo {
+    pub fn from_der(der: &[u8]) -> Result<Self, String> {
+        let (_, certificate) = X509Certificate::from_der(der).map_err(|e| e.to_string())?;
+        let sans = match certificate.subject_alternative_name() {
+            Ok(Some(extension)) => extension.value.general_names.iter().map(|name| match name {
+                GeneralName::DNSName(name) => name.to_string(),
+                GeneralName::IPAddress(bytes) => match bytes.len() {
+                    4 => std::net::Ipv4Addr::from(<[u8; 4]>::try_from(*bytes).unwrap()).to_string(),
+                    16 => std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*bytes).unwrap()).to_string(),
+                    _ => format!("{:?}", bytes)
+                },
+                other => other.to_string()
+            }).collect(),
+            _ => Vec::new()
+        };
+        Ok(Self {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            sans,
+            not_before: certificate.validity().not_before.to_string(),
+            not_after: certificate.validity().not_after.to_string(),
+            serial: certificate.raw_serial_as_string()
+        })
+    }
+}
@@ -0,0 +1,456 @@
+// the project file, captured flows get written to sqlite as they change so a crash loses at most one flush
+// bodies and websocket messages are left in the database and only read back when something looks at them
+use std::{collections::{HashMap, HashSet}, io, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::{body::remove_stale_spills, proxy::FlowStorage, resource::{DatabaseMessages, DatabaseResource, Flow, FlowContent, MemoryResource, RequestOrResponse, Resource, WebSocketFlow, WebSocketMessage}};
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS flows (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    flow TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS bodies (
+    flow_id TEXT NOT NULL,
+    side TEXT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (flow_id, side)
+);
+CREATE TABLE IF NOT EXISTS websocket_messages (
+    flow_id TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (flow_id, idx)
+);
+";
+
+// the parts of a flow that still have to be written, copied out of the storage
+struct FlowSnapshot {
+    // bodies already in the database are DatabaseResources here and websocket messages start at first_message
+    flow: Flow,
+    first_message: usize,
+}
+
+// one flow as it goes into the database
+struct FlowRecord {
+    id: String,
+    // the flow without its bodies or websocket messages
+    flow: String,
+    bodies: Vec<(&'static str, Vec<u8>)>,
+    // only the messages that aren't in the database yet
+    messages: Vec<(usize, String)>,
+}
+
+fn side_mut(flow: &mut Flow, is_response: bool) -> Option<&mut RequestOrResponse> {
+    let http_pair = flow.content.http_pair_mut();
+    match is_response {
+        true => http_pair.response.as_mut(),
+        false => Some(&mut http_pair.request)
+    }
+}
+
+pub struct FlowDatabase {
+    connection: Connection,
+    path: PathBuf,
+    // how many messages of each websocket flow were already written, they only ever get appended
+    written_messages: HashMap<String, usize>,
+}
+
+impl FlowDatabase {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection,
+            path: path.to_path_buf(),
+            written_messages: HashMap::new()
+        })
+    }
+
+    // only the flows themselves, bodies stay as DatabaseResources and websocket messages as DatabaseMessages until they're needed
+    pub fn load(&mut self) -> rusqlite::Result<Vec<Flow>> {
+        // how many messages there are and the index the next one goes at
+        let mut message_counts: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut statement = self.connection.prepare("SELECT flow_id, COUNT(*), MAX(idx) FROM websocket_messages GROUP BY flow_id")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let flow_id: String = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            let last_idx: i64 = row.get(2)?;
+            message_counts.insert(flow_id, (count as usize, last_idx as usize + 1));
+        }
+
+        let mut flows = Vec::new();
+        let mut statement = self.connection.prepare("SELECT id, flow FROM flows ORDER BY seq")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            let flow: String = row.get(1)?;
+            let mut flow: Flow = match serde_json::from_str(&flow) {
+                Ok(flow) => flow,
+                Err(e) => {
+                    warn!("skipping unreadable flow {}: {}", id, e);
+                    continue;
+                }
+            };
+            // whatever was still going on when it was written is never going to finish
+            flow.is_active = false;
+            if let FlowContent::WebSocket(websocket) = &mut flow.content {
+                if let Some((count, next_idx)) = message_counts.remove(&id) {
+                    websocket.stored_messages = Some(DatabaseMessages {
+                        path: self.path.to_string_lossy().to_string(),
+                        flow_id: id.clone(),
+                        count,
+                        loading: false
+                    });
+                    self.written_messages.insert(id, next_idx);
+                }
+            }
+            flows.push(flow);
+        }
+        Ok(flows)
+    }
+
+    fn database_body(&self, flow_id: &str, side: &str, size: u64) -> Resource {
+        Resource::Database(DatabaseResource {
+            path: self.path.to_string_lossy().to_string(),
+            flow_id: flow_id.to_string(),
+            side: side.to_string(),
+            size
+        })
+    }
+
+    // copies what the database doesn't have yet out of a flow in the storage, called with the storage locked
+    // bodies already written and websocket messages before written_messages are moved aside while the rest is cloned
+    fn snapshot(&self, flow: &mut Flow, changed_bodies: &HashSet<(String, bool)>) -> FlowSnapshot {
+        let mut moved_bodies = Vec::new();
+        for is_response in [false, true] {
+            let changed = changed_bodies.contains(&(flow.id.clone(), is_response));
+            let Some(message) = side_mut(flow, is_response) else {
+                continue;
+            };
+            let in_memory = matches!(&message.body, Resource::Memory(memory) if !memory.buffer.is_empty()) || matches!(message.body, Resource::String(_));
+            if in_memory && !changed {
+                let body = std::mem::replace(&mut message.body, Resource::Memory(MemoryResource::new(Vec::new())));
+                moved_bodies.push((is_response, body));
+            }
+        }
+        let (messages, first_message) = match &mut flow.content {
+            FlowContent::WebSocket(websocket) => {
+                // messages not read back yet are all written already, there's nothing in the vec to skip
+                let written = self.written_messages.get(&flow.id).copied().unwrap_or(0).min(websocket.messages.len());
+                let messages = std::mem::take(&mut websocket.messages);
+                (messages, written)
+            },
+            _ => (Vec::new(), 0)
+        };
+
+        let mut snapshot = flow.clone();
+        if let FlowContent::WebSocket(websocket) = &mut snapshot.content {
+            websocket.messages = messages[first_message..].to_vec();
+        }
+        if let FlowContent::WebSocket(websocket) = &mut flow.content {
+            websocket.messages = messages;
+        }
+        for (is_response, body) in moved_bodies {
+            let side = if is_response { "response" } else { "request" };
+            if let Some(message) = side_mut(&mut snapshot, is_response) {
+                message.body = self.database_body(&flow.id, side, body.size());
+            }
+            if let Some(message) = side_mut(flow, is_response) {
+                message.body = body;
+            }
+        }
+        FlowSnapshot {
+            flow: snapshot,
+            first_message
+        }
+    }
+
+    fn record(&self, snapshot: FlowSnapshot) -> serde_json::Result<FlowRecord> {
+        let FlowSnapshot { flow, first_message } = snapshot;
+        let (mut content, messages) = match flow.content {
+            FlowContent::WebSocket(websocket) => {
+                let mut messages = Vec::new();
+                for (idx, message) in websocket.messages.iter().enumerate() {
+                    messages.push((first_message + idx, serde_json::to_string(message)?));
+                }
+                (FlowContent::WebSocket(WebSocketFlow {
+                    messages: Vec::new(),
+                    ..websocket
+                }), messages)
+            },
+            content => (content, Vec::new())
+        };
+
+        // changed bodies held in memory move to their own table, files under data_dir/bodies stay where they are
+        let mut bodies = Vec::new();
+        let http_pair = content.http_pair_mut();
+        let sides = [("request", Some(&mut http_pair.request)), ("response", http_pair.response.as_mut())];
+        for (side, message) in sides {
+            let Some(message) = message else {
+                continue;
+            };
+            let data = match &mut message.body {
+                Resource::Memory(memory) if !memory.buffer.is_empty() => std::mem::take(&mut memory.buffer),
+                Resource::String(string) => std::mem::take(&mut string.string).into_bytes(),
+                _ => continue
+            };
+            message.body = self.database_body(&flow.id, side, data.len() as u64);
+            bodies.push((side, data));
+        }
+
+        let stripped = Flow {
+            content,
+            ..flow
+        };
+        Ok(FlowRecord {
+            flow: serde_json::to_string(&stripped)?,
+            id: stripped.id,
+            bodies,
+            messages
+        })
+    }
+
+    fn write(&mut self, records: Vec<FlowRecord>, removed: &[String]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for record in &records {
+            transaction.execute("INSERT INTO flows (id, flow) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET flow = excluded.flow", params![record.id, record.flow])?;
+            for (side, data) in &record.bodies {
+                transaction.execute("INSERT OR REPLACE INTO bodies (flow_id, side, data) VALUES (?1, ?2, ?3)", params![record.id, side, data])?;
+            }
+            for (idx, message) in &record.messages {
+                transaction.execute("INSERT OR REPLACE INTO websocket_messages (flow_id, idx, message) VALUES (?1, ?2, ?3)", params![record.id, *idx as i64, message])?;
+            }
+        }
+        for id in removed {
+            transaction.execute("DELETE FROM flows WHERE id = ?1", params![id])?;
+            transaction.execute("DELETE FROM bodies WHERE flow_id = ?1", params![id])?;
+            transaction.execute("DELETE FROM websocket_messages WHERE flow_id = ?1", params![id])?;
+        }
+        transaction.commit()?;
+        // only once it's really on disk
+        for record in records {
+            if let Some((idx, _)) = record.messages.last() {
+                self.written_messages.insert(record.id, idx + 1);
+            }
+        }
+        for id in removed {
+            self.written_messages.remove(id);
+        }
+        Ok(())
+    }
+
+    // writes out whatever changed in the storage since the last flush
+    pub fn flush(&mut self, flow_storage: &RwLock<FlowStorage>) -> rusqlite::Result<()> {
+        // the proxy waits on this lock, so only copy out what isn't written yet while holding it
+        let (snapshots, changes) = {
+            let mut flow_storage = flow_storage.write().unwrap();
+            let changes = flow_storage.take_changes();
+            let snapshots: Vec<FlowSnapshot> = changes.changed.iter()
+                .filter_map(|id| flow_storage.flows.get_mut(id).map(|flow| self.snapshot(flow, &changes.changed_bodies)))
+                .collect();
+            (snapshots, changes)
+        };
+        let mut records = Vec::with_capacity(snapshots.len());
+        for snapshot in snapshots {
+            let id = snapshot.flow.id.clone();
+            match self.record(snapshot) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("failed to serialize flow {}: {}", id, e)
+            }
+        }
+        if records.is_empty() && changes.removed.is_empty() {
+            return Ok(());
+        }
+        let result = self.write(records, &changes.removed);
+        if result.is_err() {
+            // bodies it was going to write are now left out of the snapshots, they have to go next time
+            flow_storage.write().unwrap().restore_changes(changes);
+        }
+        result
+    }
+}
+
+// loads the project into the storage and keeps writing changes to it from a thread of its own
+pub fn persist_flows(path: &Path, data_dir: &Path, flow_storage: Arc<RwLock<FlowStorage>>, flush_interval: Duration) -> rusqlite::Result<()> {
+    let mut database = FlowDatabase::open(path)?;
+    // from here on nothing captured gets missed, even while the old flows are still loading
+    flow_storage.write().unwrap().track_changes();
+    let started_at = SystemTime::now();
+    let path = path.to_path_buf();
+    let data_dir = data_dir.to_path_buf();
+    std::thread::spawn(move || {
+        match database.load() {
+            Ok(flows) => {
+                info!("loaded {} flows from {}", flows.len(), path.display());
+                flow_storage.write().unwrap().add_loaded_flows(flows);
+                // only once every flow that could point at a body file is back
+                remove_stale_spills(&data_dir, started_at, &flow_storage);
+            },
+            Err(e) => error!("failed to load flows from {}: {}", path.display(), e)
+        }
+        // the proxy is gone once we hold the last reference
+        while Arc::strong_count(&flow_storage) > 1 {
+            std::thread::sleep(flush_interval);
+            if let Err(e) = database.flush(&flow_storage) {
+                error!("failed to write flows to {}: {}", path.display(), e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// fills in the messages of a loaded websocket flow, on a thread of its own so the ui doesn't wait on the database
+pub fn load_websocket_messages(flow_storage: Arc<RwLock<FlowStorage>>, flow_id: String) {
+    let stored = {
+        let mut flow_storage = flow_storage.write().unwrap();
+        // straight at the map, reading the messages back isn't a change worth writing
+        let Some(FlowContent::WebSocket(websocket)) = flow_storage.flows.get_mut(&flow_id).map(|flow| &mut flow.content) else {
+            return;
+        };
+        match &mut websocket.stored_messages {
+            Some(stored) if !stored.loading => {
+                stored.loading = true;
+                stored.clone()
+            },
+            _ => return
+        }
+    };
+    std::thread::spawn(move || {
+        let messages = read_websocket_messages(&stored);
+        let mut flow_storage = flow_storage.write().unwrap();
+        let Some(FlowContent::WebSocket(websocket)) = flow_storage.flows.get_mut(&flow_id).map(|flow| &mut flow.content) else {
+            return;
+        };
+        match messages {
+            Ok(messages) => {
+                websocket.messages = messages;
+                websocket.stored_messages = None;
+            },
+            // left marked as loading so it doesn't get retried every frame
+            Err(e) => error!("failed to read websocket messages of flow {}: {}", flow_id, e)
+        }
+    });
+}
+
+pub fn read_websocket_messages(stored: &DatabaseMessages) -> io::Result<Vec<WebSocketMessage>> {
+    let connection = Connection::open_with_flags(&stored.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(io::Error::other)?;
+    let mut statement = connection.prepare("SELECT message FROM websocket_messages WHERE flow_id = ?1 ORDER BY idx")
+        .map_err(io::Error::other)?;
+    let mut rows = statement.query(params![stored.flow_id]).map_err(io::Error::other)?;
+    let mut messages = Vec::with_capacity(stored.count);
+    while let Some(row) = rows.next().map_err(io::Error::other)? {
+        let message: String = row.get(0).map_err(io::Error::other)?;
+        match serde_json::from_str(&message) {
+            Ok(message) => messages.push(message),
+            Err(e) => warn!("skipping unreadable websocket message of flow {}: {}", stored.flow_id, e)
+        }
+    }
+    Ok(messages)
+}
+
+// a separate read only connection so the ui never waits on the writer
+pub fn read_body(resource: &DatabaseResource, max: Option<usize>) -> io::Result<Vec<u8>> {
+    let connection = Connection::open_with_flags(&resource.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(io::Error::other)?;
+    let result = match max {
+        // substr on a blob counts bytes
+        Some(max) => connection.query_row("SELECT substr(data, 1, ?3) FROM bodies WHERE flow_id = ?1 AND side = ?2", params![resource.flow_id, resource.side, max as i64], |row| row.get(0)),
+        None => connection.query_row("SELECT data FROM bodies WHERE flow_id = ?1 AND side = ?2", params![resource.flow_id, resource.side], |row| row.get(0))
+    };
+    result.map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+
+    use super::*;
+    use crate::resource::{HTTPPair, MemoryResource, RequestMeta, RequestOrResponse, WebSocketDirection, WebSocketOpcode};
+
+    fn request(body: &[u8]) -> RequestOrResponse {
+        RequestOrResponse::new_request(Resource::Memory(MemoryResource::new(body.to_vec())), HeaderMap::new(), RequestMeta::new("http://example.com/", "POST", "HTTP/1.1"))
+    }
+
+    #[test]
+    fn flows_come_back_with_messages_left_in_the_database() {
+        let path = std::env::temp_dir().join(format!("telescope-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let storage = RwLock::new(FlowStorage::new());
+        storage.write().unwrap().track_changes();
+        let mut websocket = WebSocketFlow::new(HTTPPair::new_request(request(b"")));
+        websocket.add_message(WebSocketMessage::new(WebSocketDirection::ClientToServer, WebSocketOpcode::Text, b"first".to_vec()));
+        websocket.add_message(WebSocketMessage::new(WebSocketDirection::ServerToClient, WebSocketOpcode::Binary, vec![1, 2]));
+        storage.write().unwrap().add_flow(Flow::new_with_id("http".to_string(), FlowContent::RequestResponse(HTTPPair::new_request(request(b"hello")))));
+        storage.write().unwrap().add_flow(Flow::new_with_id("websocket".to_string(), FlowContent::WebSocket(websocket)));
+
+        let mut database = FlowDatabase::open(&path).unwrap();
+        database.flush(&storage).unwrap();
+        // the storage keeps its own copies
+        assert!(matches!(storage.read().unwrap().get_flow("http").unwrap().content.http_pair().request.body, Resource::Memory(_)));
+
+        let flows = FlowDatabase::open(&path).unwrap().load().unwrap();
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].content.http_pair().request.body.as_bytes().unwrap(), b"hello");
+        let FlowContent::WebSocket(websocket) = &flows[1].content else {
+            panic!("not a websocket flow");
+        };
+        assert!(websocket.messages.is_empty());
+        assert_eq!(websocket.message_count(), 2);
+        let messages = read_websocket_messages(websocket.stored_messages.as_ref().unwrap()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].payload, b"first");
+        assert_eq!(messages[1].payload, vec![1, 2]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn flush_only_writes_what_changed() {
+        let path = std::env::temp_dir().join(format!("telescope-test-changes-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let count = |sql: &str| Connection::open(&path).unwrap().query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+
+        let storage = RwLock::new(FlowStorage::new());
+        storage.write().unwrap().track_changes();
+        storage.write().unwrap().add_flow(Flow::new_with_id("http".to_string(), FlowContent::RequestResponse(HTTPPair::new_request(request(b"hello")))));
+        let mut websocket = WebSocketFlow::new(HTTPPair::new_request(request(b"")));
+        websocket.add_message(WebSocketMessage::new(WebSocketDirection::ClientToServer, WebSocketOpcode::Text, b"first".to_vec()));
+        storage.write().unwrap().add_flow(Flow::new_with_id("websocket".to_string(), FlowContent::WebSocket(websocket)));
+        let mut database = FlowDatabase::open(&path).unwrap();
+        database.flush(&storage).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM bodies"), 1);
+
+        // anything still in the database from here on was left alone by the next flush
+        Connection::open(&path).unwrap().execute_batch("DELETE FROM bodies; DELETE FROM websocket_messages;").unwrap();
+        storage.write().unwrap().get_flow_mut("http").unwrap().is_active = false;
+        if let FlowContent::WebSocket(websocket) = &mut storage.write().unwrap().get_flow_mut("websocket").unwrap().content {
+            websocket.add_message(WebSocketMessage::new(WebSocketDirection::ServerToClient, WebSocketOpcode::Text, b"second".to_vec()));
+        }
+        database.flush(&storage).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM bodies"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM websocket_messages"), 1);
+        assert_eq!(count("SELECT idx FROM websocket_messages"), 1);
+        // the flow still points at its body and the storage still holds it
+        let flow: String = Connection::open(&path).unwrap().query_row("SELECT flow FROM flows WHERE id = 'http'", [], |row| row.get(0)).unwrap();
+        let flow: Flow = serde_json::from_str(&flow).unwrap();
+        assert!(!flow.is_active);
+        assert!(matches!(flow.content.http_pair().request.body, Resource::Database(DatabaseResource { size: 5, .. })));
+        assert_eq!(storage.read().unwrap().get_flow("http").unwrap().content.http_pair().request.body.as_bytes().unwrap(), b"hello");
+
+        storage.write().unwrap().get_flow_body_mut("http", false).unwrap().content.http_pair_mut().request.body = Resource::Memory(MemoryResource::new(b"edited".to_vec()));
+        database.flush(&storage).unwrap();
+        let data: Vec<u8> = Connection::open(&path).unwrap().query_row("SELECT data FROM bodies WHERE flow_id = 'http'", [], |row| row.get(0)).unwrap();
+        assert_eq!(data, b"edited");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,197 @@
+use std::io;
+
+use async_compression::tokio::bufread::{BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder, ZstdDecoder, ZstdEncoder};
+use hyper::{header::{HeaderValue, CONTENT_ENCODING}, HeaderMap};
+use log::warn;
+use tokio::io::AsyncReadExt;
+
+use crate::resource::{MemoryResource, RequestOrResponse, Resource};
+
+// content codings in the order they were applied, identity is skipped
+pub fn content_encodings(headers: &HeaderMap) -> Vec<String> {
+    headers.get_all(CONTENT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect()
+}
+
+async fn encode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match encoding {
+        "gzip" | "x-gzip" => GzipEncoder::new(body).read_to_end(&mut out).await?,
+        "deflate" => ZlibEncoder::new(body).read_to_end(&mut out).await?,
+        "br" => BrotliEncoder::new(body).read_to_end(&mut out).await?,
+        "zstd" => ZstdEncoder::new(body).read_to_end(&mut out).await?,
+        _ => return Err(unsupported(encoding))
+    };
+    Ok(out)
+}
+
+fn unsupported(encoding: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("unsupported content encoding {}", encoding))
+}
+
+// one byte past the limit is enough to know it's too big, a small body can inflate to gigabytes
+async fn decode(encoding: &str, body: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let read_limit = limit.saturating_add(1);
+    match encoding {
+        "gzip" | "x-gzip" => GzipDecoder::new(body).take(read_limit).read_to_end(&mut out).await?,
+        "deflate" => ZlibDecoder::new(body).take(read_limit).read_to_end(&mut out).await?,
+        "br" => BrotliDecoder::new(body).take(read_limit).read_to_end(&mut out).await?,
+        "zstd" => ZstdDecoder::new(body).take(read_limit).read_to_end(&mut out).await?,
+        _ => return Err(unsupported(encoding))
+    };
+    match out.len() as u64 > limit {
+        true => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} body decodes to more than {} bytes", encoding, limit))),
+        false => Ok(out)
+    }
+}
+
+// undo every Content-Encoding on the body, last applied first, failing once it grows past limit
+pub async fn decode_body(headers: &HeaderMap, body: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+    let mut body = body.to_vec();
+    for encoding in content_encodings(headers).iter().rev() {
+        body = decode(encoding, &body, limit).await?;
+    }
+    Ok(body)
+}
+
+// apply the Content-Encoding listed in the headers to a plain body
+pub async fn encode_body(headers: &HeaderMap, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = body.to_vec();
+    for encoding in content_encodings(headers).iter() {
+        body = encode(encoding, &body).await?;
+    }
+    Ok(body)
+}
+
+// lets the ui or a script edit the plain body of a message, then puts it back on the wire the way the headers say
+pub struct DecodedEdit {
+    raw_body: Vec<u8>,
+    content_encoding: Vec<HeaderValue>,
+    decoded_body: Option<Vec<u8>>,
+}
+
+impl DecodedEdit {
+    // returns a copy of the message with its body decoded, or left raw if it decodes past limit
+    pub async fn decode(message: &RequestOrResponse, limit: u64) -> (Self, RequestOrResponse) {
+        let raw_body = match message.body.as_bytes() {
+            Ok(raw_body) => raw_body,
+            Err(e) => {
+                warn!("could not read body for editing: {}", e);
+                return (Self {
+                    raw_body: Vec::new(),
+                    content_encoding: Vec::new(),
+                    decoded_body: None
+                }, message.clone());
+            }
+        };
+        let decoded_body = match decode_body(&message.headers, &raw_body, limit).await {
+            Ok(decoded_body) => Some(decoded_body),
+            Err(e) => {
+                warn!("could not decode body, editing it raw: {}", e);
+                None
+            }
+        };
+
+        let mut editable = message.clone();
+        if let Some(decoded_body) = &decoded_body {
+            editable.body = Resource::Memory(MemoryResource::new(decoded_body.clone()));
+        }
+        (Self {
+            raw_body,
+            content_encoding: message.headers.get_all(CONTENT_ENCODING).iter().cloned().collect(),
+            decoded_body
+        }, editable)
+    }
+
+    pub async fn encode(self, edited: &mut RequestOrResponse) {
+        // an unreadable body is one nobody edited, leave it alone
+        let Ok(edited_body) = edited.body.as_bytes() else {
+            return;
+        };
+        let encoding_changed = edited.headers.get_all(CONTENT_ENCODING).iter().ne(self.content_encoding.iter());
+        match self.decoded_body {
+            Some(decoded_body) if encoding_changed || edited_body != decoded_body => {
+                match encode_body(&edited.headers, &edited_body).await {
+                    Ok(encoded_body) => {
+                        edited.body = Resource::Memory(MemoryResource::new(encoded_body));
+                    },
+                    Err(e) => {
+                        // sending it plain beats sending a body that lies about its encoding
+                        warn!("could not encode edited body, sending it without content encoding: {}", e);
+                        edited.headers.remove(CONTENT_ENCODING);
+                    }
+                }
+                edited.fix_body_framing();
+            },
+            Some(_) => {
+                // body untouched, send the original bytes instead of a re-compressed copy
+                edited.body = Resource::Memory(MemoryResource::new(self.raw_body));
+            },
+            None => {
+                if edited_body != self.raw_body {
+                    edited.fix_body_framing();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resource::RequestMeta;
+
+    use super::*;
+
+    fn headers(content_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(content_encoding).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn every_encoding_round_trips() {
+        let body = b"telescope ".repeat(100);
+        for encoding in ["gzip", "x-gzip", "deflate", "br", "zstd", "gzip, br", "identity"] {
+            let headers = headers(encoding);
+            let encoded = encode_body(&headers, &body).await.unwrap();
+            assert_eq!(decode_body(&headers, &encoded, body.len() as u64).await.unwrap(), body, "{}", encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn decoding_past_the_limit_fails() {
+        let headers = headers("gzip");
+        let bomb = encode_body(&headers, &vec![0; 1024 * 1024]).await.unwrap();
+        assert!(bomb.len() < 8 * 1024);
+        let error = decode_body(&headers, &bomb, 1024 * 1024 - 1).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(decode_body(&headers, &bomb, 1024 * 1024).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unknown_encoding_is_unsupported() {
+        let error = decode_body(&headers("compress"), b"data", u64::MAX).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_edited_raw() {
+        let headers = headers("gzip");
+        let encoded = encode_body(&headers, &[b'a'; 4096]).await.unwrap();
+        let message = RequestOrResponse::new_request(Resource::Memory(MemoryResource::new(encoded.clone())), headers, RequestMeta::new("http://example.com/", "POST", "HTTP/1.1"));
+
+        let (edit, mut editable) = DecodedEdit::decode(&message, 1024).await;
+        assert_eq!(editable.body.as_bytes().unwrap(), encoded);
+        edit.encode(&mut editable).await;
+        assert_eq!(editable.body.as_bytes().unwrap(), encoded);
+        assert_eq!(editable.headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+
+        let (_, editable) = DecodedEdit::decode(&message, 4096).await;
+        assert_eq!(editable.body.as_bytes().unwrap(), vec![b'a'; 4096]);
+    }
+}
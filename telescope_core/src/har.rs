@@ -0,0 +1,709 @@
+// HAR 1.2 (http://www.softwareishard.com/blog/har-12-spec/), what browser devtools import and export
+// websocket messages go in chrome's _webSocketMessages extension, tunnels have nothing worth exporting
+use base64::Engine;
+use hyper::{header::{HeaderName, HeaderValue, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE}, HeaderMap, StatusCode};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{encoding::{decode_body, encode_body}, resource::{Flow, FlowContent, HTTPPair, MemoryResource, RequestMeta, RequestOrResponse, Resource, ResponseMeta, WebSocketDirection, WebSocketFlow, WebSocketMessage, WebSocketOpcode}, timing::FlowTimings};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarLog {
+    pub version: String,
+    pub creator: HarCreator,
+    #[serde(default)]
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarCreator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    // total milliseconds, the sum of the timings
+    #[serde(default)]
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    #[serde(default)]
+    pub cache: serde_json::Value,
+    #[serde(default)]
+    pub timings: HarTimings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ip_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<String>,
+    #[serde(rename = "_resourceType", default, skip_serializing_if = "Option::is_none")]
+    pub resource_type: Option<String>,
+    #[serde(rename = "_webSocketMessages", default, skip_serializing_if = "Option::is_none")]
+    pub websocket_messages: Option<Vec<HarWebSocketMessage>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarCookie>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub query_string: Vec<HarNameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    // 0 when there never was a response
+    pub status: u32,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarCookie>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub content: HarContent,
+    #[serde(default, rename = "redirectURL")]
+    pub redirect_url: String,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarCookie {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<HarParam>,
+    #[serde(default)]
+    pub text: String,
+    // not in the spec but charles and a few others write base64 request bodies this way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarParam {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    #[serde(default)]
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+// milliseconds, -1 for phases that didn't happen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarTimings {
+    #[serde(default = "not_applicable")]
+    pub blocked: f64,
+    #[serde(default = "not_applicable")]
+    pub dns: f64,
+    // includes ssl
+    #[serde(default = "not_applicable")]
+    pub connect: f64,
+    #[serde(default)]
+    pub send: f64,
+    #[serde(default)]
+    pub wait: f64,
+    #[serde(default)]
+    pub receive: f64,
+    #[serde(default = "not_applicable")]
+    pub ssl: f64,
+}
+
+impl Default for HarTimings {
+    fn default() -> Self {
+        Self {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: -1.0,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+            ssl: -1.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarWebSocketMessage {
+    // "send" or "receive"
+    #[serde(rename = "type")]
+    pub kind: String,
+    // seconds since the epoch
+    pub time: f64,
+    pub opcode: u8,
+    // binary frames are base64
+    pub data: String,
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+fn not_applicable() -> f64 {
+    -1.0
+}
+
+fn format_time(millis: u128) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000).ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string())
+}
+
+fn parse_time(text: &str) -> Option<u128> {
+    let time = OffsetDateTime::parse(text, &Rfc3339).ok()?;
+    u128::try_from(time.unix_timestamp_nanos() / 1_000_000).ok()
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<HarNameValue> {
+    headers.iter().map(|(name, value)| HarNameValue {
+        name: name.to_string(),
+        value: String::from_utf8_lossy(value.as_bytes()).to_string()
+    }).collect()
+}
+
+fn header_map(headers: &[HarNameValue]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        // http/2 pseudo headers like :authority show up in chrome's exports
+        if header.name.starts_with(':') {
+            continue;
+        }
+        match (HeaderName::from_bytes(header.name.as_bytes()), HeaderValue::from_str(&header.value)) {
+            (Ok(name), Ok(value)) => {
+                map.append(name, value);
+            },
+            _ => warn!("skipping invalid header {}: {}", header.name, header.value)
+        }
+    }
+    map
+}
+
+fn mime_type(headers: &HeaderMap) -> String {
+    headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string()
+}
+
+fn request_cookies(headers: &HeaderMap) -> Vec<HarCookie> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some(HarCookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn response_cookies(headers: &HeaderMap) -> Vec<HarCookie> {
+    headers.get_all(SET_COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| {
+            let mut parts = value.split(';');
+            let (name, value) = parts.next()?.trim().split_once('=')?;
+            let mut cookie = HarCookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            };
+            for attribute in parts {
+                let (key, value) = attribute.trim().split_once('=').unwrap_or((attribute.trim(), ""));
+                match key.to_ascii_lowercase().as_str() {
+                    "path" => cookie.path = Some(value.to_string()),
+                    "domain" => cookie.domain = Some(value.to_string()),
+                    "expires" => cookie.expires = httpdate::parse_http_date(value).ok()
+                        .and_then(|expires| expires.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|expires| format_time(expires.as_millis())),
+                    "httponly" => cookie.http_only = Some(true),
+                    "secure" => cookie.secure = Some(true),
+                    _ => {}
+                }
+            }
+            Some(cookie)
+        })
+        .collect()
+}
+
+// text when it's utf8, otherwise base64
+fn body_text(body: &[u8]) -> (String, Option<String>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (base64::engine::general_purpose::STANDARD.encode(body), Some("base64".to_string()))
+    }
+}
+
+fn body_bytes(text: &str, encoding: Option<&str>) -> Result<Vec<u8>, String> {
+    match encoding {
+        Some("base64") => base64::engine::general_purpose::STANDARD.decode(text.trim()).map_err(|e| format!("invalid base64 body: {}", e)),
+        _ => Ok(text.as_bytes().to_vec())
+    }
+}
+
+// HAR bodies are always the decoded content, the headers still say how it went over the wire
+async fn decoded_body(message: &RequestOrResponse, max_body_size: u64) -> Vec<u8> {
+    let body = match message.body.as_bytes() {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("exporting without body: {}", e);
+            return Vec::new();
+        }
+    };
+    match decode_body(&message.headers, &body, max_body_size).await {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("exporting body as is, could not decode it: {}", e);
+            body
+        }
+    }
+}
+
+fn millis(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+fn har_timings(timings: &FlowTimings, time_taken: Option<u128>) -> HarTimings {
+    let mut har_timings = HarTimings::default();
+    let (Some(request_sent), Some(first_byte)) = (timings.request_sent, timings.first_byte) else {
+        // nothing finer than the wall clock, call it all waiting
+        har_timings.wait = time_taken.unwrap_or(0) as f64;
+        return har_timings;
+    };
+    let ready = timings.connection_ready().unwrap_or(request_sent);
+    let queued = timings.connect.map(|(start, _)| start).unwrap_or(ready);
+    har_timings.blocked = millis(queued);
+    if let Some((start, end)) = timings.connect {
+        har_timings.connect = millis(timings.tls_handshake.map(|(_, end)| end).unwrap_or(end).saturating_sub(start));
+    }
+    if let Some((start, end)) = timings.tls_handshake {
+        har_timings.ssl = millis(end.saturating_sub(start));
+    }
+    har_timings.send = millis(request_sent.saturating_sub(ready));
+    har_timings.wait = millis(first_byte.saturating_sub(request_sent));
+    har_timings.receive = millis(timings.response_complete.unwrap_or(first_byte).saturating_sub(first_byte));
+    har_timings
+}
+
+// the inverse of har_timings, each phase starts where the one before it ended
+fn flow_timings(har_timings: &HarTimings, started_at: u128) -> FlowTimings {
+    let micros = |millis: f64| (millis.max(0.0) * 1000.0) as u64;
+    let mut timings = FlowTimings::default();
+    timings.started_at = started_at;
+    timings.request_received = Some(0);
+    let mut at = micros(har_timings.blocked);
+    timings.forwarded = Some(at);
+    at += micros(har_timings.dns);
+    if har_timings.connect > 0.0 {
+        let tcp_end = at + micros(har_timings.connect - har_timings.ssl.max(0.0));
+        timings.connect = Some((at, tcp_end));
+        if har_timings.ssl > 0.0 {
+            timings.tls_handshake = Some((tcp_end, at + micros(har_timings.connect)));
+        }
+        at += micros(har_timings.connect);
+    }
+    at += micros(har_timings.send);
+    timings.request_sent = Some(at);
+    at += micros(har_timings.wait);
+    timings.first_byte = Some(at);
+    at += micros(har_timings.receive);
+    timings.response_complete = Some(at);
+    timings
+}
+
+fn total_time(timings: &HarTimings) -> f64 {
+    [timings.blocked, timings.dns, timings.connect, timings.send, timings.wait, timings.receive].iter()
+        .filter(|time| **time > 0.0)
+        .fold(0.0, |total, time| total + time)
+}
+
+async fn har_request(request: &RequestOrResponse, max_body_size: u64) -> HarRequest {
+    let meta = request.meta.unwrap_request_ref();
+    let size = request.body.size();
+    let post_data = if size > 0 {
+        let mime_type = mime_type(&request.headers);
+        let body = decoded_body(request, max_body_size).await;
+        let params = if mime_type.starts_with("application/x-www-form-urlencoded") {
+            url::form_urlencoded::parse(&body).map(|(name, value)| HarParam {
+                name: name.to_string(),
+                value: Some(value.to_string()),
+                file_name: None,
+                content_type: None
+            }).collect()
+        } else {
+            Vec::new()
+        };
+        let (text, encoding) = body_text(&body);
+        Some(HarPostData {
+            mime_type,
+            params,
+            text,
+            encoding
+        })
+    } else {
+        None
+    };
+    HarRequest {
+        method: meta.method.clone(),
+        url: meta.url.to_string(),
+        http_version: meta.version.clone(),
+        cookies: request_cookies(&request.headers),
+        headers: name_values(&request.headers),
+        query_string: meta.url.query_pairs().map(|(name, value)| HarNameValue {
+            name: name.to_string(),
+            value: value.to_string()
+        }).collect(),
+        post_data,
+        headers_size: -1,
+        body_size: size as i64
+    }
+}
+
+async fn har_response(response: Option<&RequestOrResponse>, max_body_size: u64) -> HarResponse {
+    let Some(response) = response else {
+        return HarResponse {
+            status: 0,
+            status_text: String::new(),
+            http_version: String::new(),
+            cookies: Vec::new(),
+            headers: Vec::new(),
+            content: HarContent::default(),
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1
+        };
+    };
+    let meta = response.meta.unwrap_response_ref();
+    let body = decoded_body(response, max_body_size).await;
+    let (text, encoding) = body_text(&body);
+    HarResponse {
+        status: meta.status,
+        status_text: StatusCode::from_u16(meta.status as u16).ok().and_then(|status| status.canonical_reason()).unwrap_or_default().to_string(),
+        http_version: meta.version.clone(),
+        cookies: response_cookies(&response.headers),
+        headers: name_values(&response.headers),
+        content: HarContent {
+            size: body.len() as i64,
+            mime_type: mime_type(&response.headers),
+            text: Some(text),
+            encoding
+        },
+        redirect_url: response.headers.get(LOCATION).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string(),
+        headers_size: -1,
+        body_size: response.body.size() as i64
+    }
+}
+
+fn har_websocket_message(message: &WebSocketMessage) -> HarWebSocketMessage {
+    let data = match message.opcode {
+        WebSocketOpcode::Text => String::from_utf8_lossy(&message.payload).to_string(),
+        _ => base64::engine::general_purpose::STANDARD.encode(&message.payload)
+    };
+    HarWebSocketMessage {
+        kind: match message.direction {
+            WebSocketDirection::ClientToServer => "send",
+            WebSocketDirection::ServerToClient => "receive"
+        }.to_string(),
+        time: message.timestamp as f64 / 1000.0,
+        opcode: message.opcode.code(),
+        data
+    }
+}
+
+// bodies that decode past max_body_size are exported still encoded
+pub async fn har_entry(flow: &Flow, max_body_size: u64) -> Option<HarEntry> {
+    let (http_pair, websocket) = match &flow.content {
+        FlowContent::RequestResponse(http_pair) => (http_pair, None),
+        FlowContent::WebSocket(websocket) => (&websocket.handshake, Some(websocket)),
+        FlowContent::Tunnel(_) => return None
+    };
+    let timings = har_timings(&flow.timings, http_pair.get_time_taken());
+    Some(HarEntry {
+        started_date_time: format_time(http_pair.request.meta.unwrap_request_ref().created_at),
+        time: total_time(&timings),
+        request: har_request(&http_pair.request, max_body_size).await,
+        response: har_response(http_pair.response.as_ref(), max_body_size).await,
+        cache: serde_json::json!({}),
+        timings,
+        server_ip_address: None,
+        connection: flow.connection.connection_id.map(|connection_id| connection_id.to_string()),
+        resource_type: websocket.map(|_| "websocket".to_string()),
+        websocket_messages: websocket.map(|websocket| websocket.messages.iter().map(har_websocket_message).collect())
+    })
+}
+
+pub async fn build_har(flows: &[Flow], max_body_size: u64) -> Har {
+    let mut entries = Vec::with_capacity(flows.len());
+    for flow in flows {
+        if let Some(entry) = har_entry(flow, max_body_size).await {
+            entries.push(entry);
+        }
+    }
+    Har {
+        log: HarLog {
+            version: "1.2".to_string(),
+            creator: HarCreator {
+                name: "Telescope".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string()
+            },
+            entries
+        }
+    }
+}
+
+pub async fn export_har(flows: &[Flow], max_body_size: u64) -> Result<Vec<u8>, String> {
+    serde_json::to_vec_pretty(&build_har(flows, max_body_size).await).map_err(|e| format!("failed to write HAR: {}", e))
+}
+
+// puts a decoded HAR body back into the encoding the headers claim, or drops the claim
+async fn wire_body(headers: &mut HeaderMap, body: Vec<u8>) -> Resource {
+    let body = match encode_body(headers, &body).await {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            warn!("importing body without its content encoding: {}", e);
+            headers.remove(hyper::header::CONTENT_ENCODING);
+            body
+        }
+    };
+    Resource::Memory(MemoryResource::new(body))
+}
+
+fn flow_websocket_message(message: &HarWebSocketMessage) -> Result<WebSocketMessage, String> {
+    let opcode = WebSocketOpcode::from_code(message.opcode).ok_or_else(|| format!("unknown websocket opcode {}", message.opcode))?;
+    let direction = match message.kind.as_str() {
+        "send" => WebSocketDirection::ClientToServer,
+        _ => WebSocketDirection::ServerToClient
+    };
+    let payload = match opcode {
+        WebSocketOpcode::Text => message.data.as_bytes().to_vec(),
+        _ => body_bytes(&message.data, Some("base64"))?
+    };
+    let mut websocket_message = WebSocketMessage::new(direction, opcode, payload);
+    websocket_message.timestamp = (message.time * 1000.0) as u128;
+    Ok(websocket_message)
+}
+
+pub async fn flow_from_entry(entry: &HarEntry) -> Result<Flow, String> {
+    let started_at = parse_time(&entry.started_date_time).ok_or_else(|| format!("invalid startedDateTime {}", entry.started_date_time))?;
+
+    let mut request_meta = RequestMeta::new_checked(&entry.request.url, &entry.request.method, &entry.request.http_version)?;
+    request_meta.created_at = started_at;
+    let mut request_headers = header_map(&entry.request.headers);
+    let request_body = match &entry.request.post_data {
+        Some(post_data) if !post_data.text.is_empty() => body_bytes(&post_data.text, post_data.encoding.as_deref())?,
+        // some exporters only fill in the params
+        Some(post_data) => url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(post_data.params.iter().map(|param| (param.name.as_str(), param.value.as_deref().unwrap_or_default())))
+            .finish()
+            .into_bytes(),
+        None => Vec::new()
+    };
+    let request_body = wire_body(&mut request_headers, request_body).await;
+    let mut http_pair = HTTPPair::new_request(RequestOrResponse::new_request(request_body, request_headers, request_meta));
+
+    if entry.response.status != 0 {
+        let mut response_meta = ResponseMeta::new(entry.response.status, &entry.response.http_version);
+        response_meta.created_at = started_at + entry.time.max(0.0) as u128;
+        let mut response_headers = header_map(&entry.response.headers);
+        let response_body = match &entry.response.content.text {
+            Some(text) => body_bytes(text, entry.response.content.encoding.as_deref())?,
+            None => Vec::new()
+        };
+        let response_body = wire_body(&mut response_headers, response_body).await;
+        http_pair.add_response(RequestOrResponse::new_response(response_body, response_headers, response_meta));
+    }
+
+    let content = match &entry.websocket_messages {
+        Some(messages) => {
+            let mut websocket = WebSocketFlow::new(http_pair);
+            for message in messages {
+                websocket.add_message(flow_websocket_message(message)?);
+            }
+            websocket.closed_at = websocket.messages.last().map(|message| message.timestamp);
+            FlowContent::WebSocket(websocket)
+        },
+        None => FlowContent::RequestResponse(http_pair)
+    };
+    let mut flow = Flow::new(content);
+    flow.is_active = false;
+    flow.timings = flow_timings(&entry.timings, started_at);
+    flow.connection.connection_id = entry.connection.as_ref().and_then(|connection| connection.parse().ok());
+    Ok(flow)
+}
+
+// entries that can't be read are skipped with a warning instead of failing the whole file
+pub async fn import_har(data: &[u8]) -> Result<Vec<Flow>, String> {
+    let har: Har = serde_json::from_slice(data).map_err(|e| format!("not a HAR file: {}", e))?;
+    let mut flows = Vec::with_capacity(har.log.entries.len());
+    for (idx, entry) in har.log.entries.iter().enumerate() {
+        match flow_from_entry(entry).await {
+            Ok(flow) => flows.push(flow),
+            Err(e) => warn!("skipping HAR entry {}: {}", idx, e)
+        }
+    }
+    Ok(flows)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::CONTENT_ENCODING;
+
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn memory(body: &[u8]) -> Resource {
+        Resource::Memory(MemoryResource::new(body.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_har() {
+        let request_headers = headers(&[("content-type", "application/x-www-form-urlencoded"), ("cookie", "a=1; b=2")]);
+        let request = RequestOrResponse::new_request(memory(b"name=tele+scope&x=%26"), request_headers, RequestMeta::new("https://example.com/search?q=rust&page=2", "POST", "HTTP/1.1"));
+        let response_headers = headers(&[("content-encoding", "gzip"), ("set-cookie", "session=abc; Path=/; HttpOnly; Secure")]);
+        let binary = vec![0, 159, 146, 150, 255];
+        let gzipped = encode_body(&response_headers, &binary).await.unwrap();
+        let mut http_pair = HTTPPair::new_request(request);
+        http_pair.add_response(RequestOrResponse::new_response(memory(&gzipped), response_headers, ResponseMeta::new(200, "HTTP/1.1")));
+        let flow = Flow::new(FlowContent::RequestResponse(http_pair));
+
+        let entry = har_entry(&flow, u64::MAX).await.unwrap();
+        assert_eq!(entry.request.query_string.len(), 2);
+        assert_eq!(entry.request.cookies.len(), 2);
+        let post_data = entry.request.post_data.as_ref().unwrap();
+        assert_eq!(post_data.params[0].value.as_deref(), Some("tele scope"));
+        assert_eq!(post_data.params[1].value.as_deref(), Some("&"));
+        // HAR holds the decoded body, base64 since it isn't text
+        assert_eq!(entry.response.content.encoding.as_deref(), Some("base64"));
+        assert_eq!(body_bytes(entry.response.content.text.as_deref().unwrap(), Some("base64")).unwrap(), binary);
+        let cookie = &entry.response.cookies[0];
+        assert_eq!((cookie.name.as_str(), cookie.value.as_str(), cookie.path.as_deref(), cookie.http_only, cookie.secure), ("session", "abc", Some("/"), Some(true), Some(true)));
+
+        let data = export_har(&[flow], u64::MAX).await.unwrap();
+        let flows = import_har(&data).await.unwrap();
+        assert_eq!(flows.len(), 1);
+        let http_pair = flows[0].content.http_pair();
+        let request_meta = http_pair.request.meta.unwrap_request_ref();
+        assert_eq!((request_meta.method.as_str(), request_meta.url.as_str()), ("POST", "https://example.com/search?q=rust&page=2"));
+        assert_eq!(http_pair.request.body.as_bytes().unwrap(), b"name=tele+scope&x=%26");
+        // the body goes back on the wire encoded the way its headers say
+        let response = http_pair.response.as_ref().unwrap();
+        assert_eq!(response.headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(decode_body(&response.headers, &response.body.as_bytes().unwrap(), u64::MAX).await.unwrap(), binary);
+    }
+
+    #[tokio::test]
+    async fn websocket_messages_round_trip() {
+        let request = RequestOrResponse::new_request(memory(b""), HeaderMap::new(), RequestMeta::new("wss://example.com/socket", "GET", "HTTP/1.1"));
+        let mut websocket = WebSocketFlow::new(HTTPPair::new_request(request));
+        websocket.add_message(WebSocketMessage::new(WebSocketDirection::ClientToServer, WebSocketOpcode::Text, b"hello".to_vec()));
+        websocket.add_message(WebSocketMessage::new(WebSocketDirection::ServerToClient, WebSocketOpcode::Binary, vec![0, 255]));
+        let data = export_har(&[Flow::new(FlowContent::WebSocket(websocket))], u64::MAX).await.unwrap();
+
+        let flows = import_har(&data).await.unwrap();
+        let FlowContent::WebSocket(websocket) = &flows[0].content else {
+            panic!("not a websocket flow");
+        };
+        assert_eq!(websocket.messages.len(), 2);
+        assert_eq!((websocket.messages[0].direction, websocket.messages[0].opcode, websocket.messages[0].payload.as_slice()), (WebSocketDirection::ClientToServer, WebSocketOpcode::Text, &b"hello"[..]));
+        assert_eq!((websocket.messages[1].direction, websocket.messages[1].opcode, websocket.messages[1].payload.as_slice()), (WebSocketDirection::ServerToClient, WebSocketOpcode::Binary, &[0, 255][..]));
+    }
+
+    #[tokio::test]
+    async fn imports_what_other_tools_write() {
+        let data = br#"{"log": {"version": "1.2", "creator": {"name": "devtools", "version": "1"}, "entries": [
+            {"startedDateTime": "2024-01-02T03:04:05.678Z", "time": 12.5,
+             "request": {"method": "POST", "url": "https://example.com/form", "httpVersion": "h2",
+                         "headers": [{"name": ":authority", "value": "example.com"}, {"name": "x-bad\n", "value": "1"}, {"name": "content-type", "value": "application/x-www-form-urlencoded"}],
+                         "postData": {"mimeType": "application/x-www-form-urlencoded", "params": [{"name": "a b", "value": "c&d"}]}},
+             "response": {"status": 0, "statusText": "", "httpVersion": "", "headers": [], "content": {"size": 0, "mimeType": ""}}},
+            {"startedDateTime": "yesterday", "request": {"method": "GET", "url": "https://example.com/"}, "response": {"status": 200, "content": {}}},
+            {"startedDateTime": "2024-01-02T03:04:05Z", "request": {"method": "GET", "url": "https://example.com/"},
+             "response": {"status": 200, "content": {"text": "not base64!", "encoding": "base64"}}}
+        ]}}"#;
+        let flows = import_har(data).await.unwrap();
+        // the bad date and the bad base64 only cost their own entries
+        assert_eq!(flows.len(), 1);
+        let http_pair = flows[0].content.http_pair();
+        assert_eq!(http_pair.request.meta.unwrap_request_ref().created_at, 1704164645678);
+        assert_eq!(http_pair.request.headers.len(), 1);
+        assert_eq!(http_pair.request.body.as_bytes().unwrap(), b"a+b=c%26d");
+        // status 0 means no response was received
+        assert!(http_pair.response.is_none());
+
+        assert!(import_har(b"{\"not\": \"har\"}").await.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use log::warn;
+use tokio::sync::oneshot;
+
+use crate::resource::{get_current_time, RequestOrResponse, WebSocketMessage};
+
+#[derive(Debug)]
+pub enum InterceptDecision<T = RequestOrResponse> {
+    Forward(Box<T>),
+    Drop,
+}
+
+// a message held by the proxy until someone decides what to do with it
+// T is an http request/response or a websocket frame
+#[derive(Debug)]
+pub struct InterceptedMessage<T = RequestOrResponse> {
+    pub id: String,
+    pub flow_id: Option<String>,
+    pub message: T,
+    pub created_at: u128,
+    responder: Option<oneshot::Sender<InterceptDecision<T>>>,
+}
+
+impl<T> InterceptedMessage<T> {
+    pub fn new(flow_id: Option<String>, message: T) -> (Self, oneshot::Receiver<InterceptDecision<T>>) {
+        let (send, recv) = oneshot::channel();
+        (Self {
+            id: nanoid::nanoid!(),
+            flow_id,
+            message,
+            created_at: get_current_time(),
+            responder: Some(send)
+        }, recv)
+    }
+
+    pub fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    pub fn resolve(mut self, decision: InterceptDecision<T>) {
+        if let Some(responder) = self.responder.take() {
+            if responder.send(decision).is_err() {
+                // client probably went away while we were editing
+                warn!("intercepted message {} was abandoned before it was resolved", self.id);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InterceptQueue {
+    pub pending: Vec<InterceptedMessage>,
+    pub websocket_pending: Vec<InterceptedMessage<WebSocketMessage>>,
+}
+
+impl InterceptQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            websocket_pending: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, message: InterceptedMessage) {
+        self.pending.push(message);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&InterceptedMessage> {
+        self.pending.iter().find(|message| message.id == id)
+    }
+
+    pub fn take(&mut self, id: &str) -> Option<InterceptedMessage> {
+        let index = self.pending.iter().position(|message| message.id == id)?;
+        Some(self.pending.remove(index))
+    }
+
+    pub fn resolve(&mut self, id: &str, decision: InterceptDecision) -> bool {
+        match self.take(id) {
+            Some(message) => {
+                message.resolve(decision);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn forward(&mut self, id: &str, message: RequestOrResponse) -> bool {
+        self.resolve(id, InterceptDecision::Forward(Box::new(message)))
+    }
+
+    pub fn drop_message(&mut self, id: &str) -> bool {
+        self.resolve(id, InterceptDecision::Drop)
+    }
+
+    pub fn push_websocket(&mut self, message: InterceptedMessage<WebSocketMessage>) {
+        self.websocket_pending.push(message);
+    }
+
+    pub fn get_websocket(&self, id: &str) -> Option<&InterceptedMessage<WebSocketMessage>> {
+        self.websocket_pending.iter().find(|message| message.id == id)
+    }
+
+    pub fn take_websocket(&mut self, id: &str) -> Option<InterceptedMessage<WebSocketMessage>> {
+        let index = self.websocket_pending.iter().position(|message| message.id == id)?;
+        Some(self.websocket_pending.remove(index))
+    }
+
+    pub fn forward_websocket(&mut self, id: &str, message: WebSocketMessage) -> bool {
+        match self.take_websocket(id) {
+            Some(intercepted) => {
+                intercepted.resolve(InterceptDecision::Forward(Box::new(message)));
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn drop_websocket(&mut self, id: &str) -> bool {
+        match self.take_websocket(id) {
+            Some(intercepted) => {
+                intercepted.resolve(InterceptDecision::Drop);
+                true
+            },
+            None => false
+        }
+    }
+
+    // release everything unchanged, used when intercepting gets turned off
+    pub fn forward_all(&mut self) {
+        for message in self.pending.drain(..) {
+            let unchanged = message.message.clone();
+            message.resolve(InterceptDecision::Forward(Box::new(unchanged)));
+        }
+        for message in self.websocket_pending.drain(..) {
+            let unchanged = message.message.clone();
+            message.resolve(InterceptDecision::Forward(Box::new(unchanged)));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.websocket_pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.websocket_pending.is_empty()
+    }
+}
@@ -2,6 +2,26 @@ pub mod config;
 pub mod certs;
 pub mod resource;
 pub mod proxy;
+pub mod matching;
+pub mod intercept;
+pub mod encoding;
+pub mod plugin;
+pub mod scripting;
+pub mod wasm;
+pub mod body;
+pub mod tunnel;
+pub mod socks;
+pub mod transparent;
+pub mod reverse;
+pub mod upstream;
+pub mod connection;
+pub mod verify;
+pub mod timing;
+pub mod database;
+pub mod har;
+pub mod mitmproxy;
+pub mod burp;
+pub mod codegen;
 
 pub async fn run_standalone() {
     let config = config::Config::default();
@@ -0,0 +1,63 @@
+// small helpers for matching user supplied patterns against hosts, paths etc
+
+// case insensitive glob style matching, * matches any run of characters and ? matches exactly one
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // position of the last * seen and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // let the last * swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// empty patterns are treated as "match everything" so unset filters don't block anything
+pub fn optional_wildcard_match(pattern: &str, text: &str) -> bool {
+    pattern.is_empty() || wildcard_match(pattern, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_case_insensitively() {
+        assert!(wildcard_match("*.Example.com", "api.example.COM"));
+        assert!(wildcard_match("a?c", "abc"));
+        assert!(wildcard_match("*a*b*", "xxaYYb"));
+        assert!(wildcard_match("**", ""));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(!wildcard_match("a?c", "ac"));
+        assert!(!wildcard_match("abc", "abcd"));
+    }
+
+    #[test]
+    fn many_stars_fail_without_blowing_up() {
+        let text = "a".repeat(2000);
+        assert!(!wildcard_match("*a*a*a*a*b", &text));
+    }
+
+    #[test]
+    fn empty_pattern_matches_everything_when_optional() {
+        assert!(optional_wildcard_match("", "anything"));
+        assert!(!wildcard_match("", "anything"));
+    }
+}
@@ -0,0 +1,448 @@
+// reads mitmproxy's .flow dumps, a run of tnetstrings (https://tnetstrings.info) with one flow state dict each
+// written against mitmproxy 7+ where websockets hang off their http flow, the older standalone websocket flows work too
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}};
+
+use hyper::{header::{HeaderName, HeaderValue}, HeaderMap};
+use log::warn;
+
+use crate::{connection::{CertificateInfo, TlsInfo}, resource::{Flow, FlowContent, HTTPPair, MemoryResource, RequestMeta, RequestOrResponse, Resource, ResponseMeta, TunnelFlow, WebSocketDirection, WebSocketFlow, WebSocketMessage, WebSocketOpcode}, timing::FlowTimings};
+
+// flow states go maybe six deep, this is only there so a crafted file can't blow the stack
+const MAX_DEPTH: usize = 32;
+// the first flow format of mitmproxy 7, which reordered websocket messages
+const WEBSOCKET_REORDER_VERSION: i64 = 12;
+
+#[derive(Debug, Clone)]
+pub enum TValue {
+    Bytes(Vec<u8>),
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    List(Vec<TValue>),
+    Dict(HashMap<String, TValue>),
+}
+
+impl TValue {
+    pub fn get(&self, key: &str) -> Option<&TValue> {
+        match self {
+            TValue::Dict(dict) => dict.get(key).filter(|value| !matches!(value, TValue::Null)),
+            _ => None
+        }
+    }
+
+    // mitmproxy has moved fields between bytes and str over the versions, either is fine here
+    pub fn as_str(&self) -> Option<String> {
+        match self {
+            TValue::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).to_string()),
+            TValue::String(string) => Some(string.clone()),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            TValue::Bytes(bytes) => Some(bytes),
+            TValue::String(string) => Some(string.as_bytes()),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            TValue::Int(int) => Some(*int),
+            TValue::Float(float) => Some(*float as i64),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            TValue::Int(int) => Some(*int as f64),
+            TValue::Float(float) => Some(*float),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            TValue::Bool(bool) => Some(*bool),
+            _ => None
+        }
+    }
+
+    pub fn as_list(&self) -> &[TValue] {
+        match self {
+            TValue::List(list) => list,
+            _ => &[]
+        }
+    }
+}
+
+// one value off the front of data, returns what's left after it
+pub fn parse_tnetstring(data: &[u8]) -> Result<(TValue, &[u8]), String> {
+    parse_nested(data, 0)
+}
+
+fn parse_nested(data: &[u8], depth: usize) -> Result<(TValue, &[u8]), String> {
+    if depth > MAX_DEPTH {
+        return Err("tnetstring is nested too deep".to_string());
+    }
+    let colon = data.iter().take(12).position(|byte| *byte == b':').ok_or("missing tnetstring length")?;
+    let length: usize = std::str::from_utf8(&data[..colon]).ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or("invalid tnetstring length")?;
+    let start = colon + 1;
+    if data.len() < start + length + 1 {
+        return Err("tnetstring is cut off".to_string());
+    }
+    let payload = &data[start..start + length];
+    let rest = &data[start + length + 1..];
+    let text = || std::str::from_utf8(payload).map_err(|e| format!("invalid tnetstring text: {}", e));
+    let value = match data[start + length] {
+        b',' => TValue::Bytes(payload.to_vec()),
+        b';' => TValue::String(text()?.to_string()),
+        b'#' => TValue::Int(text()?.parse().map_err(|e| format!("invalid tnetstring int: {}", e))?),
+        b'^' => TValue::Float(text()?.parse().map_err(|e| format!("invalid tnetstring float: {}", e))?),
+        b'!' => TValue::Bool(payload == b"true"),
+        b'~' => TValue::Null,
+        b']' => {
+            let mut list = Vec::new();
+            let mut remaining = payload;
+            while !remaining.is_empty() {
+                let (value, rest) = parse_nested(remaining, depth + 1)?;
+                list.push(value);
+                remaining = rest;
+            }
+            TValue::List(list)
+        },
+        b'}' => {
+            let mut dict = HashMap::new();
+            let mut remaining = payload;
+            while !remaining.is_empty() {
+                let (key, rest) = parse_nested(remaining, depth + 1)?;
+                let (value, rest) = parse_nested(rest, depth + 1)?;
+                dict.insert(key.as_str().ok_or("tnetstring dict key isn't a string")?, value);
+                remaining = rest;
+            }
+            TValue::Dict(dict)
+        },
+        other => return Err(format!("unknown tnetstring type {:?}", other as char))
+    };
+    Ok((value, rest))
+}
+
+fn millis(seconds: f64) -> u128 {
+    (seconds.max(0.0) * 1000.0) as u128
+}
+
+fn headers(state: &TValue) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for header in state.get("headers").map(TValue::as_list).unwrap_or_default() {
+        let [name, value] = header.as_list() else {
+            continue;
+        };
+        let (Some(name), Some(value)) = (name.as_bytes(), value.as_bytes()) else {
+            continue;
+        };
+        match (HeaderName::from_bytes(name), HeaderValue::from_bytes(value)) {
+            (Ok(name), Ok(value)) => {
+                map.append(name, value);
+            },
+            _ => warn!("skipping invalid header {}", String::from_utf8_lossy(name))
+        }
+    }
+    map
+}
+
+fn content(state: &TValue) -> Resource {
+    let content = state.get("content").and_then(TValue::as_bytes).unwrap_or_default();
+    Resource::Memory(MemoryResource::new(content.to_vec()))
+}
+
+fn address(state: Option<&TValue>) -> Option<(String, u16)> {
+    match state?.as_list() {
+        [host, port, ..] => Some((host.as_str()?, port.as_i64()? as u16)),
+        _ => None
+    }
+}
+
+fn request_url(request: &TValue) -> String {
+    let field = |key: &str| request.get(key).and_then(TValue::as_str).unwrap_or_default();
+    let (scheme, authority, host, path) = (field("scheme"), field("authority"), field("host"), field("path"));
+    if field("method") == "CONNECT" {
+        return if authority.is_empty() { format!("{}:{}", host, request.get("port").and_then(TValue::as_i64).unwrap_or(443)) } else { authority };
+    }
+    let authority = match (authority.is_empty(), request.get("port").and_then(TValue::as_i64)) {
+        (false, _) => authority,
+        (true, Some(port)) if !matches!((scheme.as_str(), port), ("http", 80) | ("https", 443)) => format!("{}:{}", host, port),
+        _ => host
+    };
+    format!("{}://{}{}", scheme, authority, path)
+}
+
+fn http_pair(state: &TValue) -> Result<HTTPPair, String> {
+    let request = state.get("request").ok_or("flow has no request")?;
+    let field = |key: &str| request.get(key).and_then(TValue::as_str).unwrap_or_default();
+    let mut request_meta = RequestMeta::new_checked(&request_url(request), &field("method"), &field("http_version"))?;
+    request_meta.created_at = millis(request.get("timestamp_start").and_then(TValue::as_f64).unwrap_or_default());
+    let mut http_pair = HTTPPair::new_request(RequestOrResponse::new_request(content(request), headers(request), request_meta));
+
+    if let Some(response) = state.get("response") {
+        let status = response.get("status_code").and_then(TValue::as_i64).ok_or("response has no status code")?;
+        let mut response_meta = ResponseMeta::new(status as u32, &response.get("http_version").and_then(TValue::as_str).unwrap_or_default());
+        response_meta.created_at = millis(response.get("timestamp_start").and_then(TValue::as_f64).unwrap_or_default());
+        http_pair.add_response(RequestOrResponse::new_response(content(response), headers(response), response_meta));
+    }
+    Ok(http_pair)
+}
+
+// mitmproxy keeps absolute timestamps, ours are offsets from the start of the request
+fn flow_timings(state: &TValue) -> FlowTimings {
+    let mut timings = FlowTimings::default();
+    let timestamp = |value: Option<&TValue>, key| value.and_then(|value| value.get(key)).and_then(TValue::as_f64);
+    let request = state.get("request");
+    let Some(origin) = timestamp(request, "timestamp_start") else {
+        return timings;
+    };
+    let offset = |time: Option<f64>| time.filter(|time| *time >= origin).map(|time| ((time - origin) * 1_000_000.0) as u64);
+    timings.started_at = millis(origin);
+    timings.request_received = offset(timestamp(request, "timestamp_end"));
+    timings.forwarded = timings.request_received;
+
+    // only a connection opened for this request says anything about it
+    let server = state.get("server_conn");
+    if let (Some(start), Some(connected)) = (offset(timestamp(server, "timestamp_start")), offset(timestamp(server, "timestamp_tcp_setup"))) {
+        timings.connect = Some((start, connected));
+        timings.tls_handshake = offset(timestamp(server, "timestamp_tls_setup")).map(|handshaken| (connected, handshaken));
+    }
+    timings.request_sent = timings.connection_ready();
+    let response = state.get("response");
+    timings.first_byte = offset(timestamp(response, "timestamp_start"));
+    timings.response_complete = offset(timestamp(response, "timestamp_end"));
+    timings
+}
+
+fn tls_info(server: &TValue) -> Option<TlsInfo> {
+    let field = |key: &str| server.get(key).and_then(TValue::as_str);
+    if !server.get("tls_established").and_then(TValue::as_bool).unwrap_or(field("tls_version").is_some()) {
+        return None;
+    }
+    let certificates = server.get("certificate_list").map(TValue::as_list).unwrap_or_default().iter()
+        .filter_map(|certificate| {
+            let (_, pem) = x509_parser::pem::parse_x509_pem(certificate.as_bytes()?).ok()?;
+            CertificateInfo::from_der(&pem.contents).ok()
+        })
+        .collect();
+    Some(TlsInfo {
+        sni: field("sni").unwrap_or_default(),
+        version: field("tls_version").unwrap_or_default(),
+        cipher_suite: field("cipher").or_else(|| field("cipher_name")).unwrap_or_default(),
+        alpn: field("alpn").or_else(|| field("alpn_proto_negotiated")).filter(|alpn| !alpn.is_empty()),
+        certificates
+    })
+}
+
+// 7+ has (type, content, from_client, timestamp, ...), before that it was (type, from_client, content, timestamp, killed)
+fn websocket_message(state: &TValue, version: Option<i64>) -> Option<WebSocketMessage> {
+    let [kind, second, third, timestamp, ..] = state.as_list() else {
+        return None;
+    };
+    let is_old_order = match version {
+        Some(version) => version < WEBSOCKET_REORDER_VERSION,
+        // no version to go by, the flag is the only bool of the two
+        None => matches!(second, TValue::Bool(_))
+    };
+    let (content, from_client) = match is_old_order {
+        true => (third, second),
+        false => (second, third)
+    };
+    let opcode = match kind {
+        TValue::Int(code) => WebSocketOpcode::from_code(*code as u8)?,
+        // before mitmproxy 7 the type was a name
+        kind => match kind.as_str()?.as_str() {
+            "text" => WebSocketOpcode::Text,
+            _ => WebSocketOpcode::Binary
+        }
+    };
+    let direction = match from_client.as_bool()? {
+        true => WebSocketDirection::ClientToServer,
+        false => WebSocketDirection::ServerToClient
+    };
+    let mut message = WebSocketMessage::new(direction, opcode, content.as_bytes()?.to_vec());
+    message.timestamp = millis(timestamp.as_f64()?);
+    Some(message)
+}
+
+fn websocket_flow(http_pair: HTTPPair, websocket: &TValue, version: Option<i64>) -> WebSocketFlow {
+    let mut websocket_flow = WebSocketFlow::new(http_pair);
+    for message in websocket.get("messages").map(TValue::as_list).unwrap_or_default() {
+        match websocket_message(message, version) {
+            Some(message) => websocket_flow.add_message(message),
+            None => warn!("skipping unreadable websocket message")
+        }
+    }
+    websocket_flow.closed_at = websocket.get("timestamp_end").and_then(TValue::as_f64).map(millis);
+    websocket_flow
+}
+
+// raw tcp flows have no CONNECT of their own, one is made up from the server address
+fn tunnel_flow(state: &TValue) -> Result<TunnelFlow, String> {
+    let server = state.get("server_conn");
+    let (host, port) = address(server.and_then(|server| server.get("address"))).ok_or("tcp flow has no server address")?;
+    let target = format!("{}:{}", host, port);
+    let mut connect_meta = RequestMeta::new_checked(&target, "CONNECT", "HTTP/1.1")?;
+    let opened_at = server.and_then(|server| server.get("timestamp_start")).and_then(TValue::as_f64);
+    if let Some(opened_at) = opened_at {
+        connect_meta.created_at = millis(opened_at);
+    }
+    let connect = HTTPPair::new_request(RequestOrResponse::new_request(Resource::empty(), HeaderMap::new(), connect_meta));
+    let mut tunnel = TunnelFlow::new(connect, &target);
+    for message in state.get("messages").map(TValue::as_list).unwrap_or_default() {
+        let [from_client, content, ..] = message.as_list() else {
+            continue;
+        };
+        let length = content.as_bytes().map_or(0, |content| content.len() as u64);
+        match from_client.as_bool() {
+            Some(true) => tunnel.bytes_sent += length,
+            _ => tunnel.bytes_received += length
+        }
+    }
+    tunnel.closed_at = server.and_then(|server| server.get("timestamp_end")).and_then(TValue::as_f64).map(millis);
+    Ok(tunnel)
+}
+
+pub fn flow_from_state(state: &TValue) -> Result<Flow, String> {
+    let kind = state.get("type").and_then(TValue::as_str).unwrap_or_else(|| "http".to_string());
+    let version = state.get("version").and_then(TValue::as_i64);
+    let (content, http_state) = match kind.as_str() {
+        "http" => {
+            let http_pair = http_pair(state)?;
+            match state.get("websocket") {
+                Some(websocket) => (FlowContent::WebSocket(websocket_flow(http_pair, websocket, version)), state),
+                None => (FlowContent::RequestResponse(http_pair), state)
+            }
+        },
+        "websocket" => {
+            let handshake = state.get("handshake_flow").ok_or("websocket flow has no handshake")?;
+            (FlowContent::WebSocket(websocket_flow(http_pair(handshake)?, state, version)), handshake)
+        },
+        "tcp" => (FlowContent::Tunnel(tunnel_flow(state)?), state),
+        other => return Err(format!("{} flows aren't supported", other))
+    };
+
+    let mut flow = Flow::new(content);
+    flow.is_active = false;
+    flow.timings = flow_timings(http_state);
+    let client = state.get("client_conn");
+    flow.connection.client_addr = address(client.and_then(|client| client.get("peername").or_else(|| client.get("address"))))
+        .and_then(|(host, port)| Some(SocketAddr::new(host.parse::<IpAddr>().ok()?, port)));
+    flow.connection.tls = state.get("server_conn").and_then(tls_info);
+    flow.connection.error = state.get("error").and_then(|error| error.get("msg")).and_then(TValue::as_str);
+    Ok(flow)
+}
+
+// flows that can't be converted are skipped with a warning, a broken tnetstring ends the import there
+pub fn import_mitmproxy(data: &[u8]) -> Result<Vec<Flow>, String> {
+    let mut flows = Vec::new();
+    let mut remaining = data;
+    while !remaining.iter().all(|byte| byte.is_ascii_whitespace()) {
+        let (state, rest) = match parse_tnetstring(remaining) {
+            Ok(parsed) => parsed,
+            Err(e) if flows.is_empty() => return Err(format!("not a mitmproxy flow file: {}", e)),
+            Err(e) => {
+                warn!("stopping after {} flows, the rest of the file is unreadable: {}", flows.len(), e);
+                break;
+            }
+        };
+        remaining = rest;
+        match flow_from_state(&state) {
+            Ok(flow) => flows.push(flow),
+            Err(e) => warn!("skipping mitmproxy flow {}: {}", state.get("id").and_then(TValue::as_str).unwrap_or_default(), e)
+        }
+    }
+    Ok(flows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tnetstring(payload: &[u8], kind: char) -> Vec<u8> {
+        let mut value = format!("{}:", payload.len()).into_bytes();
+        value.extend_from_slice(payload);
+        value.push(kind as u8);
+        value
+    }
+
+    fn list(values: &[Vec<u8>]) -> Vec<u8> {
+        tnetstring(&values.concat(), ']')
+    }
+
+    fn dict(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let payload: Vec<u8> = entries.iter().flat_map(|(key, value)| [tnetstring(key.as_bytes(), ';'), value.clone()].concat()).collect();
+        tnetstring(&payload, '}')
+    }
+
+    #[test]
+    fn parses_values_and_rest() {
+        let data = [tnetstring(b"5", '#'), b"extra".to_vec()].concat();
+        let (value, rest) = parse_tnetstring(&data).unwrap();
+        assert_eq!(value.as_i64(), Some(5));
+        assert_eq!(rest, b"extra");
+
+        let data = dict(&[("a", list(&[tnetstring(b"x", ','), tnetstring(b"true", '!'), tnetstring(b"", '~')]))]);
+        let (value, _) = parse_tnetstring(&data).unwrap();
+        let items = value.get("a").unwrap().as_list();
+        assert_eq!(items[0].as_bytes(), Some(&b"x"[..]));
+        assert_eq!(items[1].as_bool(), Some(true));
+        assert!(matches!(items[2], TValue::Null));
+    }
+
+    #[test]
+    fn rejects_broken_input() {
+        assert!(parse_tnetstring(b"10:abc,").is_err());
+        assert!(parse_tnetstring(b"abc").is_err());
+        assert!(parse_tnetstring(b"99999999999999999999:x,").is_err());
+        assert!(parse_tnetstring(b"3:abc?").is_err());
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut data = tnetstring(b"", ']');
+        for _ in 0..1000 {
+            data = tnetstring(&data, ']');
+        }
+        assert!(parse_tnetstring(&data).is_err());
+
+        let mut data = tnetstring(b"", ']');
+        for _ in 0..MAX_DEPTH {
+            data = tnetstring(&data, ']');
+        }
+        assert!(parse_tnetstring(&data).is_ok());
+    }
+
+    #[test]
+    fn websocket_message_orders() {
+        let new = list(&[tnetstring(b"1", '#'), tnetstring(b"hi", ','), tnetstring(b"true", '!'), tnetstring(b"1.5", '^')]);
+        let old = list(&[tnetstring(b"2", '#'), tnetstring(b"false", '!'), tnetstring(b"\x01", ','), tnetstring(b"1.5", '^'), tnetstring(b"false", '!')]);
+        let (new, _) = parse_tnetstring(&new).unwrap();
+        let (old, _) = parse_tnetstring(&old).unwrap();
+
+        for version in [Some(19), None] {
+            let message = websocket_message(&new, version).unwrap();
+            assert_eq!(message.direction, WebSocketDirection::ClientToServer);
+            assert_eq!(message.payload, b"hi");
+            assert_eq!(message.timestamp, 1500);
+        }
+        for version in [Some(11), None] {
+            let message = websocket_message(&old, version).unwrap();
+            assert_eq!(message.direction, WebSocketDirection::ServerToClient);
+            assert_eq!(message.opcode, WebSocketOpcode::Binary);
+            assert_eq!(message.payload, b"\x01");
+        }
+        // the wrong order doesn't decode into something made up
+        assert!(websocket_message(&old, Some(19)).is_none());
+    }
+}
@@ -0,0 +1,159 @@
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use hudsucker::tokio_tungstenite::tungstenite::Message;
+use log::warn;
+
+use crate::resource::{Flow, RequestOrResponse, WebSocketDirection};
+
+#[derive(Debug, Clone)]
+pub struct PluginContext {
+    pub client_addr: SocketAddr,
+    pub flow_id: Option<String>,
+    // bodies that decode past this are handed to plugins still encoded
+    pub max_body_size: u64,
+}
+
+#[derive(Debug)]
+pub enum PluginAction {
+    // leave the message alone (or just looked at it)
+    Continue,
+    // the plugin changed the message in place, a changed body needs fix_body_framing on it
+    Modified,
+    // skip everything after this and answer with this response instead
+    Respond(Box<RequestOrResponse>),
+    // throw the message away
+    Drop,
+}
+
+// every hook has a default so plugins only implement what they care about
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn on_request(&self, _ctx: &PluginContext, _request: &mut RequestOrResponse) -> PluginAction {
+        PluginAction::Continue
+    }
+
+    async fn on_response(&self, _ctx: &PluginContext, _response: &mut RequestOrResponse) -> PluginAction {
+        PluginAction::Continue
+    }
+
+    // Respond has no meaning for a websocket frame, it is ignored here
+    async fn on_websocket_message(&self, _ctx: &PluginContext, _direction: WebSocketDirection, _message: &mut Message) -> PluginAction {
+        PluginAction::Continue
+    }
+
+    // observe only, called once the flow won't change anymore
+    async fn on_flow_completed(&self, _ctx: &PluginContext, _flow: &Flow) {}
+}
+
+// plugins run in registration order
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl PluginRegistry {
+    pub fn new() -> Self {
+        Self {
+            plugins: Vec::new()
+        }
+    }
+
+    pub fn register(&mut self, plugin: Arc<dyn Plugin>) {
+        self.plugins.push(plugin);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<Arc<dyn Plugin>> {
+        let index = self.plugins.iter().position(|plugin| plugin.name() == name)?;
+        Some(self.plugins.remove(index))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.plugins.iter().map(|plugin| plugin.name().to_string()).collect()
+    }
+
+    // cheap copy of the list so hooks can be awaited without holding the lock
+    pub fn snapshot(&self) -> Vec<Arc<dyn Plugin>> {
+        self.plugins.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+}
+
+// runs on_request or on_response over the chain, stopping at the first plugin that responds or drops
+pub async fn run_message_hooks(plugins: &[Arc<dyn Plugin>], ctx: &PluginContext, message: &mut RequestOrResponse) -> PluginAction {
+    if plugins.is_empty() {
+        return PluginAction::Continue;
+    }
+
+    let mut modified = false;
+    for plugin in plugins {
+        let action = if message.is_response {
+            plugin.on_response(ctx, message).await
+        } else {
+            plugin.on_request(ctx, message).await
+        };
+        match action {
+            PluginAction::Continue => {},
+            PluginAction::Modified => modified = true,
+            short_circuit => return short_circuit
+        }
+    }
+
+    match modified {
+        true => PluginAction::Modified,
+        false => PluginAction::Continue
+    }
+}
+
+pub async fn run_websocket_hooks(plugins: &[Arc<dyn Plugin>], ctx: &PluginContext, direction: WebSocketDirection, message: &mut Message) -> PluginAction {
+    let mut modified = false;
+    for plugin in plugins {
+        match plugin.on_websocket_message(ctx, direction, message).await {
+            PluginAction::Continue => {},
+            PluginAction::Modified => modified = true,
+            PluginAction::Respond(_) => {
+                warn!("plugin {} tried to respond to a websocket message, ignoring", plugin.name());
+            },
+            PluginAction::Drop => return PluginAction::Drop
+        }
+    }
+
+    if modified {
+        PluginAction::Modified
+    } else {
+        PluginAction::Continue
+    }
+}
+
+pub async fn run_flow_completed_hooks(plugins: &[Arc<dyn Plugin>], ctx: &PluginContext, flow: &Flow) {
+    for plugin in plugins {
+        plugin.on_flow_completed(ctx, flow).await;
+    }
+}
+
+// plugin files in a directory with their mtimes (for reloading), sorted by file name since that is the run order
+pub(crate) fn list_plugin_files(dir: &Path, extension: &str) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<(PathBuf, Option<SystemTime>)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|path_extension| path_extension == extension))
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+            (path, modified)
+        })
+        .collect();
+    files.sort();
+    files
+}
@@ -1,49 +1,132 @@
-use std::{collections::HashMap, sync::{Arc, RwLock}};
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, sync::{Arc, Mutex, OnceLock, RwLock}, time::{Duration, SystemTime}};
 
-use hudsucker::{certificate_authority::RcgenAuthority, decode_request, decode_response, hyper::{Request, Response}, rcgen::{self, CertificateParams, KeyPair}, rustls::crypto::aws_lc_rs, tokio_tungstenite::tungstenite::{http::request, Message}, Body, HttpContext, HttpHandler, Proxy, RequestOrResponse, WebSocketContext, WebSocketHandler};
-use log::warn;
-use tokio::sync::watch::Receiver;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use hudsucker::{hyper::{self, body::Body as _, HeaderMap, Method, Request, Response, StatusCode, Uri}, hyper_util::rt::TokioIo, rcgen, rustls::{self, crypto::aws_lc_rs}, tokio_tungstenite::{self, tungstenite::{self, protocol::Role, Message}, Connector, WebSocketStream}, Body, HttpContext, HttpHandler, Proxy, RequestOrResponse, WebSocketContext, WebSocketHandler};
+use log::{error, info, warn};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream, sync::{mpsc, watch::Receiver}};
 
-use crate::{config::{self, Config}, resource::{Flow, FlowContent, HTTPPair, ResolveString}};
+use crate::{body::{on_body_end, remove_spilled_bodies, remove_stale_spills, spill_path, tee_body, BodyRecorder, PendingBody, PendingReason}, certs::build_authority, config::{Config, ScopeTarget}, connection::{ConnectionTracker, TlsInfo}, database::persist_flows, encoding::DecodedEdit, intercept::{InterceptDecision, InterceptQueue, InterceptedMessage}, plugin::{run_flow_completed_hooks, run_message_hooks, run_websocket_hooks, Plugin, PluginAction, PluginContext, PluginRegistry}, resource::{get_current_time, is_websocket_upgrade, Flow, FlowContent, HTTPPair, Resource, ResponseMeta, TunnelFlow, WebSocketDirection, WebSocketFlow, WebSocketMessage}, scripting::ScriptPlugin, timing::{ConnectTimings, FlowTimings}, tunnel::{relay, TunnelCounters}, upstream::{build_client, UpstreamConnector}, wasm::WasmPlugin};
 
 // rewrite
 #[derive(Debug, Default)]
 pub struct FlowStorage {
     pub flows: HashMap<String, Flow>,
     pub flow_id_timeline: Vec<String>,
+    // only collected while something persists the flows, see track_changes
+    changes: Option<FlowChanges>,
+}
+
+// flows touched since the last take_changes, in the order they were first touched
+#[derive(Debug, Default)]
+pub struct FlowChanges {
+    pub changed: Vec<String>,
+    // (flow id, is response) of the bodies that were replaced or grew, the rest are already written
+    pub changed_bodies: HashSet<(String, bool)>,
+    pub removed: Vec<String>,
 }
 
 impl FlowStorage {
     pub fn new() -> Self {
         Self {
             flows: HashMap::new(),
-            flow_id_timeline: Vec::new()
+            flow_id_timeline: Vec::new(),
+            changes: None
         }
     }
     
     pub fn add_flow(&mut self, flow: Flow) {
         // 2 clones here
         let id = flow.get_id();
+        if let Some(changes) = &mut self.changes {
+            changes.changed.push(id.clone());
+            changes.changed_bodies.insert((id.clone(), false));
+            changes.changed_bodies.insert((id.clone(), true));
+        }
         self.flows.insert(id.clone(), flow);
         self.flow_id_timeline.push(id);
     }
 
+    // flows read back from disk, they go before anything captured since
+    pub fn add_loaded_flows(&mut self, flows: Vec<Flow>) {
+        let mut timeline = Vec::with_capacity(flows.len() + self.flow_id_timeline.len());
+        for flow in flows {
+            let id = flow.get_id();
+            if self.flows.contains_key(&id) {
+                continue;
+            }
+            timeline.push(id.clone());
+            self.flows.insert(id, flow);
+        }
+        timeline.append(&mut self.flow_id_timeline);
+        self.flow_id_timeline = timeline;
+    }
+
     pub fn get_flow(&self, id: &str) -> Option<&Flow> {
         self.flows.get(id)
     }
 
+    // anything handed out here counts as changed
     pub fn get_flow_mut(&mut self, id: &str) -> Option<&mut Flow> {
-        self.flows.get_mut(id)
+        let flow = self.flows.get_mut(id)?;
+        if let Some(changes) = &mut self.changes {
+            if changes.changed.last().map(|last| last.as_str()) != Some(id) {
+                changes.changed.push(id.to_string());
+            }
+        }
+        Some(flow)
+    }
+
+    // same as get_flow_mut, for when the request or response body gets replaced or grows
+    pub fn get_flow_body_mut(&mut self, id: &str, is_response: bool) -> Option<&mut Flow> {
+        if let Some(changes) = &mut self.changes {
+            if self.flows.contains_key(id) {
+                changes.changed_bodies.insert((id.to_string(), is_response));
+            }
+        }
+        self.get_flow_mut(id)
     }
 
     pub fn remove_flow(&mut self, id: &str) -> Option<Flow> {
         let flow_opt = self.flows.remove(id);
-        if flow_opt.is_some() {
+        if let Some(flow) = &flow_opt {
+            remove_spilled_bodies(flow);
             self.flow_id_timeline.retain(|x| x != id);
+            if let Some(changes) = &mut self.changes {
+                changes.removed.push(id.to_string());
+            }
         }
         flow_opt
     }
 
+    pub fn track_changes(&mut self) {
+        self.changes.get_or_insert_with(FlowChanges::default);
+    }
+
+    pub fn take_changes(&mut self) -> FlowChanges {
+        let Some(changes) = &mut self.changes else {
+            return FlowChanges::default();
+        };
+        let mut changes = std::mem::take(changes);
+        let mut seen = HashSet::new();
+        changes.changed.retain(|id| self.flows.contains_key(id) && seen.insert(id.clone()));
+        changes.changed_bodies.retain(|(id, _)| self.flows.contains_key(id));
+        changes
+    }
+
+    // hands back changes that couldn't be written, so the next take_changes has them again
+    pub fn restore_changes(&mut self, restored: FlowChanges) {
+        let Some(changes) = &mut self.changes else {
+            return;
+        };
+        let mut changed = restored.changed;
+        changed.append(&mut changes.changed);
+        changes.changed = changed;
+        changes.changed_bodies.extend(restored.changed_bodies);
+        let mut removed = restored.removed;
+        removed.append(&mut changes.removed);
+        changes.removed = removed;
+    }
+
     pub fn iter_flow_timeline(&self) -> impl Iterator<Item=&Flow> {
         self.flow_id_timeline.iter().map(|id| self.flows.get(id).unwrap())
     }
@@ -57,18 +140,118 @@ impl FlowStorage {
     }
 }
 
+struct PendingWebSocket {
+    flow_id: String,
+    // each direction gets its own forwarder, the flow is done once every claimed side let go
+    claimed: u8,
+    open: u8,
+}
+
+// hudsucker opens the websocket on its own after handle_request, this is how the two forwarders find their flow
+// keyed by (client addr, ws:// or wss:// uri), the client addr is unique per upgraded connection
+#[derive(Default)]
+pub struct PendingWebSockets {
+    links: HashMap<(SocketAddr, String), PendingWebSocket>,
+}
+
+impl PendingWebSockets {
+    pub fn new() -> Self {
+        Self {
+            links: HashMap::new()
+        }
+    }
+
+    pub fn link(&mut self, client_addr: SocketAddr, uri: String, flow_id: String) {
+        self.links.insert((client_addr, uri), PendingWebSocket {
+            flow_id,
+            claimed: 0,
+            open: 0
+        });
+    }
+
+    pub fn claim(&mut self, client_addr: SocketAddr, uri: &str) -> Option<String> {
+        let pending = self.links.get_mut(&(client_addr, uri.to_string()))?;
+        pending.claimed += 1;
+        pending.open += 1;
+        Some(pending.flow_id.clone())
+    }
+
+    // the upgrade never happened, nothing is going to claim the link
+    pub fn unlink(&mut self, client_addr: SocketAddr, uri: &str) {
+        let key = (client_addr, uri.to_string());
+        if self.links.get(&key).is_some_and(|pending| pending.claimed == 0) {
+            self.links.remove(&key);
+        }
+    }
+
+    // true when this was the last open side of the websocket
+    pub fn release(&mut self, client_addr: SocketAddr, uri: &str) -> bool {
+        let key = (client_addr, uri.to_string());
+        let Some(pending) = self.links.get_mut(&key) else {
+            return false;
+        };
+        pending.open = pending.open.saturating_sub(1);
+        if pending.open == 0 && pending.claimed >= 2 {
+            self.links.remove(&key);
+            return true;
+        }
+        false
+    }
+}
+
+// the uri hudsucker hands to the websocket handler for an upgrade request
+fn websocket_uri(uri: &Uri) -> String {
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = match parts.scheme.as_ref().map(|scheme| scheme.as_str()) {
+        Some("http") | None => Some("ws".try_into().expect("Failed to convert scheme")),
+        _ => Some("wss".try_into().expect("Failed to convert scheme"))
+    };
+    match Uri::from_parts(parts) {
+        Ok(uri) => uri.to_string(),
+        Err(_) => uri.to_string()
+    }
+}
+
+// live websocket sides that accept injected messages, (flow id, direction the message travels)
+pub type WebSocketInjectors = HashMap<(String, WebSocketDirection), mpsc::UnboundedSender<Message>>;
+
 pub struct TelescopeProxy {
     pub storage: Arc<RwLock<FlowStorage>>, // flow id -> flow
     pub config: Receiver<Config>, 
-    // rwlock plugins
+    pub intercept_queue: Arc<RwLock<InterceptQueue>>,
+    pub plugins: Arc<RwLock<PluginRegistry>>,
+    pub pending_websockets: Arc<RwLock<PendingWebSockets>>,
+    pub websocket_injectors: Arc<RwLock<WebSocketInjectors>>,
 }
 
 impl TelescopeProxy {
     pub fn new(config: Receiver<Config>) -> Self {
-        Self {
+        let scripts_dir = config.borrow().data_dir.join("scripts");
+        let plugins_dir = config.borrow().data_dir.join("plugins");
+        let proxy = Self {
             storage: Arc::new(RwLock::new(FlowStorage::new())),
-            config: config
-        }
+            config: config,
+            intercept_queue: Arc::new(RwLock::new(InterceptQueue::new())),
+            plugins: Arc::new(RwLock::new(PluginRegistry::new())),
+            pending_websockets: Arc::new(RwLock::new(PendingWebSockets::new())),
+            websocket_injectors: Arc::new(RwLock::new(HashMap::new()))
+        };
+        // built in plugins go first
+        proxy.register_plugin(Arc::new(ScriptPlugin::new(scripts_dir)));
+        proxy.register_plugin(Arc::new(WasmPlugin::new(plugins_dir, proxy.storage.clone())));
+        proxy
+    }
+
+    pub fn register_plugin(&self, plugin: Arc<dyn Plugin>) {
+        self.plugins.write().unwrap().register(plugin);
+    }
+
+    pub fn inject_websocket_message(&self, flow_id: &str, direction: WebSocketDirection, message: Message) -> Result<(), String> {
+        let injectors = self.websocket_injectors.read().unwrap();
+        let Some(injector) = injectors.get(&(flow_id.to_string(), direction)) else {
+            return Err("websocket is not open".to_string());
+        };
+        injector.send(message).map_err(|_| "websocket is closing".to_string())
     }
 }
 
@@ -76,19 +259,25 @@ impl TelescopeProxy {
 pub struct TelescopeProxyRef {
     pub proxy: Arc<RwLock<TelescopeProxy>>,
     pub config: Receiver<Config>,
+    pub connections: Arc<Mutex<ConnectionTracker>>,
+    // what the client connects with, set once the proxy starts, websocket upgrades connect through it too
+    pub upstream: Arc<OnceLock<UpstreamConnector>>,
 }
 
 #[derive(Debug)]
 pub enum StartupError {
     RcgenError(rcgen::Error),
     HudsuckerError(hudsucker::Error),
+    TlsError(rustls::Error),
 }
 
 impl TelescopeProxyRef {
     pub fn wrap(proxy: TelescopeProxy) -> Self {
         Self {
             config: proxy.config.clone(),
-            proxy: Arc::new(RwLock::new(proxy))
+            proxy: Arc::new(RwLock::new(proxy)),
+            connections: Arc::new(Mutex::new(ConnectionTracker::default())),
+            upstream: Arc::new(OnceLock::new())
         }
     }
 
@@ -100,28 +289,25 @@ impl TelescopeProxyRef {
 
             let config = self.config.borrow();
 
-            match KeyPair::from_pem(&config.resolve_string(&config.ca.key_pair)) {
-                Ok(key_pair) => {
-                    match CertificateParams::from_ca_cert_pem(&config.resolve_string(&config.ca.certificate)) {
-                        Ok(ca_cert) => {
-                            match ca_cert.self_signed(&key_pair) {
-                                Ok(ca_cert) => {
-                                    let ca = RcgenAuthority::new(key_pair, ca_cert, 1_000, aws_lc_rs::default_provider());
-                                    match Proxy::builder()
-                                        .with_addr(config.addr).with_ca(ca).with_rustls_client(aws_lc_rs::default_provider())
-                                        .with_http_handler(TelescopeProxyHandler::new(self.clone()))
-                                        .with_websocket_handler(TelescopeProxyHandler::new(self.clone()))
-                                        .build() {
-                                        Ok(proxy) => {
-                                            Ok(proxy)
-                                        },
-                                        Err(e) => Err(StartupError::HudsuckerError(e))
-                                    }
+            match build_authority(&config) {
+                Ok(ca) => {
+                    match UpstreamConnector::new(self.config.clone(), aws_lc_rs::default_provider()) {
+                        Ok(connector) => {
+                            let websocket_tls = connector.websocket_tls_config();
+                            let connector = self.upstream.get_or_init(|| connector).clone();
+                            match Proxy::builder()
+                                .with_addr(config.addr).with_ca(ca).with_client(build_client(connector))
+                                .with_http_handler(TelescopeProxyHandler::new(self.clone()))
+                                .with_websocket_handler(TelescopeProxyHandler::new(self.clone()))
+                                .with_websocket_connector(Connector::Rustls(websocket_tls))
+                                .build() {
+                                Ok(proxy) => {
+                                    Ok(proxy)
                                 },
-                                Err(e) => Err(StartupError::RcgenError(e))
+                                Err(e) => Err(StartupError::HudsuckerError(e))
                             }
                         },
-                        Err(e) => Err(StartupError::RcgenError(e))
+                        Err(e) => Err(StartupError::TlsError(e))
                     }
                 },
                 Err(e) => Err(StartupError::RcgenError(e))
@@ -129,6 +315,50 @@ impl TelescopeProxyRef {
         };
         match maybe_proxy {
             Ok(proxy) => {
+                let (project, data_dir) = {
+                    let config = self.config.borrow();
+                    (config.project.clone(), config.data_dir.clone())
+                };
+                let storage = self.proxy.read().unwrap().storage.clone();
+                if project.enabled {
+                    let path = data_dir.join(&project.path);
+                    // not worth refusing to start over, the flows just won't survive a restart
+                    if let Err(e) = persist_flows(&path, &data_dir, storage, Duration::from_millis(project.flush_interval_ms)) {
+                        error!("failed to open project database {}: {}", path.display(), e);
+                    }
+                } else {
+                    // nothing from an earlier run is coming back, so none of its body files are needed
+                    let started_at = SystemTime::now();
+                    std::thread::spawn(move || remove_stale_spills(&data_dir, started_at, &storage));
+                }
+                let (socks_addr, transparent_addr, reverse_addr) = {
+                    let config = self.config.borrow();
+                    (config.socks_addr, config.transparent_addr, config.reverse.listen_addr)
+                };
+                if let Some(socks_addr) = socks_addr {
+                    let proxy_ref = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = crate::socks::serve(proxy_ref, socks_addr).await {
+                            error!("socks listener on {} stopped: {}", socks_addr, e);
+                        }
+                    });
+                }
+                if let Some(transparent_addr) = transparent_addr {
+                    let proxy_ref = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = crate::transparent::serve(proxy_ref, transparent_addr).await {
+                            error!("transparent listener on {} stopped: {}", transparent_addr, e);
+                        }
+                    });
+                }
+                if let Some(reverse_addr) = reverse_addr {
+                    let proxy_ref = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = crate::reverse::serve(proxy_ref, reverse_addr).await {
+                            error!("reverse proxy listener on {} stopped: {}", reverse_addr, e);
+                        }
+                    });
+                }
                 match proxy.start().await {
                     Ok(_) => {
                         Ok(())
@@ -141,72 +371,1043 @@ impl TelescopeProxyRef {
     }
 }
 
+fn dropped_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from("Request dropped by Telescope"))
+        .expect("Failed to build response")
+}
+
 #[derive(Clone)]
 pub struct TelescopeProxyHandler {
     pub proxy_ref: TelescopeProxyRef,
     pub flow_id: Option<String>,
     pub flow_storage: Arc<RwLock<FlowStorage>>,
+    pub intercept_queue: Arc<RwLock<InterceptQueue>>,
+    pub plugins: Arc<RwLock<PluginRegistry>>,
+    pub pending_websockets: Arc<RwLock<PendingWebSockets>>,
+    pub websocket_injectors: Arc<RwLock<WebSocketInjectors>>,
+    // the pending websocket this request linked, until the upgrade answer is in
+    pub websocket_link: Option<(SocketAddr, String)>,
+    // hidden out of scope flows are recorded, plugins and intercept leave them alone
+    pub in_scope: bool,
+}
+
+// sse and the like are expected, a big or slow body slipping past plugins and intercept is worth knowing about
+fn streamed_warning(message: &crate::resource::RequestOrResponse, request: &crate::resource::RequestMeta, reason: PendingReason) {
+    let side = if message.is_response { "response" } else { "request" };
+    match reason {
+        PendingReason::Streaming => info!("{} body of {} {} streamed through, {}", side, request.method, request.url, reason.as_str()),
+        _ => warn!("{} body of {} {} streamed through without plugins or intercept, {}", side, request.method, request.url, reason.as_str())
+    }
+}
+
+// keeps hyper's upgrade handles and such from the request we are replacing
+fn rebuild_request(edited: &crate::resource::RequestOrResponse, mut original: Request<Body>) -> Request<Body> {
+    match edited.to_request() {
+        Ok(mut edited_request) => {
+            *edited_request.extensions_mut() = std::mem::take(original.extensions_mut());
+            edited_request
+        },
+        Err(e) => {
+            warn!("could not rebuild edited request, forwarding original: {}", e);
+            original
+        }
+    }
+}
+
+fn rebuild_response(edited: &crate::resource::RequestOrResponse, mut original: Response<Body>) -> Response<Body> {
+    match edited.to_response() {
+        Ok(mut edited_response) => {
+            *edited_response.extensions_mut() = std::mem::take(original.extensions_mut());
+            edited_response
+        },
+        Err(e) => {
+            warn!("could not rebuild edited response, forwarding original: {}", e);
+            original
+        }
+    }
 }
 
 impl TelescopeProxyHandler {
     pub fn new(proxy_ref: TelescopeProxyRef) -> Self {
-        let flow_storage = {
+        let (flow_storage, intercept_queue, plugins, pending_websockets, websocket_injectors) = {
             let proxy = proxy_ref.proxy.read().unwrap();
-            proxy.storage.clone()
+            (proxy.storage.clone(), proxy.intercept_queue.clone(), proxy.plugins.clone(), proxy.pending_websockets.clone(), proxy.websocket_injectors.clone())
         };
 
         Self {
             proxy_ref,
             flow_id: None,
-            flow_storage: flow_storage
+            flow_storage: flow_storage,
+            intercept_queue,
+            plugins,
+            pending_websockets,
+            websocket_injectors,
+            websocket_link: None,
+            in_scope: true
+        }
+    }
+
+    fn active_plugins(&self) -> Vec<Arc<dyn Plugin>> {
+        match self.in_scope {
+            true => self.plugins.read().unwrap().snapshot(),
+            false => Vec::new()
+        }
+    }
+
+    fn plugin_context(&self, client_addr: std::net::SocketAddr) -> PluginContext {
+        PluginContext {
+            client_addr,
+            flow_id: self.flow_id.clone(),
+            max_body_size: self.proxy_ref.config.borrow().body_storage.max_recorded_size
+        }
+    }
+
+    // the flow is done changing, let plugins have a look without holding up the client
+    fn complete_flow(&mut self, client_addr: std::net::SocketAddr) {
+        let Some(flow_id) = self.flow_id.clone() else {
+            return;
+        };
+        let plugins = self.active_plugins();
+        mark_flow_completed(&self.flow_storage, plugins, self.plugin_context(client_addr), &flow_id);
+    }
+
+    // parks the message in the intercept queue until the ui forwards or drops it
+    // the ui edits the decoded body, whatever comes back gets encoded again to match its headers
+    async fn hold_for_intercept(&self, message: crate::resource::RequestOrResponse) -> InterceptDecision {
+        let max_body_size = self.proxy_ref.config.borrow().body_storage.max_recorded_size;
+        let (decoded_edit, held) = DecodedEdit::decode(&message, max_body_size).await;
+
+        let (intercepted, decision) = InterceptedMessage::new(self.flow_id.clone(), held);
+        self.intercept_queue.write().unwrap().push(intercepted);
+        let mut edited = match decision.await {
+            Ok(InterceptDecision::Forward(edited)) => edited,
+            Ok(InterceptDecision::Drop) => return InterceptDecision::Drop,
+            Err(_) => {
+                // queue got torn down, don't hang the client forever
+                warn!("intercept queue went away, forwarding message unchanged");
+                return InterceptDecision::Forward(Box::new(message));
+            }
+        };
+        decoded_edit.encode(&mut edited).await;
+        InterceptDecision::Forward(edited)
+    }
+
+    // None means the request was dropped
+    async fn hold_request(&mut self, request: crate::resource::RequestOrResponse, original: Request<Body>) -> Option<Request<Body>> {
+        match self.hold_for_intercept(request).await {
+            InterceptDecision::Forward(edited) => {
+                let edited_request = rebuild_request(&edited, original);
+                self.update_flow_body(false, |http_pair| http_pair.request = *edited);
+                Some(edited_request)
+            },
+            InterceptDecision::Drop => None
+        }
+    }
+
+    async fn hold_response(&mut self, response: crate::resource::RequestOrResponse, original: Response<Body>) -> Option<Response<Body>> {
+        match self.hold_for_intercept(response).await {
+            InterceptDecision::Forward(edited) => {
+                let edited_response = rebuild_response(&edited, original);
+                self.update_flow_body(true, |http_pair| http_pair.add_response(*edited));
+                Some(edited_response)
+            },
+            InterceptDecision::Drop => None
+        }
+    }
+
+    // is_response says which body the update may replace
+    fn update_flow_body(&self, is_response: bool, update: impl FnOnce(&mut HTTPPair)) {
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_body_mut(flow_id, is_response) {
+                update(flow.content.http_pair_mut());
+            }
+        }
+    }
+
+    // the body is copied into the flow as it streams, a streamed response also keeps the flow active until it ends
+    fn record_streamed_body(&self, pending_body: PendingBody, is_response: bool, client_addr: std::net::SocketAddr) -> Body {
+        let Some(flow_id) = self.flow_id.clone() else {
+            return pending_body.into_body();
+        };
+        let plugins = self.active_plugins();
+        let ctx = self.plugin_context(client_addr);
+        let (spill_path, limits) = {
+            let config = self.proxy_ref.config.borrow();
+            let side = if is_response { "response" } else { "request" };
+            (spill_path(&config.data_dir, &flow_id, side), config.body_storage.clone())
+        };
+        let flow_storage = self.flow_storage.clone();
+        tee_body(pending_body, BodyRecorder::new(spill_path, limits), move |recorded, done| {
+            if let Some(flow) = flow_storage.write().unwrap().get_flow_body_mut(&flow_id, is_response) {
+                let http_pair = flow.content.http_pair_mut();
+                let message = if is_response { http_pair.response.as_mut() } else { Some(&mut http_pair.request) };
+                if let Some(message) = message {
+                    message.body = recorded.body;
+                    message.body_truncated = recorded.truncated;
+                }
+                if done {
+                    let now = flow.timings.now();
+                    if is_response {
+                        flow.timings.response_complete = now;
+                    } else {
+                        // streamed through as it arrived, so received and sent are the same moment
+                        flow.timings.request_received = now;
+                        flow.timings.request_sent = now;
+                    }
+                }
+            }
+            if done && is_response {
+                mark_flow_completed(&flow_storage, plugins.clone(), ctx.clone(), &flow_id);
+            }
+        })
+    }
+
+    fn update_timings(&self, update: impl FnOnce(&mut FlowTimings)) {
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                update(&mut flow.timings);
+            }
+        }
+    }
+
+    // last stop before the upstream client, the body is watched to tell when it was sent
+    fn forward_request(&self, mut req: Request<Body>) -> Request<Body> {
+        self.update_timings(|timings| timings.forwarded = timings.now());
+        let Some(flow_id) = self.flow_id.clone() else {
+            return req;
+        };
+        if req.body().is_end_stream() {
+            // hyper never polls an empty body, handle_response falls back to when the connection was ready
+            return req;
+        }
+        let flow_storage = self.flow_storage.clone();
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        *req.body_mut() = on_body_end(body, move || {
+            if let Some(flow) = flow_storage.write().unwrap().get_flow_mut(&flow_id) {
+                flow.timings.request_sent = flow.timings.now();
+            }
+        });
+        req
+    }
+
+    fn is_tls_passthrough(&self, uri: &Uri) -> bool {
+        uri.host().is_some_and(|host| self.proxy_ref.config.borrow().is_tls_passthrough(host))
+    }
+
+    // answers the CONNECT ourselves and relays the upgraded connection as raw tcp so the byte counts can be recorded
+    async fn start_tunnel(&mut self, client_addr: SocketAddr, mut req: Request<Body>) -> Response<Body> {
+        let Some(authority) = req.uri().authority().cloned() else {
+            return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap();
+        };
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        let limits = self.proxy_ref.config.borrow().body_storage.clone();
+        let (req_intermediate, _, _) = crate::resource::RequestOrResponse::copy_request(req, &limits).await;
+        let mut connect = HTTPPair::new_request(req_intermediate);
+        connect.add_response(crate::resource::RequestOrResponse::new_response(Resource::empty(), HeaderMap::new(), ResponseMeta::new(200, "HTTP/1.1")));
+        self.add_tunnel_flow(client_addr, connect, authority.as_str());
+
+        let mut handler = self.clone();
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => handler.run_tunnel(client_addr, TokioIo::new(upgraded), authority.host(), authority.port_u16().unwrap_or(443), None).await,
+                Err(e) => {
+                    let error = format!("upgrade of tunnel to {} failed: {}", authority, e);
+                    warn!("{}", error);
+                    handler.close_tunnel(client_addr, Some(error));
+                }
+            }
+        });
+        Response::new(Body::empty())
+    }
+
+    pub(crate) fn add_tunnel_flow(&mut self, client_addr: SocketAddr, connect: HTTPPair, target: &str) {
+        let flow_id = Flow::generate_id();
+        self.flow_id = Some(flow_id.clone());
+        let flow = self.new_flow(flow_id, FlowContent::Tunnel(TunnelFlow::new(connect, target)), client_addr, FlowTimings::start());
+        self.flow_storage.write().unwrap().add_flow(flow);
+    }
+
+    fn new_flow(&self, flow_id: String, content: FlowContent, client_addr: SocketAddr, timings: FlowTimings) -> Flow {
+        let mut flow = Flow::new_with_id(flow_id, content);
+        flow.timings = timings;
+        let (client_addr, connection_id) = self.proxy_ref.connections.lock().unwrap().identify(client_addr);
+        flow.connection.client_addr = Some(client_addr);
+        flow.connection.connection_id = Some(connection_id);
+        flow
+    }
+
+    fn update_tunnel(&self, update: impl FnOnce(&mut TunnelFlow)) {
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                if let FlowContent::Tunnel(ref mut tunnel) = flow.content {
+                    update(tunnel);
+                }
+            }
+        }
+    }
+
+    // relays client to host:port for the tunnel flow added with add_tunnel_flow, returns once both sides are closed
+    // server is an already open connection to host:port, when the caller had to connect before answering its client
+    pub(crate) async fn run_tunnel(&mut self, client_addr: SocketAddr, client: impl AsyncRead + AsyncWrite + Unpin, host: &str, port: u16, server: Option<TcpStream>) {
+        let upstream = self.proxy_ref.config.borrow().upstream.clone();
+        let counters = Arc::new(TunnelCounters::default());
+        let server = match server {
+            Some(server) => Ok(server),
+            None => crate::upstream::connect(&upstream, host, port).await
+        };
+        let result = match server {
+            Ok(server) => {
+                relay(client, server, counters.clone(), |sent, received| self.update_tunnel(|tunnel| {
+                    tunnel.bytes_sent = sent;
+                    tunnel.bytes_received = received;
+                })).await.map_err(|e| format!("tunnel to {}:{} failed: {}", host, port, e))
+            },
+            Err(e) => Err(format!("failed to connect to {}:{}: {}", host, port, e))
+        };
+        if let Err(e) = &result {
+            warn!("{}", e);
+        }
+        let (sent, received) = counters.get();
+        self.update_tunnel(|tunnel| {
+            tunnel.bytes_sent = sent;
+            tunnel.bytes_received = received;
+        });
+        self.close_tunnel(client_addr, result.err());
+    }
+
+    fn close_tunnel(&mut self, client_addr: SocketAddr, error: Option<String>) {
+        self.update_tunnel(|tunnel| {
+            tunnel.closed_at = Some(get_current_time());
+            tunnel.error = error;
+        });
+        self.complete_flow(client_addr);
+    }
+
+    fn update_websocket(&self, update: impl FnOnce(&mut WebSocketFlow)) {
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                if let FlowContent::WebSocket(ref mut websocket) = flow.content {
+                    update(websocket);
+                }
+            }
+        }
+    }
+
+    fn record_dropped_websocket_message(&self, mut message: WebSocketMessage) {
+        message.dropped = true;
+        self.update_websocket(|websocket| websocket.add_message(message));
+    }
+
+    // frames are matched by their upgrade request, same filters as http
+    fn should_intercept_websocket_message(&self) -> bool {
+        let config = self.proxy_ref.config.borrow();
+        if !self.in_scope || !config.intercept.intercept_websocket_messages {
+            return false;
+        }
+        let Some(flow_id) = &self.flow_id else {
+            return false;
+        };
+        self.flow_storage.read().unwrap().get_flow(flow_id)
+            .is_some_and(|flow| config.intercept.should_intercept_websocket_message(flow.content.http_pair().request.meta.unwrap_request_ref()))
+    }
+
+    // holding a frame holds everything behind it in the same direction, frames can't be reordered
+    async fn hold_websocket_message(&self, message: WebSocketMessage) -> InterceptDecision<WebSocketMessage> {
+        let (intercepted, decision) = InterceptedMessage::new(self.flow_id.clone(), message.clone());
+        self.intercept_queue.write().unwrap().push_websocket(intercepted);
+        match decision.await {
+            Ok(decision) => decision,
+            Err(_) => {
+                warn!("intercept queue went away, forwarding websocket message unchanged");
+                InterceptDecision::Forward(Box::new(message))
+            }
+        }
+    }
+
+    // the upgrade goes out as a normal request, remember it so the websocket forwarders can find the flow
+    fn link_websocket(&mut self, client_addr: SocketAddr, request: &Request<Body>) {
+        if let Some(flow_id) = &self.flow_id {
+            let uri = websocket_uri(request.uri());
+            self.pending_websockets.write().unwrap().link(client_addr, uri.clone(), flow_id.clone());
+            self.websocket_link = Some((client_addr, uri));
+        }
+    }
+
+    // a refused or failed upgrade would otherwise leave its link behind forever
+    fn unlink_websocket(&mut self) {
+        if let Some((client_addr, uri)) = self.websocket_link.take() {
+            self.pending_websockets.write().unwrap().unlink(client_addr, &uri);
         }
     }
+
+    // websocket upgrades are connected here rather than by hudsucker, through the same connector as every other request,
+    // and the server answers before the client gets its 101, so a failed or refused upgrade ends up on the flow and the client
+    async fn upgrade_websocket(&mut self, client_addr: SocketAddr, mut req: Request<Body>) -> Response<Body> {
+        let Some(connector) = self.proxy_ref.upstream.get().cloned() else {
+            return self.websocket_failed(client_addr, "proxy is not running".to_string());
+        };
+        let mut server_request = Request::builder()
+            .uri(websocket_uri(req.uri()))
+            .body(())
+            .expect("Failed to build websocket request");
+        *server_request.headers_mut() = req.headers().clone();
+        // tungstenite can't do permessage-deflate, so the server mustn't get to turn it on
+        server_request.headers_mut().remove(hyper::header::SEC_WEBSOCKET_EXTENSIONS);
+        if !server_request.headers().contains_key(hyper::header::HOST) {
+            if let Some(host) = req.uri().authority().and_then(|authority| authority.as_str().parse().ok()) {
+                server_request.headers_mut().insert(hyper::header::HOST, host);
+            }
+        }
+
+        self.update_timings(|timings| timings.forwarded = timings.now());
+        let stream = match connector.connect_http1(req.uri().clone()).await {
+            Ok(stream) => stream,
+            Err(e) => return self.websocket_failed(client_addr, e.to_string())
+        };
+        let (connect, tls) = (stream.timings, stream.tls.clone());
+        let handshake = tokio_tungstenite::client_async(server_request, TokioIo::new(stream)).await;
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                flow.timings.record_connect(&connect);
+                flow.timings.request_sent = flow.timings.connection_ready();
+                flow.timings.first_byte = flow.timings.now();
+                flow.connection.tls = tls.map(|tls| TlsInfo::clone(&tls));
+            }
+        }
+        let (server, response) = match handshake {
+            Ok((server, response)) => (server, response.map(|_| Body::empty())),
+            // the server said no, the client gets to see why
+            Err(tungstenite::Error::Http(response)) => {
+                let response = response.map(|body| Body::from(http_body_util::Full::new(hyper::body::Bytes::from(body.unwrap_or_default()))));
+                let response = self.record_websocket_response(response).await;
+                self.complete_flow(client_addr);
+                return response;
+            },
+            Err(e) => return self.websocket_failed(client_addr, e.to_string())
+        };
+        let response = self.record_websocket_response(response).await;
+
+        let upgrade = hyper::upgrade::on(&mut req);
+        self.link_websocket(client_addr, &req);
+        let handler = self.clone();
+        // the forwarders claim the link from here on
+        self.websocket_link = None;
+        tokio::spawn(async move {
+            let client = match upgrade.await {
+                Ok(upgraded) => WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await,
+                Err(e) => {
+                    error!("client never took the websocket upgrade: {}", e);
+                    let mut handler = handler;
+                    handler.unlink_websocket();
+                    handler.complete_flow(client_addr);
+                    return;
+                }
+            };
+            let (server_sink, server_stream) = server.split();
+            let (client_sink, client_stream) = client.split();
+            let Some((_, uri)) = handler.websocket_link.clone() else {
+                // out of scope, nothing to record
+                tokio::spawn(server_stream.forward(client_sink));
+                tokio::spawn(client_stream.forward(server_sink));
+                return;
+            };
+            let forwarder = || TelescopeProxyHandler { in_scope: handler.in_scope, ..TelescopeProxyHandler::new(handler.proxy_ref.clone()) };
+            tokio::spawn(forwarder().forward_websocket(WebSocketDirection::ServerToClient, client_addr, uri.clone(), server_stream, client_sink));
+            tokio::spawn(forwarder().forward_websocket(WebSocketDirection::ClientToServer, client_addr, uri, client_stream, server_sink));
+        });
+        response
+    }
+
+    // the handshake answer goes on the flow like any other response
+    async fn record_websocket_response(&self, response: Response<Body>) -> Response<Body> {
+        if self.flow_id.is_none() {
+            return response;
+        }
+        let limits = self.proxy_ref.config.borrow().body_storage.clone();
+        let (recorded, response, _) = crate::resource::RequestOrResponse::copy_response(response, &limits).await;
+        self.update_flow_body(true, |http_pair| http_pair.add_response(recorded));
+        self.update_timings(|timings| timings.response_complete = timings.now());
+        response
+    }
+
+    fn websocket_failed(&mut self, client_addr: SocketAddr, message: String) -> Response<Body> {
+        warn!("failed to open websocket: {}", message);
+        if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                flow.connection.error = Some(message.clone());
+            }
+            self.complete_flow(client_addr);
+        }
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(format!("Telescope failed to open the websocket upstream: {}", message)))
+            .expect("Failed to build response")
+    }
 }
 
-impl WebSocketHandler for TelescopeProxyHandler {
-    async fn handle_message(&mut self, _ctx: &WebSocketContext, msg: Message) -> Option<Message> {
+// marks the flow as done and hands it to the plugins without holding up the client
+fn mark_flow_completed(flow_storage: &RwLock<FlowStorage>, plugins: Vec<Arc<dyn Plugin>>, ctx: PluginContext, flow_id: &str) {
+    let flow = match flow_storage.write().unwrap().get_flow_mut(flow_id) {
+        Some(flow) => {
+            flow.is_active = false;
+            flow.clone()
+        },
+        None => return
+    };
+    if plugins.is_empty() {
+        return;
+    }
+    // streamed bodies can finish while the runtime is shutting down
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    runtime.spawn(async move {
+        run_flow_completed_hooks(&plugins, &ctx, &flow).await;
+    });
+}
+
+async fn send_websocket_message(sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin), message: Message) {
+    if let Err(e) = sink.send(message).await {
+        if !matches!(e, tungstenite::Error::ConnectionClosed) {
+            error!("websocket send error: {}", e);
+        }
+    }
+}
+
+fn websocket_side(ctx: &WebSocketContext) -> (WebSocketDirection, SocketAddr, &Uri) {
+    match ctx {
+        WebSocketContext::ClientToServer { src, dst, .. } => (WebSocketDirection::ClientToServer, *src, dst),
+        WebSocketContext::ServerToClient { src, dst, .. } => (WebSocketDirection::ServerToClient, *dst, src),
+    }
+}
+
+impl TelescopeProxyHandler {
+    // same loop as hudsucker's default, plus finding the flow first and closing it at the end
+    // uri is the ws:// or wss:// one the upgrade was linked under
+    async fn forward_websocket(
+        mut self,
+        direction: WebSocketDirection,
+        client_addr: SocketAddr,
+        uri: String,
+        mut stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        mut sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+        self.flow_id = self.pending_websockets.write().unwrap().claim(client_addr, &uri);
+        if self.flow_id.is_none() {
+            warn!("websocket {} has no recorded upgrade request, frames won't be recorded", uri);
+        }
+
+        // messages typed into the ui go out on this side's sink
+        let (inject_send, mut inject_recv) = mpsc::unbounded_channel();
+        if let Some(flow_id) = &self.flow_id {
+            self.websocket_injectors.write().unwrap().insert((flow_id.clone(), direction), inject_send);
+        }
+
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    match message {
+                        Ok(message) => {
+                            let Some(message) = self.handle_frame(direction, client_addr, message).await else {
+                                continue;
+                            };
+                            send_websocket_message(&mut sink, message).await;
+                        },
+                        Err(e) => {
+                            error!("websocket message error: {}", e);
+                            send_websocket_message(&mut sink, Message::Close(None)).await;
+                            break;
+                        }
+                    }
+                },
+                Some(message) = inject_recv.recv() => {
+                    let mut recorded = WebSocketMessage::from_message(direction, &message);
+                    recorded.injected = true;
+                    self.update_websocket(|websocket| websocket.add_message(recorded));
+                    send_websocket_message(&mut sink, message).await;
+                }
+            }
+        }
+
+        if let Some(flow_id) = &self.flow_id {
+            self.websocket_injectors.write().unwrap().remove(&(flow_id.clone(), direction));
+        }
+        let closed = self.pending_websockets.write().unwrap().release(client_addr, &uri);
+        if closed {
+            self.update_websocket(|websocket| websocket.closed_at = Some(get_current_time()));
+            self.complete_flow(client_addr);
+        }
+    }
+
+    async fn handle_frame(&mut self, direction: WebSocketDirection, client_addr: SocketAddr, mut msg: Message) -> Option<Message> {
+        let plugins = self.active_plugins();
+        if !plugins.is_empty() {
+            if let PluginAction::Drop = run_websocket_hooks(&plugins, &self.plugin_context(client_addr), direction, &mut msg).await {
+                self.record_dropped_websocket_message(WebSocketMessage::from_message(direction, &msg));
+                return None;
+            }
+        }
+
+        if self.should_intercept_websocket_message() {
+            let held = WebSocketMessage::from_message(direction, &msg);
+            match self.hold_websocket_message(held.clone()).await {
+                InterceptDecision::Forward(edited) => {
+                    // unchanged frames go out as they came in
+                    if edited.opcode != held.opcode || edited.payload != held.payload {
+                        msg = edited.to_message();
+                    }
+                },
+                InterceptDecision::Drop => {
+                    self.record_dropped_websocket_message(held);
+                    return None;
+                }
+            }
+        }
+
+        // recorded as forwarded
+        self.update_websocket(|websocket| websocket.add_message(WebSocketMessage::from_message(direction, &msg)));
         Some(msg)
     }
 }
 
-impl HttpHandler for TelescopeProxyHandler {
-    async fn handle_request(&mut self, _ctx: &HttpContext, req: Request<Body> ) -> RequestOrResponse {
+impl WebSocketHandler for TelescopeProxyHandler {
+    async fn handle_websocket(
+        self,
+        ctx: WebSocketContext,
+        stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+        let (direction, client_addr, uri) = websocket_side(&ctx);
+        let uri = uri.to_string();
+        self.forward_websocket(direction, client_addr, uri, stream, sink).await;
+    }
 
-        // run plugins
+    async fn handle_message(&mut self, ctx: &WebSocketContext, msg: Message) -> Option<Message> {
+        let (direction, client_addr, _) = websocket_side(ctx);
+        self.handle_frame(direction, client_addr, msg).await
+    }
+}
 
-        let should_track = true; // TODO: discard huge bodies
+impl HttpHandler for TelescopeProxyHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body> ) -> RequestOrResponse {
+        let mut timings = FlowTimings::start();
+        let (should_track, in_scope) = {
+            let scope = &self.proxy_ref.config.borrow().scope;
+            (scope.should_record(req.uri()), scope.is_in_scope(&ScopeTarget::from_uri(req.uri())))
+        };
+        self.in_scope = in_scope;
+        if should_track && req.method() == Method::CONNECT && self.is_tls_passthrough(req.uri()) {
+            return self.start_tunnel(ctx.client_addr, req).await.into();
+        }
         if should_track {
             // let flow = Flow::new(FlowContent::RequestResponse(HTTPPair { request: RequestOrResponse::Request(req.), response: None })
-            let (req_intermediate, duplicated_request) = crate::resource::RequestOrResponse::copy_request(req).await;
+            let limits = self.proxy_ref.config.borrow().body_storage.clone();
+            let (mut req_intermediate, mut duplicated_request, pending_body) = crate::resource::RequestOrResponse::copy_request(req, &limits).await;
+            let flow_id = Flow::generate_id();
+            self.flow_id = Some(flow_id.clone());
+
+            if let Some(pending_body) = pending_body {
+                // too big or too slow to hold for plugins or intercepting, record it on the way through
+                streamed_warning(&req_intermediate, req_intermediate.meta.unwrap_request_ref(), pending_body.reason);
+                req_intermediate.body_streamed = true;
+                let flow = self.new_flow(flow_id, FlowContent::RequestResponse(HTTPPair::new_request(req_intermediate)), ctx.client_addr, timings);
+                self.flow_storage.write().unwrap().add_flow(flow);
+                self.update_timings(|timings| timings.forwarded = timings.now());
+                *duplicated_request.body_mut() = self.record_streamed_body(pending_body, false, ctx.client_addr);
+                return duplicated_request.into();
+            }
+
+            // run plugins
+            let plugins = self.active_plugins();
+            let plugin_action = run_message_hooks(&plugins, &self.plugin_context(ctx.client_addr), &mut req_intermediate).await;
+            if let PluginAction::Modified = plugin_action {
+                duplicated_request = rebuild_request(&req_intermediate, duplicated_request);
+            }
+
+            let should_intercept = self.in_scope
+                && matches!(plugin_action, PluginAction::Continue | PluginAction::Modified)
+                && self.proxy_ref.config.borrow().intercept.should_intercept_request(req_intermediate.meta.unwrap_request_ref());
+            let held_request = if should_intercept { Some(req_intermediate.clone()) } else { None };
 
-            let flow = Flow::new(FlowContent::RequestResponse(HTTPPair::new_request(req_intermediate)));
-            self.flow_id = Some(flow.get_id());
+            let is_websocket = is_websocket_upgrade(&req_intermediate.headers);
+            let mut http_pair = HTTPPair::new_request(req_intermediate);
+            let early_response = match plugin_action {
+                PluginAction::Respond(response) => {
+                    let early_response = response.to_response().unwrap_or_else(|e| {
+                        warn!("plugin produced an invalid response: {}", e);
+                        dropped_response()
+                    });
+                    http_pair.add_response(*response);
+                    Some(early_response)
+                },
+                PluginAction::Drop => Some(dropped_response()),
+                _ => None
+            };
+
+            let flow_content = if is_websocket {
+                FlowContent::WebSocket(WebSocketFlow::new(http_pair))
+            } else {
+                FlowContent::RequestResponse(http_pair)
+            };
+            timings.request_received = timings.now();
+            let flow = self.new_flow(flow_id, flow_content, ctx.client_addr, timings);
             self.flow_storage.write().unwrap().add_flow(flow);
 
-            return duplicated_request.into();
+            if let Some(early_response) = early_response {
+                self.complete_flow(ctx.client_addr);
+                return early_response.into();
+            }
+
+            if let Some(held_request) = held_request {
+                return match self.hold_request(held_request, duplicated_request).await {
+                    Some(edited_request) => match is_websocket {
+                        true => self.upgrade_websocket(ctx.client_addr, edited_request).await.into(),
+                        false => self.forward_request(edited_request).into()
+                    },
+                    None => {
+                        self.complete_flow(ctx.client_addr);
+                        dropped_response().into()
+                    }
+                };
+            }
+
+            if is_websocket {
+                return self.upgrade_websocket(ctx.client_addr, duplicated_request).await.into();
+            }
+            return self.forward_request(duplicated_request).into();
+        }
+        // out of scope, nothing to attach a response to
+        self.flow_id = None;
+        if is_websocket_upgrade(req.headers()) {
+            return self.upgrade_websocket(ctx.client_addr, req).await.into();
         }
         req.into()
     }
 
-    async fn handle_response(&mut self, _ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
-
-        // run plugins
+    // untracked passthrough tunnels end up here, hudsucker relays those itself
+    async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+        !self.is_tls_passthrough(req.uri())
+    }
 
+    async fn handle_error(&mut self, ctx: &HttpContext, err: hudsucker::hyper_util::client::legacy::Error) -> Response<Body> {
+        // hyper's own message is just "client error (Connect)", the useful part like a bad certificate is further down
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            message.push_str(&format!(": {}", cause));
+            source = cause.source();
+        }
+        warn!("failed to forward request: {}", message);
+        self.unlink_websocket();
         if let Some(flow_id) = &self.flow_id {
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id) {
+                flow.connection.error = Some(message.clone());
+            }
+            self.complete_flow(ctx.client_addr);
+        }
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(format!("Telescope failed to reach the upstream server: {}", message)))
+            .expect("Failed to build response")
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        match res.status() {
+            StatusCode::SWITCHING_PROTOCOLS => self.websocket_link = None,
+            _ => self.unlink_websocket()
+        }
+        if let Some(flow_id) = self.flow_id.clone() {
             // we are tracking this flow
-            let (res_intermediate, duplicated_response) = crate::resource::RequestOrResponse::copy_response(res).await;
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(&flow_id) {
+                flow.timings.first_byte = flow.timings.now();
+                if let Some(connect) = res.extensions().get::<ConnectTimings>() {
+                    flow.timings.record_connect(connect);
+                }
+                if flow.timings.request_sent.is_none() {
+                    flow.timings.request_sent = flow.timings.connection_ready();
+                }
+                if let Some(tls) = res.extensions().get::<Arc<TlsInfo>>() {
+                    flow.connection.tls = Some(TlsInfo::clone(tls));
+                }
+            }
+            let limits = self.proxy_ref.config.borrow().body_storage.clone();
+            let (mut res_intermediate, mut duplicated_response, pending_body) = crate::resource::RequestOrResponse::copy_response(res, &limits).await;
+            if pending_body.is_none() {
+                self.update_timings(|timings| timings.response_complete = timings.now());
+            }
+
+            if let Some(pending_body) = pending_body {
+                // sse and other streams, the flow stays active until the body ends
+                self.update_flow_body(true, |http_pair| {
+                    streamed_warning(&res_intermediate, http_pair.request.meta.unwrap_request_ref(), pending_body.reason);
+                    res_intermediate.body_streamed = true;
+                    http_pair.add_response(res_intermediate);
+                });
+                *duplicated_response.body_mut() = self.record_streamed_body(pending_body, true, ctx.client_addr);
+                return duplicated_response;
+            }
+
+            // run plugins
+            let plugins = self.active_plugins();
+            match run_message_hooks(&plugins, &self.plugin_context(ctx.client_addr), &mut res_intermediate).await {
+                PluginAction::Continue => {},
+                PluginAction::Modified => {
+                    duplicated_response = rebuild_response(&res_intermediate, duplicated_response);
+                },
+                PluginAction::Respond(response) => {
+                    duplicated_response = rebuild_response(&response, duplicated_response);
+                    res_intermediate = *response;
+                },
+                PluginAction::Drop => {
+                    self.complete_flow(ctx.client_addr);
+                    return dropped_response();
+                }
+            }
+
+            let mut held_response = None;
             // record into flow
-            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_mut(flow_id)  {
-                match flow.content {
-                    FlowContent::RequestResponse(ref mut http_pair) => {
-                        http_pair.add_response(res_intermediate);
-                    },
+            if let Some(flow) = self.flow_storage.write().unwrap().get_flow_body_mut(&flow_id, true)  {
+                let http_pair = flow.content.http_pair_mut();
+                if self.in_scope && self.proxy_ref.config.borrow().intercept.should_intercept_response(http_pair.request.meta.unwrap_request_ref()) {
+                    held_response = Some(res_intermediate.clone());
                 }
+                http_pair.add_response(res_intermediate);
             } else {
                 warn!("flow id {} deleted, response not recorded", flow_id);
             }
+            if let Some(held_response) = held_response {
+                duplicated_response = match self.hold_response(held_response, duplicated_response).await {
+                    Some(edited_response) => edited_response,
+                    None => dropped_response()
+                };
+            }
+            self.complete_flow(ctx.client_addr);
             return duplicated_response;
         }
         res
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use hudsucker::tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+    use super::*;
+    use crate::certs::CertDerivable;
+
+    async fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port()
+    }
+
+    async fn start_proxy(name: &str, configure: impl FnOnce(&mut Config)) -> (u16, Arc<RwLock<FlowStorage>>) {
+        let (port, proxy) = start_proxy_ref(name, configure).await;
+        let storage = proxy.proxy.read().unwrap().storage.clone();
+        (port, storage)
+    }
+
+    async fn start_proxy_ref(name: &str, configure: impl FnOnce(&mut Config)) -> (u16, TelescopeProxyRef) {
+        let data_dir = std::env::temp_dir().join(format!("telescope-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let port = free_port().await;
+        let mut config = Config {
+            data_dir,
+            addr: ([127, 0, 0, 1], port).into(),
+            ..Default::default()
+        };
+        config.project.enabled = false;
+        configure(&mut config);
+        config.derive_cert().unwrap();
+        let (_, config) = tokio::sync::watch::channel(config);
+        let proxy = TelescopeProxyRef::wrap(TelescopeProxy::new(config));
+        let started = proxy.clone();
+        tokio::spawn(async move { started.start().await });
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        (port, proxy)
+    }
+
+    // plain ws through a CONNECT tunnel, the proxy serves http on it once it sees it isn't tls
+    async fn tunnel(proxy_port: u16, target: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream.write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        stream
+    }
+
+    fn websocket_flows(storage: &RwLock<FlowStorage>) -> Vec<Flow> {
+        storage.read().unwrap().iter_flow_timeline().filter(|flow| matches!(flow.content, FlowContent::WebSocket(_))).cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_are_connected_and_recorded() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = websocket.next().await {
+                if message.is_text() {
+                    websocket.send(Message::text(format!("echo {}", message.to_text().unwrap()))).await.unwrap();
+                }
+            }
+        });
+        let (proxy_port, storage) = start_proxy("websocket", |_| {}).await;
+
+        let stream = tunnel(proxy_port, &server_addr.to_string()).await;
+        let request = format!("ws://{}/chat", server_addr).into_client_request().unwrap();
+        let (mut websocket, response) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        websocket.send(Message::text("hi")).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), Message::text("echo hi"));
+        websocket.close(None).await.unwrap();
+        while websocket.next().await.is_some() {}
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let flows = websocket_flows(&storage);
+        assert_eq!(flows.len(), 1);
+        let FlowContent::WebSocket(recorded) = &flows[0].content else {
+            unreachable!();
+        };
+        assert_eq!(recorded.handshake.response.as_ref().unwrap().meta.unwrap_response_ref().status, 101);
+        assert_eq!(recorded.messages[0].payload, b"hi");
+        assert_eq!(recorded.messages[1].payload, b"echo hi");
+        assert!(!flows[0].is_active);
+    }
+
+    #[tokio::test]
+    async fn failed_websocket_upgrades_reach_the_client_and_the_flow() {
+        let (proxy_port, storage) = start_proxy("websocket-refused", |_| {}).await;
+        let closed_port = free_port().await;
+
+        let stream = tunnel(proxy_port, &format!("127.0.0.1:{}", closed_port)).await;
+        let request = format!("ws://127.0.0.1:{}/", closed_port).into_client_request().unwrap();
+        match tokio_tungstenite::client_async(request, stream).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::BAD_GATEWAY),
+            other => panic!("expected a 502, got {:?}", other.map(|(_, response)| response))
+        }
+
+        let flows = websocket_flows(&storage);
+        assert_eq!(flows.len(), 1);
+        assert!(flows[0].connection.error.is_some());
+        assert!(!flows[0].is_active);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_go_through_the_upstream_proxy() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = websocket.next().await {
+                if message.is_text() {
+                    websocket.send(message).await.unwrap();
+                }
+            }
+        });
+        // answers one CONNECT and relays it, handing back what was asked for
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (connect_send, connect_recv) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut client, _) = upstream.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split_whitespace().nth(1).unwrap().to_string();
+            let mut server = TcpStream::connect(&target).await.unwrap();
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+            let _ = connect_send.send(request);
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+        });
+        let (proxy_port, _) = start_proxy("websocket-upstream", |config| {
+            config.upstream.enabled = true;
+            config.upstream.proxy = Some(crate::config::UpstreamProxy {
+                kind: crate::config::UpstreamProxyKind::Http,
+                address: upstream_addr.to_string(),
+                ..Default::default()
+            });
+        }).await;
+
+        let stream = tunnel(proxy_port, &server_addr.to_string()).await;
+        let request = format!("ws://{}/", server_addr).into_client_request().unwrap();
+        let (mut websocket, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        websocket.send(Message::text("through")).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), Message::text("through"));
+        assert!(connect_recv.await.unwrap().starts_with(&format!("CONNECT {} ", server_addr)));
+    }
+
+    struct DropEverything;
+
+    #[async_trait::async_trait]
+    impl Plugin for DropEverything {
+        fn name(&self) -> &str {
+            "drop everything"
+        }
+
+        async fn on_request(&self, _ctx: &PluginContext, _request: &mut crate::resource::RequestOrResponse) -> PluginAction {
+            PluginAction::Drop
+        }
+
+        async fn on_response(&self, _ctx: &PluginContext, _response: &mut crate::resource::RequestOrResponse) -> PluginAction {
+            PluginAction::Drop
+        }
+    }
+
+    #[tokio::test]
+    async fn hidden_flows_skip_plugins_and_intercept() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+        });
+        let (proxy_port, proxy) = start_proxy_ref("hidden", |config| {
+            config.scope = crate::config::ScopeConfig {
+                enabled: true,
+                include: vec![crate::config::ScopeRule { host: "example.com".to_string(), ..Default::default() }],
+                out_of_scope: crate::config::OutOfScopeAction::Hide,
+                ..Default::default()
+            };
+            config.intercept.intercept_requests = true;
+            config.intercept.intercept_responses = true;
+        }).await;
+        let storage = {
+            let proxy = proxy.proxy.read().unwrap();
+            proxy.plugins.write().unwrap().register(Arc::new(DropEverything));
+            proxy.storage.clone()
+        };
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream.write_all(format!("GET http://{server_addr}/ HTTP/1.1\r\nHost: {server_addr}\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ok"));
+
+        let flows: Vec<Flow> = storage.read().unwrap().iter_flow_timeline().cloned().collect();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].content.http_pair().response.as_ref().unwrap().meta.unwrap_response_ref().status, 200);
+        assert!(proxy.proxy.read().unwrap().intercept_queue.read().unwrap().is_empty());
+    }
+}
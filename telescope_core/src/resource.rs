@@ -1,12 +1,11 @@
-use std::{io::Cursor, time::{Instant, SystemTime, UNIX_EPOCH}};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use http_body_util::{BodyExt, BodyStream, Collected};
-use hudsucker::{rustls::version, tokio_tungstenite::tungstenite::http::request};
+use hudsucker::tokio_tungstenite::tungstenite::{protocol::{frame::coding::{CloseCode, Control, Data, OpCode}, CloseFrame}, Message};
 use hyper::HeaderMap;
 use log::warn;
 use serde::{de, Deserialize, Serialize, Serializer};
 
-use crate::config::Config;
+use crate::{body::{capture_body, CapturedBody, PendingBody}, config::{BodyStorageConfig, Config}, connection::ConnectionInfo, database::{read_body, read_websocket_messages}, timing::FlowTimings};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MemoryResource {
@@ -53,29 +52,92 @@ impl StringResource {
     }
 }
 
+// a body that lives in the project database, only read when something looks at it
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DatabaseResource {
+    pub path: String,
+    pub flow_id: String,
+    pub side: String,
+    pub size: u64
+}
+
+// websocket messages of a flow read back from the project, they stay in the database until the flow gets looked at
+#[derive(Debug, Clone)]
+pub struct DatabaseMessages {
+    pub path: String,
+    pub flow_id: String,
+    pub count: usize,
+    // something is already reading them
+    pub loading: bool
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Resource {
     Memory(MemoryResource),
     File(FileResource),
-    String(StringResource)
+    String(StringResource),
+    Database(DatabaseResource)
 }
 
 impl Resource {
-    pub fn as_string(&self) -> String {
+    // bodies that aren't valid utf-8 come back lossy instead of failing
+    pub fn as_string(&self) -> std::io::Result<String> {
         match self {
-            Resource::Memory(m) => String::from_utf8(m.buffer.clone()).unwrap(),
-            Resource::File(f) => std::fs::read_to_string(f.path.clone()).expect("File resource read failed"),
-            Resource::String(s) => s.string.clone()
+            Resource::String(s) => Ok(s.string.clone()),
+            _ => Ok(String::from_utf8_lossy(&self.as_bytes()?).to_string())
+        }
+    }
+
+    // file and database resources can disappear under us, so reading them can fail
+    pub fn as_bytes(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Resource::Memory(m) => Ok(m.buffer.clone()),
+            Resource::File(f) => std::fs::read(&f.path)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("failed to read {}: {}", f.path, e))),
+            Resource::String(s) => Ok(s.string.clone().into_bytes()),
+            Resource::Database(d) => read_body(d, None)
         }
     }
 
     pub fn empty() -> Self {
         Self::Memory(MemoryResource::new(Vec::new()))
     }
+
+    // without reading a file resource into memory
+    pub fn size(&self) -> u64 {
+        match self {
+            Resource::Memory(m) => m.buffer.len() as u64,
+            Resource::File(f) => std::fs::metadata(&f.path).map(|metadata| metadata.len()).unwrap_or(0),
+            Resource::String(s) => s.string.len() as u64,
+            Resource::Database(d) => d.size
+        }
+    }
+
+    // at most max bytes from the start, for previews of bodies that may live on disk
+    pub fn prefix(&self, max: usize) -> Vec<u8> {
+        match self {
+            Resource::Memory(m) => m.buffer[..m.buffer.len().min(max)].to_vec(),
+            Resource::File(f) => {
+                let mut buffer = Vec::new();
+                if let Ok(file) = std::fs::File::open(&f.path) {
+                    let _ = std::io::Read::read_to_end(&mut std::io::Read::take(file, max as u64), &mut buffer);
+                }
+                buffer
+            },
+            Resource::String(s) => s.string.as_bytes()[..s.string.len().min(max)].to_vec(),
+            Resource::Database(d) => read_body(d, Some(max)).unwrap_or_default()
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, Resource::File(_))
+    }
 }
 
 pub trait ResolveString {
     fn resolve_string(&self, resource: &Resource) -> String;
+    // like resolve_string but for things read while proxying, where a missing file shouldn't panic
+    fn try_resolve_bytes(&self, resource: &Resource) -> std::io::Result<Vec<u8>>;
 }
 
 impl ResolveString for Config {
@@ -93,6 +155,18 @@ impl ResolveString for Config {
             Resource::String(string_resource) => {
                 return string_resource.string.clone();
             },
+            Resource::Database(_) => resource.as_string().unwrap_or_else(|e| {
+                warn!("{}", e);
+                String::new()
+            })
+        }
+    }
+
+    fn try_resolve_bytes(&self, resource: &Resource) -> std::io::Result<Vec<u8>> {
+        match resource {
+            Resource::File(file_resource) => std::fs::read(self.data_dir.join(&file_resource.path))
+                .map_err(|e| std::io::Error::new(e.kind(), format!("failed to read {}: {}", file_resource.path, e))),
+            _ => resource.as_bytes()
         }
     }
 }
@@ -115,6 +189,28 @@ where
     reqwest::Url::parse(url_str).map_err(de::Error::custom)
 }
 
+// header values that aren't utf8 get mangled, they're rare enough and headers_to_string does the same
+fn headers_serialize<S>(headers: &HeaderMap, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.collect_seq(headers.iter().map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes()))))
+}
+
+fn headers_deserialize<'de, D>(deserializer: D) -> Result<HeaderMap, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let pairs: Vec<(String, String)> = de::Deserialize::deserialize(deserializer)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        let name = hyper::header::HeaderName::from_bytes(name.as_bytes()).map_err(de::Error::custom)?;
+        let value = hyper::header::HeaderValue::from_str(&value).map_err(de::Error::custom)?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
 pub fn get_current_time() -> u128 {
     // TODO: fix non-monotonic func use?
     SystemTime::now().duration_since(UNIX_EPOCH).expect("time keeping failure").as_millis()
@@ -131,18 +227,33 @@ pub struct RequestMeta {
 
 impl RequestMeta {
     pub fn new(url: &str, method: &str, version: &str) -> Self {
-        Self {
-            url: reqwest::Url::parse(url).unwrap(), // TODO: error handling
+        Self::new_checked(url, method, version).unwrap() // TODO: error handling
+    }
+
+    // for urls that come from files rather than from hyper
+    pub fn new_checked(url: &str, method: &str, version: &str) -> Result<Self, String> {
+        // CONNECT to an ip like 127.0.0.1:443 isn't a valid url on its own
+        let url = reqwest::Url::parse(url).or_else(|e| match method {
+            "CONNECT" => reqwest::Url::parse(&format!("https://{}", url)),
+            _ => Err(e)
+        }).map_err(|e| format!("invalid url {}: {}", url, e))?;
+        Ok(Self {
+            url,
             method: String::from(method),
             version: String::from(version),
             created_at: get_current_time()
-        }
+        })
     }
 
     pub fn url_str(&self) -> &str {
         self.url.as_str()
     }
 
+    pub fn set_url(&mut self, url: &str) -> Result<(), String> {
+        self.url = reqwest::Url::parse(url).map_err(|e| format!("invalid url: {}", e))?;
+        Ok(())
+    }
+
     pub fn is_proxy_client_connection(&self) -> bool {
         self.method == "CONNECT"
     }
@@ -179,12 +290,26 @@ impl RequestOrResponseMeta {
         }
     }
 
+    pub fn unwrap_request_mut(&mut self) -> &mut RequestMeta {
+        match self {
+            RequestOrResponseMeta::Request(request_meta) => request_meta,
+            RequestOrResponseMeta::Response(_) => panic!("ResponseMeta cannot be unwrapped as RequestMeta")
+        }
+    }
+
     pub fn unwrap_response_ref(&self) -> &ResponseMeta {
         match self {
             RequestOrResponseMeta::Request(request_meta) => panic!("RequestMeta cannot be unwrapped as ResponseMeta"),
             RequestOrResponseMeta::Response(response_meta) => response_meta
         }
     }
+
+    pub fn unwrap_response_mut(&mut self) -> &mut ResponseMeta {
+        match self {
+            RequestOrResponseMeta::Request(_) => panic!("RequestMeta cannot be unwrapped as ResponseMeta"),
+            RequestOrResponseMeta::Response(response_meta) => response_meta
+        }
+    }
 }
 
 impl Into<RequestMeta> for RequestOrResponseMeta {
@@ -205,12 +330,18 @@ impl Into<ResponseMeta> for RequestOrResponseMeta {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestOrResponse {
     pub body: Resource,
+    #[serde(serialize_with = "headers_serialize", deserialize_with = "headers_deserialize")]
     pub headers: HeaderMap,
     pub is_response: bool,
-    pub meta: RequestOrResponseMeta
+    pub meta: RequestOrResponseMeta,
+    // only a prefix of the body was kept
+    pub body_truncated: bool,
+    // forwarded as it arrived, plugins and intercept never saw it
+    #[serde(default)]
+    pub body_streamed: bool
     // pub reply: Option<Box<RequestOrResponse>>
 }
 
@@ -221,7 +352,9 @@ impl RequestOrResponse {
             body,
             headers,
             is_response: false,
-            meta: RequestOrResponseMeta::Request(meta)
+            meta: RequestOrResponseMeta::Request(meta),
+            body_truncated: false,
+            body_streamed: false
         }
     }
 
@@ -230,7 +363,9 @@ impl RequestOrResponse {
             body,
             headers,
             is_response: true,
-            meta: RequestOrResponseMeta::Response(meta)
+            meta: RequestOrResponseMeta::Response(meta),
+            body_truncated: false,
+            body_streamed: false
         }
     }
 }
@@ -255,7 +390,93 @@ pub fn version_to_string(version: hyper::Version) -> String {
     }
 }
 
+pub fn string_to_version(version: &str) -> hyper::Version {
+    match version {
+        "HTTP/0.9" => hyper::Version::HTTP_09,
+        "HTTP/1.0" => hyper::Version::HTTP_10,
+        "HTTP/1.1" => hyper::Version::HTTP_11,
+        "HTTP/2" | "HTTP/2.0" => hyper::Version::HTTP_2,
+        "HTTP/3" | "HTTP/3.0" => hyper::Version::HTTP_3,
+        _ => {
+            warn!("Unsupported HTTP version string {}", version);
+            hyper::Version::HTTP_11
+        }
+    }
+}
+
+// "Name: value" per line, the same shape as on the wire
+pub fn headers_to_string(headers: &HeaderMap) -> String {
+    let mut out = String::new();
+    for (name, value) in headers.iter() {
+        out.push_str(name.as_str());
+        out.push_str(": ");
+        out.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        out.push('\n');
+    }
+    out
+}
+
+// same check hudsucker makes before it takes over the connection
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let header_has = |name: hyper::header::HeaderName, token: &str| headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)));
+    header_has(hyper::header::CONNECTION, "upgrade") && header_has(hyper::header::UPGRADE, "websocket")
+}
+
+pub fn parse_headers(text: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(format!("line {}: missing ':' in header", line_number + 1));
+        };
+        let name = hyper::header::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| format!("line {}: invalid header name: {}", line_number + 1, e))?;
+        let value = hyper::header::HeaderValue::from_str(value.trim())
+            .map_err(|e| format!("line {}: invalid header value: {}", line_number + 1, e))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
 impl RequestOrResponse {
+    // the body we send is always fully buffered so the framing headers have to describe it exactly
+    pub fn fix_body_framing(&mut self) {
+        let body_len = self.body.size();
+        self.headers.remove(hyper::header::TRANSFER_ENCODING);
+        if body_len > 0 || self.headers.contains_key(hyper::header::CONTENT_LENGTH) {
+            self.headers.insert(hyper::header::CONTENT_LENGTH, hyper::header::HeaderValue::from(body_len));
+        }
+    }
+
+    // rebuild a hyper request from a (possibly edited) recorded request
+    pub fn to_request(&self) -> Result<hyper::Request<hudsucker::Body>, String> {
+        let meta = self.meta.unwrap_request_ref();
+        let mut request = hyper::Request::builder()
+            .method(meta.method.as_str())
+            .uri(meta.url.as_str())
+            .version(string_to_version(&meta.version))
+            .body(hudsucker::Body::from(http_body_util::Full::new(hyper::body::Bytes::from(self.body.as_bytes().map_err(|e| e.to_string())?))))
+            .map_err(|e| e.to_string())?;
+        *request.headers_mut() = self.headers.clone();
+        Ok(request)
+    }
+
+    pub fn to_response(&self) -> Result<hyper::Response<hudsucker::Body>, String> {
+        let meta = self.meta.unwrap_response_ref();
+        let mut response = hyper::Response::builder()
+            .status(meta.status as u16)
+            .version(string_to_version(&meta.version))
+            .body(hudsucker::Body::from(http_body_util::Full::new(hyper::body::Bytes::from(self.body.as_bytes().map_err(|e| e.to_string())?))))
+            .map_err(|e| e.to_string())?;
+        *response.headers_mut() = self.headers.clone();
+        Ok(response)
+    }
+
     /*pub fn has_reply(&self) -> bool {
         self.reply.is_some()
     }*/
@@ -263,42 +484,49 @@ impl RequestOrResponse {
     // code from https://github.com/sinKettu/cruster/blob/0238047e713624b17942ad18fb9a9a9d136ab8f2/src/cruster_proxy/request_response.rs#L84
     // note hyper reexports a lot of http stuff so the types are essentially the same
     // TODO: maybe better to use hyper only version to prevent version conflicts
-    pub async fn copy_request(request: hyper::Request<hudsucker::Body>) -> (RequestOrResponse, hyper::Request<hudsucker::Body>) {
+    // bodies over the spill threshold or that look like streams come back as a PendingBody, the copy then has an empty body until the caller tees the rest through
+    pub async fn copy_request(request: hyper::Request<hudsucker::Body>, limits: &BodyStorageConfig) -> (RequestOrResponse, hyper::Request<hudsucker::Body>, Option<PendingBody>) {
         let (parts, body) = request.into_parts();
         let url = parts.uri.clone().to_string();
         let method = parts.method.clone().to_string();
         let headers = parts.headers.clone();
         let version_str = version_to_string(parts.version);
 
-        let body_collected =  body.collect().await.unwrap_or_default();
-        let body_bytes = body_collected.to_bytes();
-        // let cursor = Cursor::new(body_bytes.clone().to_vec());
-        let body_cloned: hudsucker::Body = hudsucker::Body::from(http_body_util::Full::new(body_bytes.clone()));
+        let (body_saved, body_cloned, pending_body) = match capture_body(body, &parts.headers, limits).await {
+            CapturedBody::Complete(body_bytes) => {
+                // let cursor = Cursor::new(body_bytes.clone().to_vec());
+                (Resource::Memory(MemoryResource::new(body_bytes.to_vec())), hudsucker::Body::from(http_body_util::Full::new(body_bytes)), None)
+            },
+            CapturedBody::Pending(pending_body) => (Resource::empty(), hudsucker::Body::empty(), Some(pending_body))
+        };
 
         let duplicated_request = hyper::Request::from_parts(parts, body_cloned);
-        let request_to_save = RequestOrResponse::new_request(Resource::Memory(MemoryResource::new(body_bytes.to_vec())), headers, RequestMeta::new(&url, &method, &version_str));
+        let request_to_save = RequestOrResponse::new_request(body_saved, headers, RequestMeta::new(&url, &method, &version_str));
 
-        (request_to_save, duplicated_request)
+        (request_to_save, duplicated_request, pending_body)
     }
 
-    pub async fn copy_response(response: hyper::Response<hudsucker::Body>) -> (RequestOrResponse, hyper::Response<hudsucker::Body>) {
+    pub async fn copy_response(response: hyper::Response<hudsucker::Body>, limits: &BodyStorageConfig) -> (RequestOrResponse, hyper::Response<hudsucker::Body>, Option<PendingBody>) {
         let (parts, body) = response.into_parts();
-        let body_collected = body.collect().await.unwrap_or_default();
-        let body_bytes = body_collected.to_bytes();
-        let body_cloned: hudsucker::Body = hudsucker::Body::from(http_body_util::Full::new(body_bytes.clone()));
+        let (body_saved, body_cloned, pending_body) = match capture_body(body, &parts.headers, limits).await {
+            CapturedBody::Complete(body_bytes) => {
+                (Resource::Memory(MemoryResource::new(body_bytes.to_vec())), hudsucker::Body::from(http_body_util::Full::new(body_bytes)), None)
+            },
+            CapturedBody::Pending(pending_body) => (Resource::empty(), hudsucker::Body::empty(), Some(pending_body))
+        };
         let status = parts.status.as_u16() as u32;
         let version_str = version_to_string(parts.version.clone());
         let headers = parts.headers.clone();
 
         let duplicated_response = hyper::Response::from_parts(parts, body_cloned);
-        let response_to_save = RequestOrResponse::new_response(Resource::Memory(MemoryResource::new(body_bytes.to_vec())), headers, ResponseMeta::new(status, &version_str));
+        let response_to_save = RequestOrResponse::new_response(body_saved, headers, ResponseMeta::new(status, &version_str));
 
-        (response_to_save, duplicated_response)
+        (response_to_save, duplicated_response, pending_body)
     }
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HTTPPair {
     pub request: RequestOrResponse,
     pub response: Option<RequestOrResponse>,
@@ -320,38 +548,315 @@ impl HTTPPair {
         self.response = Some(response);
     }
 
+    // wall clock and only to the millisecond, Flow::timings has the monotonic breakdown
     pub fn get_time_taken(&self) -> Option<u128> {
         if let Some(response) = &self.response {
-            Some(response.meta.unwrap_response_ref().created_at - self.request.meta.unwrap_request_ref().created_at)
+            Some(response.meta.unwrap_response_ref().created_at.saturating_sub(self.request.meta.unwrap_request_ref().created_at))
         } else {
             None
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WebSocketDirection {
+    ClientToServer,
+    ServerToClient
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebSocketOpcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong
+}
+
+impl WebSocketOpcode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebSocketOpcode::Continuation => "Continuation",
+            WebSocketOpcode::Text => "Text",
+            WebSocketOpcode::Binary => "Binary",
+            WebSocketOpcode::Close => "Close",
+            WebSocketOpcode::Ping => "Ping",
+            WebSocketOpcode::Pong => "Pong"
+        }
+    }
+
+    // the opcode as it is on the wire, for formats that store it as a number
+    pub fn code(&self) -> u8 {
+        match self {
+            WebSocketOpcode::Continuation => 0x0,
+            WebSocketOpcode::Text => 0x1,
+            WebSocketOpcode::Binary => 0x2,
+            WebSocketOpcode::Close => 0x8,
+            WebSocketOpcode::Ping => 0x9,
+            WebSocketOpcode::Pong => 0xa
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x0 => Some(WebSocketOpcode::Continuation),
+            0x1 => Some(WebSocketOpcode::Text),
+            0x2 => Some(WebSocketOpcode::Binary),
+            0x8 => Some(WebSocketOpcode::Close),
+            0x9 => Some(WebSocketOpcode::Ping),
+            0xa => Some(WebSocketOpcode::Pong),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketMessage {
+    pub direction: WebSocketDirection,
+    pub opcode: WebSocketOpcode,
+    pub timestamp: u128,
+    // close frames keep the wire layout, 2 byte big endian code then the reason
+    pub payload: Vec<u8>,
+    // sent by us rather than relayed
+    #[serde(default)]
+    pub injected: bool,
+    // held back, the other side never saw it
+    #[serde(default)]
+    pub dropped: bool
+}
+
+impl WebSocketMessage {
+    pub fn new(direction: WebSocketDirection, opcode: WebSocketOpcode, payload: Vec<u8>) -> Self {
+        Self {
+            direction,
+            opcode,
+            timestamp: get_current_time(),
+            payload,
+            injected: false,
+            dropped: false
+        }
+    }
+
+    pub fn from_message(direction: WebSocketDirection, message: &Message) -> Self {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (WebSocketOpcode::Text, text.as_bytes().to_vec()),
+            Message::Binary(data) => (WebSocketOpcode::Binary, data.clone()),
+            Message::Ping(data) => (WebSocketOpcode::Ping, data.clone()),
+            Message::Pong(data) => (WebSocketOpcode::Pong, data.clone()),
+            Message::Close(frame) => {
+                let payload = match frame {
+                    Some(frame) => {
+                        let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+                        payload.extend_from_slice(frame.reason.as_bytes());
+                        payload
+                    },
+                    None => Vec::new()
+                };
+                (WebSocketOpcode::Close, payload)
+            },
+            Message::Frame(frame) => {
+                let opcode = match frame.header().opcode {
+                    OpCode::Data(Data::Continue) => WebSocketOpcode::Continuation,
+                    OpCode::Data(Data::Text) => WebSocketOpcode::Text,
+                    OpCode::Data(Data::Binary) | OpCode::Data(Data::Reserved(_)) => WebSocketOpcode::Binary,
+                    OpCode::Control(Control::Close) => WebSocketOpcode::Close,
+                    OpCode::Control(Control::Ping) => WebSocketOpcode::Ping,
+                    OpCode::Control(Control::Pong) | OpCode::Control(Control::Reserved(_)) => WebSocketOpcode::Pong
+                };
+                (opcode, frame.payload().clone())
+            }
+        };
+        Self::new(direction, opcode, payload)
+    }
+
+    pub fn to_message(&self) -> Message {
+        match self.opcode {
+            WebSocketOpcode::Text => Message::Text(String::from_utf8_lossy(&self.payload).to_string()),
+            WebSocketOpcode::Binary | WebSocketOpcode::Continuation => Message::Binary(self.payload.clone()),
+            WebSocketOpcode::Ping => Message::Ping(self.payload.clone()),
+            WebSocketOpcode::Pong => Message::Pong(self.payload.clone()),
+            WebSocketOpcode::Close => {
+                if self.payload.len() < 2 {
+                    return Message::Close(None);
+                }
+                Message::Close(Some(CloseFrame {
+                    code: CloseCode::from(u16::from_be_bytes([self.payload[0], self.payload[1]])),
+                    reason: String::from_utf8_lossy(&self.payload[2..]).to_string().into()
+                }))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketFlow {
+    // the upgrade request, hudsucker answers it with its own 101 so there is rarely a response
+    pub handshake: HTTPPair,
+    pub messages: Vec<WebSocketMessage>,
+    pub closed_at: Option<u128>,
+    // set while the messages are still only in the project database
+    #[serde(skip)]
+    pub stored_messages: Option<DatabaseMessages>,
+}
+
+impl WebSocketFlow {
+    pub fn new(handshake: HTTPPair) -> Self {
+        Self {
+            handshake,
+            messages: Vec::new(),
+            closed_at: None,
+            stored_messages: None
+        }
+    }
+
+    pub fn add_message(&mut self, message: WebSocketMessage) {
+        self.messages.push(message);
+    }
+
+    // counts the messages that haven't been read back yet too
+    pub fn message_count(&self) -> usize {
+        match &self.stored_messages {
+            Some(stored) => stored.count,
+            None => self.messages.len()
+        }
+    }
+
+    // blocks on the database, for copies of a flow that are about to leave the app
+    pub fn load_messages(&mut self) {
+        let Some(stored) = &self.stored_messages else {
+            return;
+        };
+        match read_websocket_messages(stored) {
+            Ok(messages) => {
+                self.messages = messages;
+                self.stored_messages = None;
+            },
+            Err(e) => warn!("failed to read websocket messages of flow {}: {}", stored.flow_id, e)
+        }
+    }
+}
+
+// a CONNECT tunnel relayed as raw tcp, nothing inside it can be seen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelFlow {
+    pub connect: HTTPPair,
+    // host:port the tunnel goes to
+    pub target: String,
+    // client to server
+    pub bytes_sent: u64,
+    // server to client
+    pub bytes_received: u64,
+    pub closed_at: Option<u128>,
+    pub error: Option<String>,
+}
+
+impl TunnelFlow {
+    pub fn new(connect: HTTPPair, target: &str) -> Self {
+        Self {
+            connect,
+            target: target.to_string(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            closed_at: None,
+            error: None
+        }
+    }
+
+    pub fn opened_at(&self) -> u128 {
+        self.connect.request.meta.unwrap_request_ref().created_at
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FlowContent {
-    RequestResponse(HTTPPair)
-    // TODO: websocket?
+    RequestResponse(HTTPPair),
+    WebSocket(WebSocketFlow),
+    Tunnel(TunnelFlow)
 }
 
-#[derive(Debug, Clone)]
+impl FlowContent {
+    // the http exchange behind the flow, for websockets that's the upgrade handshake and for tunnels the CONNECT
+    pub fn http_pair(&self) -> &HTTPPair {
+        match self {
+            FlowContent::RequestResponse(http_pair) => http_pair,
+            FlowContent::WebSocket(websocket) => &websocket.handshake,
+            FlowContent::Tunnel(tunnel) => &tunnel.connect
+        }
+    }
+
+    pub fn http_pair_mut(&mut self) -> &mut HTTPPair {
+        match self {
+            FlowContent::RequestResponse(http_pair) => http_pair,
+            FlowContent::WebSocket(websocket) => &mut websocket.handshake,
+            FlowContent::Tunnel(tunnel) => &mut tunnel.connect
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Flow {
     pub id: String,
     pub content: FlowContent,
     pub is_active: bool,
+    pub connection: ConnectionInfo,
+    pub timings: FlowTimings,
 }
 
 impl Flow {
     pub fn new(content: FlowContent) -> Self {
+        Self::new_with_id(Self::generate_id(), content)
+    }
+
+    // for when the id has to be handed out before the flow exists
+    pub fn new_with_id(id: String, content: FlowContent) -> Self {
         Self {
-            id: nanoid::nanoid!(), // TODO: restrict charset for id
+            id,
             content,
-            is_active: true
+            is_active: true,
+            connection: ConnectionInfo::default(),
+            timings: FlowTimings::start()
         }
     }
 
+    pub fn generate_id() -> String {
+        nanoid::nanoid!() // TODO: restrict charset for id
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_body_is_an_error() {
+        let resource = Resource::File(FileResource::new("/nonexistent/telescope-body"));
+        assert!(resource.as_bytes().is_err());
+        assert!(resource.as_string().is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_body_reads_lossy() {
+        let resource = Resource::Memory(MemoryResource::new(vec![b'a', 0xff]));
+        assert_eq!(resource.as_string().unwrap(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn parse_headers_keeps_repeats_and_skips_blank_lines() {
+        let headers = parse_headers("Accept: */*\r\n\nX-Dup: a\nx-dup:  b \n").unwrap();
+        assert_eq!(headers.get("accept").unwrap(), "*/*");
+        let dups: Vec<_> = headers.get_all("x-dup").iter().collect();
+        assert_eq!(dups, ["a", "b"]);
+    }
+
+    #[test]
+    fn parse_headers_reports_the_bad_line() {
+        assert_eq!(parse_headers("A: 1\nno colon").unwrap_err(), "line 2: missing ':' in header");
+        assert!(parse_headers("bad name: 1").unwrap_err().starts_with("line 1: invalid header name"));
+        assert!(parse_headers("A: \u{7f}").unwrap_err().starts_with("line 1: invalid header value"));
+    }
+}
@@ -0,0 +1,93 @@
+// reverse proxy mode, clients talk to this listener like it's the server and everything goes to one upstream origin
+// requests are sent through the http listener so they're recorded and intercepted like any other flow
+// websocket upgrades aren't passed through yet
+use std::{net::SocketAddr, sync::Arc};
+
+use hudsucker::{certificate_authority::CertificateAuthority, hyper::{header::{HeaderValue, HOST}, http::uri::Authority, Uri}};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::LazyConfigAcceptor;
+
+use crate::{certs::build_authority, proxy::TelescopeProxyRef, tunnel::serve_via_http_listener};
+
+pub async fn serve(proxy_ref: TelescopeProxyRef, addr: SocketAddr) -> std::io::Result<()> {
+    let (tls, upstream) = {
+        let config = proxy_ref.config.borrow();
+        (config.reverse.tls, config.reverse.upstream.clone())
+    };
+    // only checked here so a bad origin shows up once instead of on every request
+    parse_origin(&upstream)?;
+    let ca = if tls {
+        let ca = build_authority(&proxy_ref.config.borrow())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("failed to load ca: {}", e)))?;
+        Some(Arc::new(ca))
+    } else {
+        None
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("reverse proxy listener on {} forwarding to {}", addr, upstream);
+    loop {
+        let (stream, client_addr) = listener.accept().await?;
+        let proxy_ref = proxy_ref.clone();
+        let ca = ca.clone();
+        tokio::spawn(async move {
+            let result = match ca {
+                Some(ca) => handle_tls_client(proxy_ref, stream, client_addr, addr, ca.as_ref()).await,
+                None => handle_client(proxy_ref, stream, client_addr).await
+            };
+            if let Err(e) = result {
+                warn!("reverse proxy client {}: {}", client_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_tls_client(proxy_ref: TelescopeProxyRef, stream: TcpStream, client_addr: SocketAddr, listen_addr: SocketAddr, ca: &impl CertificateAuthority) -> std::io::Result<()> {
+    let start = LazyConfigAcceptor::new(Default::default(), stream).await?;
+    // the certificate is for whatever name the client asked for, or the listen address without sni
+    let name = start.client_hello().server_name().map(|name| name.to_string())
+        .unwrap_or_else(|| listen_addr.ip().to_string());
+    let authority: Authority = name.parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("bad server name {}: {}", name, e)))?;
+    let mut server_config = (*ca.gen_server_config(&authority).await).clone();
+    // we only serve http/1 on this side
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let stream = start.into_stream(Arc::new(server_config)).await?;
+    handle_client(proxy_ref, stream, client_addr).await
+}
+
+async fn handle_client(proxy_ref: TelescopeProxyRef, stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static, client_addr: SocketAddr) -> std::io::Result<()> {
+    let (upstream, preserve_host) = {
+        let config = proxy_ref.config.borrow();
+        (config.reverse.upstream.clone(), config.reverse.preserve_host)
+    };
+    let origin = parse_origin(&upstream)?;
+    let base_path = origin.path().trim_end_matches('/').to_string();
+    serve_via_http_listener(&proxy_ref, stream, client_addr, move |req| {
+        let path_and_query = req.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+        let uri = Uri::builder()
+            .scheme(origin.scheme_str().unwrap_or("http"))
+            .authority(origin.authority().map_or("", |authority| authority.as_str()))
+            .path_and_query(format!("{}{}", base_path, path_and_query))
+            .build()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        *req.uri_mut() = uri;
+        if !preserve_host {
+            if let Some(authority) = origin.authority() {
+                let host = HeaderValue::from_str(authority.as_str()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+        Ok(())
+    }).await
+}
+
+fn parse_origin(upstream: &str) -> std::io::Result<Uri> {
+    let origin: Uri = upstream.parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad reverse proxy upstream {}: {}", upstream, e)))?;
+    match (origin.scheme_str(), origin.authority()) {
+        (Some("http" | "https"), Some(_)) => Ok(origin),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("reverse proxy upstream {} needs to be an http:// or https:// origin", upstream)))
+    }
+}
@@ -0,0 +1,393 @@
+use std::{path::PathBuf, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant, SystemTime}};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::{encoding::DecodedEdit, plugin::{list_plugin_files, Plugin, PluginAction, PluginContext}, resource::{MemoryResource, RequestOrResponse, RequestOrResponseMeta, Resource, ResponseMeta}};
+
+// user scripts live in <data_dir>/scripts/*.rhai and may define on_request(msg) and/or on_response(msg)
+//
+// fn on_request(msg) {
+//     if msg.host == "example.com" {
+//         msg.set_header("x-telescope", "1");
+//         let body = msg.body;
+//         body.replace("foo", "bar");
+//         msg.body = body;
+//     }
+// }
+
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+// keeps a runaway loop in a script from stalling the proxy
+const MAX_OPERATIONS: u64 = 5_000_000;
+
+struct ScriptMessageState {
+    message: RequestOrResponse,
+    modified: bool,
+    dropped: bool,
+    response: Option<RequestOrResponse>,
+}
+
+// handle passed into scripts, clones share the same message so edits are visible after the call
+#[derive(Clone)]
+pub struct ScriptMessage {
+    state: Arc<Mutex<ScriptMessageState>>,
+}
+
+fn script_error(message: String) -> Box<EvalAltResult> {
+    message.into()
+}
+
+impl ScriptMessage {
+    fn new(message: RequestOrResponse) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ScriptMessageState {
+                message,
+                modified: false,
+                dropped: false,
+                response: None
+            }))
+        }
+    }
+
+    fn with_message<T>(&mut self, read: impl FnOnce(&RequestOrResponse) -> T) -> T {
+        read(&self.state.lock().unwrap().message)
+    }
+
+    fn modify<T>(&mut self, write: impl FnOnce(&mut RequestOrResponse) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        state.modified = true;
+        write(&mut state.message)
+    }
+
+    fn is_response(&mut self) -> bool {
+        self.with_message(|message| message.is_response)
+    }
+
+    fn get_method(&mut self) -> String {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(request) => request.method.clone(),
+            RequestOrResponseMeta::Response(_) => String::new()
+        })
+    }
+
+    fn set_method(&mut self, method: String) -> Result<(), Box<EvalAltResult>> {
+        if self.is_response() {
+            return Err(script_error("responses have no method".to_string()));
+        }
+        self.modify(|message| message.meta.unwrap_request_mut().method = method);
+        Ok(())
+    }
+
+    fn get_url(&mut self) -> String {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(request) => request.url.to_string(),
+            RequestOrResponseMeta::Response(_) => String::new()
+        })
+    }
+
+    fn set_url(&mut self, url: String) -> Result<(), Box<EvalAltResult>> {
+        if self.is_response() {
+            return Err(script_error("responses have no url".to_string()));
+        }
+        self.modify(|message| message.meta.unwrap_request_mut().set_url(&url)).map_err(script_error)
+    }
+
+    fn get_host(&mut self) -> String {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(request) => request.url.host_str().unwrap_or("").to_string(),
+            RequestOrResponseMeta::Response(_) => String::new()
+        })
+    }
+
+    fn get_path(&mut self) -> String {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(request) => request.url.path().to_string(),
+            RequestOrResponseMeta::Response(_) => String::new()
+        })
+    }
+
+    fn get_status(&mut self) -> rhai::INT {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(_) => 0,
+            RequestOrResponseMeta::Response(response) => response.status as rhai::INT
+        })
+    }
+
+    fn set_status(&mut self, status: rhai::INT) -> Result<(), Box<EvalAltResult>> {
+        if !self.is_response() {
+            return Err(script_error("requests have no status".to_string()));
+        }
+        if !(100..=999).contains(&status) {
+            return Err(script_error(format!("invalid status code {}", status)));
+        }
+        self.modify(|message| message.meta.unwrap_response_mut().status = status as u32);
+        Ok(())
+    }
+
+    fn get_version(&mut self) -> String {
+        self.with_message(|message| match &message.meta {
+            RequestOrResponseMeta::Request(request) => request.version.clone(),
+            RequestOrResponseMeta::Response(response) => response.version.clone()
+        })
+    }
+
+    fn set_version(&mut self, version: String) {
+        self.modify(|message| match &mut message.meta {
+            RequestOrResponseMeta::Request(request) => request.version = version,
+            RequestOrResponseMeta::Response(response) => response.version = version
+        });
+    }
+
+    fn get_body(&mut self) -> Result<String, Box<EvalAltResult>> {
+        self.with_message(|message| message.body.as_string()).map_err(|e| script_error(e.to_string()))
+    }
+
+    fn set_body(&mut self, body: String) {
+        self.modify(|message| message.body = Resource::Memory(MemoryResource::new(body.into_bytes())));
+    }
+
+    fn get_body_bytes(&mut self) -> Result<Blob, Box<EvalAltResult>> {
+        self.with_message(|message| message.body.as_bytes()).map_err(|e| script_error(e.to_string()))
+    }
+
+    fn set_body_bytes(&mut self, body: Blob) {
+        self.modify(|message| message.body = Resource::Memory(MemoryResource::new(body)));
+    }
+
+    fn header(&mut self, name: &str) -> Dynamic {
+        self.with_message(|message| match message.headers.get(name) {
+            Some(value) => String::from_utf8_lossy(value.as_bytes()).to_string().into(),
+            None => Dynamic::UNIT
+        })
+    }
+
+    // repeated headers get joined with ", "
+    fn headers(&mut self) -> Map {
+        self.with_message(|message| {
+            let mut headers = Map::new();
+            for name in message.headers.keys() {
+                let values: Vec<String> = message.headers.get_all(name).iter()
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+                    .collect();
+                headers.insert(name.as_str().into(), values.join(", ").into());
+            }
+            headers
+        })
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) -> Result<(), Box<EvalAltResult>> {
+        let name = hyper::header::HeaderName::from_bytes(name.as_bytes()).map_err(|e| script_error(e.to_string()))?;
+        let value = hyper::header::HeaderValue::from_str(value).map_err(|e| script_error(e.to_string()))?;
+        self.modify(|message| message.headers.insert(name, value));
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: &str) -> Result<(), Box<EvalAltResult>> {
+        let name = hyper::header::HeaderName::from_bytes(name.as_bytes()).map_err(|e| script_error(e.to_string()))?;
+        let value = hyper::header::HeaderValue::from_str(value).map_err(|e| script_error(e.to_string()))?;
+        self.modify(|message| message.headers.append(name, value));
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        self.modify(|message| message.headers.remove(name));
+    }
+
+    fn drop_message(&mut self) {
+        self.state.lock().unwrap().dropped = true;
+    }
+
+    // answer right away with a plain response, skipping the rest of the pipeline
+    fn respond(&mut self, status: rhai::INT, body: String) -> Result<(), Box<EvalAltResult>> {
+        if !(100..=999).contains(&status) {
+            return Err(script_error(format!("invalid status code {}", status)));
+        }
+        let mut response = RequestOrResponse::new_response(
+            Resource::Memory(MemoryResource::new(body.into_bytes())),
+            hyper::HeaderMap::new(),
+            ResponseMeta::new(status as u32, "HTTP/1.1")
+        );
+        response.fix_body_framing();
+        self.state.lock().unwrap().response = Some(response);
+        Ok(())
+    }
+
+    fn into_state(self) -> ScriptMessageState {
+        match Arc::try_unwrap(self.state) {
+            Ok(state) => state.into_inner().unwrap(),
+            Err(shared) => {
+                // a script stashed the handle somewhere, copy out what we need
+                let state = shared.lock().unwrap();
+                ScriptMessageState {
+                    message: state.message.clone(),
+                    modified: state.modified,
+                    dropped: state.dropped,
+                    response: state.response.clone()
+                }
+            }
+        }
+    }
+}
+
+fn build_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| info!("[script] {}", text));
+    engine.on_debug(|text, source, pos| info!("[script] {} {:?} @ {:?}", text, source, pos));
+
+    engine.register_type_with_name::<ScriptMessage>("Message")
+        .register_get("is_response", ScriptMessage::is_response)
+        .register_get_set("method", ScriptMessage::get_method, ScriptMessage::set_method)
+        .register_get_set("url", ScriptMessage::get_url, ScriptMessage::set_url)
+        .register_get("host", ScriptMessage::get_host)
+        .register_get("path", ScriptMessage::get_path)
+        .register_get_set("status", ScriptMessage::get_status, ScriptMessage::set_status)
+        .register_get_set("version", ScriptMessage::get_version, ScriptMessage::set_version)
+        .register_get_set("body", ScriptMessage::get_body, ScriptMessage::set_body)
+        .register_get_set("body_bytes", ScriptMessage::get_body_bytes, ScriptMessage::set_body_bytes)
+        .register_fn("header", ScriptMessage::header)
+        .register_fn("headers", ScriptMessage::headers)
+        .register_fn("set_header", ScriptMessage::set_header)
+        .register_fn("append_header", ScriptMessage::append_header)
+        .register_fn("remove_header", ScriptMessage::remove_header)
+        .register_fn("drop", ScriptMessage::drop_message)
+        .register_fn("respond", ScriptMessage::respond);
+    engine
+}
+
+struct LoadedScript {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    // None when the last edit didn't compile
+    ast: Option<Arc<AST>>,
+}
+
+#[derive(Default)]
+struct ScriptCache {
+    scripts: Vec<LoadedScript>,
+    last_scan: Option<Instant>,
+}
+
+pub struct ScriptPlugin {
+    pub scripts_dir: PathBuf,
+    engine: Arc<Engine>,
+    cache: RwLock<ScriptCache>,
+}
+
+impl ScriptPlugin {
+    pub fn new(scripts_dir: PathBuf) -> Self {
+        Self {
+            scripts_dir,
+            engine: Arc::new(build_engine()),
+            cache: RwLock::new(ScriptCache::default())
+        }
+    }
+
+    // picks up new, changed and deleted scripts, at most once per RESCAN_INTERVAL
+    fn refresh(&self) {
+        {
+            let cache = self.cache.read().unwrap();
+            if cache.last_scan.is_some_and(|last_scan| last_scan.elapsed() < RESCAN_INTERVAL) {
+                return;
+            }
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        cache.last_scan = Some(Instant::now());
+        let mut scripts = Vec::new();
+        for (path, modified) in list_plugin_files(&self.scripts_dir, "rhai") {
+            let existing = cache.scripts.iter().position(|script| script.path == path);
+            if let Some(index) = existing {
+                if cache.scripts[index].modified == modified {
+                    scripts.push(cache.scripts.remove(index));
+                    continue;
+                }
+            }
+            let ast = match self.engine.compile_file(path.clone()) {
+                Ok(ast) => {
+                    info!("loaded script {}", path.display());
+                    Some(Arc::new(ast))
+                },
+                Err(e) => {
+                    error!("failed to compile script {}: {}", path.display(), e);
+                    None
+                }
+            };
+            scripts.push(LoadedScript {
+                path,
+                modified,
+                ast
+            });
+        }
+        cache.scripts = scripts;
+    }
+
+    fn scripts_with(&self, function: &str) -> Vec<(PathBuf, Arc<AST>)> {
+        self.refresh();
+        let cache = self.cache.read().unwrap();
+        cache.scripts.iter()
+            .filter_map(|script| Some((script.path.clone(), script.ast.clone()?)))
+            .filter(|(_, ast)| ast.iter_functions().any(|f| f.name == function && f.params.len() == 1))
+            .collect()
+    }
+
+    async fn run_hook(&self, ctx: &PluginContext, function: &'static str, message: &mut RequestOrResponse) -> PluginAction {
+        let scripts = self.scripts_with(function);
+        if scripts.is_empty() {
+            return PluginAction::Continue;
+        }
+
+        // scripts see the decoded body
+        let (decoded_edit, editable) = DecodedEdit::decode(message, ctx.max_body_size).await;
+        let engine = self.engine.clone();
+        let handle = ScriptMessage::new(editable);
+        let script_handle = handle.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            for (path, ast) in scripts {
+                let mut scope = Scope::new();
+                if let Err(e) = engine.call_fn::<Dynamic>(&mut scope, &ast, function, (script_handle.clone(),)) {
+                    error!("script {} failed in {}: {}", path.display(), function, e);
+                }
+                let state = script_handle.state.lock().unwrap();
+                if state.dropped || state.response.is_some() {
+                    break;
+                }
+            }
+        }).await;
+        if let Err(e) = result {
+            warn!("script runner crashed: {}", e);
+            return PluginAction::Continue;
+        }
+
+        let state = handle.into_state();
+        if state.dropped {
+            return PluginAction::Drop;
+        }
+        if let Some(response) = state.response {
+            return PluginAction::Respond(Box::new(response));
+        }
+        if state.modified {
+            let mut edited = state.message;
+            decoded_edit.encode(&mut edited).await;
+            *message = edited;
+            return PluginAction::Modified;
+        }
+        PluginAction::Continue
+    }
+}
+
+#[async_trait]
+impl Plugin for ScriptPlugin {
+    fn name(&self) -> &str {
+        "scripts"
+    }
+
+    async fn on_request(&self, ctx: &PluginContext, request: &mut RequestOrResponse) -> PluginAction {
+        self.run_hook(ctx, "on_request", request).await
+    }
+
+    async fn on_response(&self, ctx: &PluginContext, response: &mut RequestOrResponse) -> PluginAction {
+        self.run_hook(ctx, "on_response", response).await
+    }
+}
@@ -0,0 +1,346 @@
+use std::{io::ErrorKind, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, time::Duration};
+
+use hudsucker::hyper::Uri;
+use log::{info, warn};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+use crate::{proxy::TelescopeProxyRef, tunnel::{authority, connect_via_http_listener, looks_like_request_line, relay_and_record, serve_via_http_listener, SNIFF_LEN, SNIFF_TIMEOUT}};
+
+// a client that connects and goes quiet mid handshake doesn't get to hold the connection open
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocksVersion {
+    Socks4,
+    Socks5,
+}
+
+impl SocksVersion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SocksVersion::Socks4 => "SOCKS4",
+            SocksVersion::Socks5 => "SOCKS5"
+        }
+    }
+}
+
+struct SocksRequest {
+    version: SocksVersion,
+    host: String,
+    port: u16,
+}
+
+pub async fn serve(proxy_ref: TelescopeProxyRef, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("socks listener on {}", addr);
+    loop {
+        let (stream, client_addr) = listener.accept().await?;
+        let proxy_ref = proxy_ref.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(proxy_ref, stream, client_addr).await {
+                warn!("socks client {}: {}", client_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(proxy_ref: TelescopeProxyRef, mut stream: TcpStream, client_addr: SocketAddr) -> std::io::Result<()> {
+    let request = read_request(&mut stream, HANDSHAKE_TIMEOUT).await?;
+
+    // the client only hears back once we know the target is reachable, so a failure gets a real error code
+    let upstream = proxy_ref.config.borrow().upstream.clone();
+    let server = match crate::upstream::connect(&upstream, &request.host, request.port).await {
+        Ok(server) => server,
+        Err(e) => {
+            stream.write_all(&failure_reply(request.version, &e)).await?;
+            return Err(e);
+        }
+    };
+    stream.write_all(&success_reply(request.version)).await?;
+
+    // tls and plain http go through the same mitm pipeline as the http listener, which opens its own upstream connection
+    let target = authority(&request.host, request.port);
+    match sniff_protocol(&stream).await {
+        Sniffed::Tls => {
+            drop(server);
+            connect_via_http_listener(&proxy_ref, stream, client_addr, &target).await
+        },
+        Sniffed::Http => {
+            drop(server);
+            serve_plain_http(&proxy_ref, stream, client_addr, target).await
+        },
+        Sniffed::Other => relay_and_record(proxy_ref, stream, client_addr, request.version.as_str(), &request.host, request.port, Some(server)).await
+    }
+}
+
+async fn read_request(stream: &mut TcpStream, limit: Duration) -> std::io::Result<SocksRequest> {
+    let handshake = async {
+        match stream.read_u8().await? {
+            4 => socks4_handshake(stream).await,
+            5 => socks5_handshake(stream).await,
+            version => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown socks version {}", version)))
+        }
+    };
+    tokio::time::timeout(limit, handshake).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "socks handshake timed out"))?
+}
+
+fn success_reply(version: SocksVersion) -> Vec<u8> {
+    match version {
+        SocksVersion::Socks4 => vec![0, 0x5a, 0, 0, 0, 0, 0, 0],
+        // the bound address isn't meaningful here, clients ignore it for CONNECT
+        SocksVersion::Socks5 => vec![5, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+    }
+}
+
+fn failure_reply(version: SocksVersion, error: &std::io::Error) -> Vec<u8> {
+    match version {
+        // socks4 only has the one failure code
+        SocksVersion::Socks4 => vec![0, 0x5b, 0, 0, 0, 0, 0, 0],
+        SocksVersion::Socks5 => {
+            let code = match error.kind() {
+                ErrorKind::ConnectionRefused => 5,
+                ErrorKind::TimedOut => 6,
+                ErrorKind::NetworkUnreachable => 3,
+                // name lookups that find nothing end up here too
+                ErrorKind::HostUnreachable | ErrorKind::NotFound => 4,
+                ErrorKind::PermissionDenied => 2,
+                _ => 1
+            };
+            vec![5, code, 0, 1, 0, 0, 0, 0, 0, 0]
+        }
+    }
+}
+
+async fn socks4_handshake(stream: &mut TcpStream) -> std::io::Result<SocksRequest> {
+    let command = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut ip = [0u8; 4];
+    stream.read_exact(&mut ip).await?;
+    // user id, nothing checks it
+    read_null_terminated(stream).await?;
+    // socks4a: 0.0.0.x means the host name follows
+    let host = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        read_null_terminated(stream).await?
+    } else {
+        Ipv4Addr::from(ip).to_string()
+    };
+
+    if command != 1 {
+        stream.write_all(&[0, 0x5b, 0, 0, 0, 0, 0, 0]).await?;
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("socks4 command {} is not supported", command)));
+    }
+    Ok(SocksRequest {
+        version: SocksVersion::Socks4,
+        host,
+        port
+    })
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).to_string()),
+            byte if bytes.len() < 255 => bytes.push(byte),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "socks4 field too long"))
+        }
+    }
+}
+
+async fn socks5_handshake(stream: &mut TcpStream) -> std::io::Result<SocksRequest> {
+    let method_count = stream.read_u8().await?;
+    let mut methods = vec![0u8; method_count as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0) {
+        stream.write_all(&[5, 0xff]).await?;
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "socks5 client wants authentication"));
+    }
+    stream.write_all(&[5, 0]).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let host = match header[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::from(ip).to_string()
+        },
+        3 => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).to_string()
+        },
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        },
+        other => {
+            stream.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown socks5 address type {}", other)));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if header[1] != 1 {
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("socks5 command {} is not supported", header[1])));
+    }
+    Ok(SocksRequest {
+        version: SocksVersion::Socks5,
+        host,
+        port
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    Tls,
+    Http,
+    Other,
+}
+
+async fn sniff_protocol(stream: &TcpStream) -> Sniffed {
+    let mut buffer = [0u8; SNIFF_LEN];
+    match tokio::time::timeout(SNIFF_TIMEOUT, stream.peek(&mut buffer)).await {
+        Ok(Ok(read)) if buffer[..read.min(2)] == [0x16, 0x03] => Sniffed::Tls,
+        Ok(Ok(read)) if looks_like_request_line(&buffer[..read]) => Sniffed::Http,
+        _ => Sniffed::Other
+    }
+}
+
+// every request goes to the http listener in absolute form, the socks target is where it was headed
+async fn serve_plain_http(proxy_ref: &TelescopeProxyRef, stream: TcpStream, client_addr: SocketAddr, target: String) -> std::io::Result<()> {
+    serve_via_http_listener(proxy_ref, stream, client_addr, move |req| {
+        let path_and_query = req.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str()).to_string();
+        *req.uri_mut() = Uri::builder().scheme("http").authority(target.as_str()).path_and_query(path_and_query).build()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        Ok(())
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the client end and the end the listener would have accepted
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    // what the client sent and what it got back
+    async fn handshake(sent: &[u8]) -> (std::io::Result<SocksRequest>, Vec<u8>) {
+        let (mut client, mut server) = connected_pair().await;
+        client.write_all(sent).await.unwrap();
+        let request = read_request(&mut server, Duration::from_secs(5)).await;
+        drop(server);
+        // a reset from unread bytes still leaves whatever came before it
+        let mut reply = Vec::new();
+        let _ = client.read_to_end(&mut reply).await;
+        (request, reply)
+    }
+
+    fn target(request: std::io::Result<SocksRequest>) -> (SocksVersion, String, u16) {
+        let request = request.unwrap();
+        (request.version, request.host, request.port)
+    }
+
+    #[tokio::test]
+    async fn socks4_connects_to_an_ip() {
+        let (request, reply) = handshake(&[4, 1, 0, 80, 10, 0, 0, 1, b'm', b'e', 0]).await;
+        assert_eq!(target(request), (SocksVersion::Socks4, "10.0.0.1".to_string(), 80));
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn socks4a_sends_the_host_name() {
+        let mut sent = vec![4, 1, 0x01, 0xbb, 0, 0, 0, 1, 0];
+        sent.extend_from_slice(b"example.com\0");
+        let (request, _) = handshake(&sent).await;
+        assert_eq!(target(request), (SocksVersion::Socks4, "example.com".to_string(), 443));
+    }
+
+    #[tokio::test]
+    async fn socks4_bind_is_rejected() {
+        let (request, reply) = handshake(&[4, 2, 0, 80, 10, 0, 0, 1, 0]).await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::Unsupported);
+        assert_eq!(reply, [0, 0x5b, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn socks5_address_types() {
+        let (request, reply) = handshake(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await;
+        assert_eq!(target(request), (SocksVersion::Socks5, "10.0.0.1".to_string(), 80));
+        assert_eq!(reply, [5, 0]);
+
+        let mut sent = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        sent.extend_from_slice(b"example.com");
+        sent.extend_from_slice(&443u16.to_be_bytes());
+        let (request, _) = handshake(&sent).await;
+        assert_eq!(target(request), (SocksVersion::Socks5, "example.com".to_string(), 443));
+
+        let mut sent = vec![5, 1, 0, 5, 1, 0, 4];
+        sent.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        sent.extend_from_slice(&8080u16.to_be_bytes());
+        let (request, _) = handshake(&sent).await;
+        assert_eq!(target(request), (SocksVersion::Socks5, "::1".to_string(), 8080));
+    }
+
+    #[tokio::test]
+    async fn socks5_without_no_auth_is_rejected() {
+        // username/password and gssapi only
+        let (request, reply) = handshake(&[5, 2, 2, 1]).await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(reply, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn socks5_unsupported_commands_and_address_types_are_rejected() {
+        // udp associate
+        let (request, reply) = handshake(&[5, 1, 0, 5, 3, 0, 1, 10, 0, 0, 1, 0, 80]).await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::Unsupported);
+        assert_eq!(reply, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let (request, reply) = handshake(&[5, 1, 0, 5, 1, 0, 9]).await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::InvalidData);
+        assert_eq!(reply, [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn unknown_versions_are_rejected() {
+        let (request, _) = handshake(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_time_out() {
+        let (mut client, mut server) = connected_pair().await;
+        // the version and command, then nothing
+        client.write_all(&[5, 1]).await.unwrap();
+        let request = read_request(&mut server, Duration::from_millis(50)).await;
+        assert_eq!(request.err().unwrap().kind(), ErrorKind::TimedOut);
+    }
+
+    async fn sniff(sent: &[u8]) -> Sniffed {
+        let (mut client, server) = connected_pair().await;
+        client.write_all(sent).await.unwrap();
+        // peek returns whatever is there, give it a moment to arrive
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        sniff_protocol(&server).await
+    }
+
+    #[tokio::test]
+    async fn sniffer_tells_tls_http_and_anything_else_apart() {
+        assert_eq!(sniff(&[0x16, 0x03, 0x01, 0x02, 0x00]).await, Sniffed::Tls);
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await, Sniffed::Http);
+        assert_eq!(sniff(b"PROPFIND /dav/ HTTP/1.1\r\n").await, Sniffed::Http);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.6\r\n").await, Sniffed::Other);
+        // server speaks first protocols stay quiet until the timeout
+        assert_eq!(sniff(b"").await, Sniffed::Other);
+    }
+}
@@ -0,0 +1,94 @@
+// monotonic timings of a flow, get_current_time is wall clock and jumps with clock changes
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::resource::get_current_time;
+
+// when the connection a request went over was made, hyper hands this to every response on it
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectTimings {
+    pub started: Instant,
+    pub connected: Instant,
+    // None for plain http
+    pub handshaken: Option<Instant>,
+}
+
+// offsets are microseconds from when the request started arriving
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FlowTimings {
+    // gone after a reload, the offsets stay meaningful though
+    #[serde(skip)]
+    origin: Option<Instant>,
+    // wall clock, only for showing when it happened
+    pub started_at: u128,
+    // the whole request body is in
+    pub request_received: Option<u64>,
+    // plugins and intercept are done with it and it's handed to the upstream client
+    pub forwarded: Option<u64>,
+    // only for requests that needed a new connection, reused ones have none
+    pub connect: Option<(u64, u64)>,
+    pub tls_handshake: Option<(u64, u64)>,
+    pub request_sent: Option<u64>,
+    pub first_byte: Option<u64>,
+    pub response_complete: Option<u64>,
+}
+
+impl FlowTimings {
+    pub fn start() -> Self {
+        Self {
+            origin: Some(Instant::now()),
+            started_at: get_current_time(),
+            ..Default::default()
+        }
+    }
+
+    pub fn offset(&self, instant: Instant) -> Option<u64> {
+        self.origin.map(|origin| instant.saturating_duration_since(origin).as_micros() as u64)
+    }
+
+    pub fn now(&self) -> Option<u64> {
+        self.offset(Instant::now())
+    }
+
+    pub fn record_connect(&mut self, connect: &ConnectTimings) {
+        let (Some(started), Some(connected)) = (self.offset(connect.started), self.offset(connect.connected)) else {
+            return;
+        };
+        // a pooled connection made before this request was forwarded belongs to some earlier one
+        if started < self.forwarded.unwrap_or(0) || connect.started < self.origin.unwrap_or(connect.started) {
+            return;
+        }
+        self.connect = Some((started, connected));
+        self.tls_handshake = connect.handshaken.and_then(|handshaken| self.offset(handshaken)).map(|handshaken| (connected, handshaken));
+    }
+
+    // when the connection was ready to send on, the end of the handshake or connect for new ones
+    pub fn connection_ready(&self) -> Option<u64> {
+        self.tls_handshake.map(|(_, end)| end)
+            .or(self.connect.map(|(_, end)| end))
+            .or(self.forwarded)
+    }
+
+    // (name, start, end) of every phase that was recorded, in order, for the waterfall
+    pub fn phases(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut phases = Vec::new();
+        let mut push = |name, start: Option<u64>, end: Option<u64>| {
+            if let (Some(start), Some(end)) = (start, end) {
+                phases.push((name, start, end.max(start)));
+            }
+        };
+        push("Request received", Some(0), self.request_received);
+        push("Proxy", self.request_received, self.forwarded);
+        push("Connect", self.connect.map(|(start, _)| start), self.connect.map(|(_, end)| end));
+        push("TLS handshake", self.tls_handshake.map(|(start, _)| start), self.tls_handshake.map(|(_, end)| end));
+        push("Request sent", self.connection_ready(), self.request_sent);
+        push("Waiting", self.request_sent, self.first_byte);
+        push("Response", self.first_byte, self.response_complete);
+        phases
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        self.response_complete
+    }
+}
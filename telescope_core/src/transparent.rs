@@ -0,0 +1,256 @@
+// listener for connections redirected with iptables/nftables REDIRECT, the clients don't know there is a proxy
+// the destination comes from SO_ORIGINAL_DST, then the tls sni or Host header
+// make sure the proxy's own outgoing traffic isn't redirected back into it, e.g. with -m owner --uid-owner
+use std::{net::SocketAddr, time::Duration};
+
+use hudsucker::hyper::{header::HOST, Uri};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{proxy::TelescopeProxyRef, tunnel::{authority, connect_via_http_listener, looks_like_request_line, relay_and_record, serve_via_http_listener, SNIFF_LEN, SNIFF_TIMEOUT}};
+
+// a ClientHello record can't be bigger than this
+const MAX_CLIENT_HELLO: usize = 16 * 1024 + 5;
+
+pub async fn serve(proxy_ref: TelescopeProxyRef, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("transparent listener on {}", addr);
+    loop {
+        let (stream, client_addr) = listener.accept().await?;
+        let proxy_ref = proxy_ref.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(proxy_ref, stream, client_addr).await {
+                warn!("transparent client {}: {}", client_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(proxy_ref: TelescopeProxyRef, stream: TcpStream, client_addr: SocketAddr) -> std::io::Result<()> {
+    // connections straight to the listener report the listener itself
+    let original_dst = original_destination(&stream).filter(|dst| Some(*dst) != stream.local_addr().ok());
+
+    let peeked = peek_client_hello(&stream).await;
+    if peeked.starts_with(&[0x16, 0x03]) {
+        let sni = parse_sni(&peeked);
+        let host = match (&sni, original_dst) {
+            (Some(sni), _) => sni.clone(),
+            (None, Some(dst)) => dst.ip().to_string(),
+            (None, None) => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "tls client without sni or original destination"))
+        };
+        let port = original_dst.map_or(443, |dst| dst.port());
+        // hudsucker mints the certificate for the CONNECT authority, so the sni ends up in it
+        return connect_via_http_listener(&proxy_ref, stream, client_addr, &authority(&host, port)).await;
+    }
+    if looks_like_request_line(&peeked) {
+        return serve_plain_http(&proxy_ref, stream, client_addr, original_dst).await;
+    }
+    match original_dst {
+        Some(dst) => relay_and_record(proxy_ref, stream, client_addr, "TCP", &dst.ip().to_string(), dst.port(), None).await,
+        None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "raw tcp client without an original destination"))
+    }
+}
+
+// peeks until a whole tls record is buffered, or whatever showed up for anything else
+async fn peek_client_hello(stream: &TcpStream) -> Vec<u8> {
+    let mut buffer = vec![0u8; MAX_CLIENT_HELLO];
+    let mut peeked = 0;
+    let _ = tokio::time::timeout(SNIFF_TIMEOUT, async {
+        loop {
+            peeked = match stream.peek(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => read
+            };
+            let wanted = if buffer[0] == 0x16 && peeked >= 5 {
+                5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize
+            } else {
+                // not tls, a request line shows itself in the first bytes
+                SNIFF_LEN
+            };
+            if peeked >= wanted.min(MAX_CLIENT_HELLO) {
+                return;
+            }
+            // peek returns right away with the same bytes until more arrive
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await;
+    buffer.truncate(peeked);
+    buffer
+}
+
+// walks a ClientHello up to the server_name extension
+fn parse_sni(record: &[u8]) -> Option<String> {
+    let mut reader = Reader { data: record.get(5..)?, position: 0 };
+    // handshake type 1 is ClientHello
+    if reader.u8()? != 1 {
+        return None;
+    }
+    reader.skip(3)?; // handshake length
+    reader.skip(2 + 32)?; // version and random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+    let extensions_end = reader.u16()? as usize + reader.position;
+    while reader.position + 4 <= extensions_end {
+        let extension_type = reader.u16()?;
+        let extension_len = reader.u16()? as usize;
+        if extension_type != 0 {
+            reader.skip(extension_len)?;
+            continue;
+        }
+        reader.skip(2)?; // server name list length
+        // name type 0 is a host name, the only kind there is
+        if reader.u8()? != 0 {
+            return None;
+        }
+        let name_len = reader.u16()? as usize;
+        return std::str::from_utf8(reader.take(name_len)?).ok().map(|name| name.to_string());
+    }
+    None
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position + len)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// plain http gets every request rewritten to absolute form using the Host header
+async fn serve_plain_http(proxy_ref: &TelescopeProxyRef, stream: TcpStream, client_addr: SocketAddr, original_dst: Option<SocketAddr>) -> std::io::Result<()> {
+    serve_via_http_listener(proxy_ref, stream, client_addr, move |req| {
+        let host = req.headers().get(HOST).and_then(|host| host.to_str().ok()).map(|host| host.to_string())
+            .or_else(|| original_dst.map(|dst| dst.to_string()));
+        let Some(host) = host else {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "request without a Host header or original destination"));
+        };
+        let path_and_query = req.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str()).to_string();
+        *req.uri_mut() = Uri::builder().scheme("http").authority(host).path_and_query(path_and_query).build()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        Ok(())
+    }).await
+}
+
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    use std::{net::{Ipv4Addr, Ipv6Addr}, os::fd::AsRawFd};
+    // from linux/netfilter_ipv4.h and netfilter_ipv6/ip6_tables.h
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let fd = stream.as_raw_fd();
+    // SAFETY: getsockopt writes at most len bytes into a zeroed sockaddr of the matching family
+    unsafe {
+        if stream.local_addr().ok()?.is_ipv4() {
+            let mut addr: libc::sockaddr_in = std::mem::zeroed();
+            let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            if libc::getsockopt(fd, libc::SOL_IP, SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len) != 0 {
+                return None;
+            }
+            Some(SocketAddr::from((Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)), u16::from_be(addr.sin_port))))
+        } else {
+            let mut addr: libc::sockaddr_in6 = std::mem::zeroed();
+            let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            if libc::getsockopt(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len) != 0 {
+                return None;
+            }
+            Some(SocketAddr::from((Ipv6Addr::from(addr.sin6_addr.s6_addr), u16::from_be(addr.sin6_port))))
+        }
+    }
+}
+
+// only netfilter has SO_ORIGINAL_DST, elsewhere we go by sni and Host
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_stream: &TcpStream) -> Option<SocketAddr> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ClientHello record with whatever extensions are passed in
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[4, 1, 2, 3, 4]); // session id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[1, 0]); // compression
+        let mut extension_bytes = Vec::new();
+        for (extension_type, data) in extensions {
+            extension_bytes.extend_from_slice(&extension_type.to_be_bytes());
+            extension_bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extension_bytes.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(extension_bytes.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension_bytes);
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn server_name(name: &[u8]) -> (u16, Vec<u8>) {
+        let mut data = ((name.len() + 3) as u16).to_be_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name);
+        (0, data)
+    }
+
+    #[test]
+    fn finds_server_name_after_other_extensions() {
+        let record = client_hello(&[(10, vec![0, 2, 0, 29]), server_name(b"example.com")]);
+        assert_eq!(parse_sni(&record).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn no_server_name_is_none() {
+        assert_eq!(parse_sni(&client_hello(&[(10, vec![0, 2, 0, 29])])), None);
+        assert_eq!(parse_sni(&client_hello(&[])), None);
+    }
+
+    #[test]
+    fn truncated_or_garbage_records_are_none() {
+        let record = client_hello(&[server_name(b"example.com")]);
+        for len in 0..record.len() {
+            assert_eq!(parse_sni(&record[..len]), None);
+        }
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_sni(&client_hello(&[server_name(b"\xff\xfe")])), None);
+    }
+
+    #[test]
+    fn oversized_lengths_do_not_panic() {
+        let mut record = client_hello(&[server_name(b"example.com")]);
+        // the host name length, just ahead of the 11 name bytes
+        let name_len = record.len() - 13;
+        record[name_len] = 0xff;
+        record[name_len + 1] = 0xff;
+        assert_eq!(parse_sni(&record), None);
+    }
+}
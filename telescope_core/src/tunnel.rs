@@ -0,0 +1,224 @@
+use std::{net::{Ipv4Addr, Ipv6Addr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use hudsucker::hyper::{self, body::Incoming, service::service_fn, Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::warn;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpStream, sync::Mutex};
+
+use crate::{connection::ForwardedConnection, proxy::{TelescopeProxyHandler, TelescopeProxyRef}, resource::{HTTPPair, RequestMeta, RequestOrResponse, Resource}};
+
+// how often the byte counts of an open tunnel get copied into its flow
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// clients speak first for tls and http, anything quieter than this is relayed as raw tcp
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+// enough for any method we'd expect and the space after it
+pub const SNIFF_LEN: usize = 24;
+
+#[derive(Debug, Default)]
+pub struct TunnelCounters {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+}
+
+impl TunnelCounters {
+    pub fn get(&self) -> (u64, u64) {
+        (self.sent.load(Ordering::Relaxed), self.received.load(Ordering::Relaxed))
+    }
+}
+
+async fn pipe(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, counter: &AtomicU64) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; 16 * 1024];
+    loop {
+        let read = from.read(&mut buffer).await?;
+        if read == 0 {
+            // pass the half close along so the other side sees the end of stream
+            return to.shutdown().await;
+        }
+        to.write_all(&buffer[..read]).await?;
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+    }
+}
+
+// copies bytes both ways until both sides are done, like copy_bidirectional but the counts survive errors
+// on_progress gets called every so often with (sent, received) while the tunnel is open
+pub async fn relay(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    server: TcpStream,
+    counters: Arc<TunnelCounters>,
+    mut on_progress: impl FnMut(u64, u64),
+) -> std::io::Result<()> {
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = server.into_split();
+
+    let copy = async {
+        let (upload, download) = tokio::join!(
+            pipe(client_read, server_write, &counters.sent),
+            pipe(server_read, client_write, &counters.received)
+        );
+        upload.and(download)
+    };
+    tokio::pin!(copy);
+
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut copy => {
+                return result;
+            },
+            _ = progress.tick() => {
+                let (sent, received) = counters.get();
+                on_progress(sent, received);
+            }
+        }
+    }
+}
+
+// any method token followed by a path or an absolute url, not just the GETs hudsucker looks for
+pub fn looks_like_request_line(peeked: &[u8]) -> bool {
+    let peeked = &peeked[..peeked.len().min(SNIFF_LEN)];
+    let Some(space) = peeked.iter().position(|byte| *byte == b' ') else {
+        return false;
+    };
+    // tchar from rfc 9110
+    let is_tchar = |byte: &u8| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(byte);
+    let target = &peeked[space + 1..];
+    space > 0 && peeked[..space].iter().all(is_tchar)
+        && (target.starts_with(b"/") || target.starts_with(b"*") || target.starts_with(b"http://") || target.starts_with(b"https://"))
+}
+
+// host:port with brackets around ipv6 hosts
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// where the other listeners reach the http one, an unspecified bind address means loopback
+pub fn http_listener_addr(proxy_ref: &TelescopeProxyRef) -> SocketAddr {
+    let mut http_addr = proxy_ref.config.borrow().addr;
+    if http_addr.ip().is_unspecified() {
+        http_addr.set_ip(if http_addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+    }
+    http_addr
+}
+
+// turns a connection from another listener into a CONNECT on our own http listener so hudsucker can mitm it
+pub async fn connect_via_http_listener(proxy_ref: &TelescopeProxyRef, mut client: TcpStream, client_addr: SocketAddr, authority: &str) -> std::io::Result<()> {
+    let mut proxy = TcpStream::connect(http_listener_addr(proxy_ref)).await?;
+    let _forwarded = ForwardedConnection::new(proxy_ref.connections.clone(), proxy.local_addr()?, client_addr);
+    proxy.write_all(format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n").as_bytes()).await?;
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(proxy.read_u8().await?);
+    }
+    if !response.starts_with(b"HTTP/1.1 200") {
+        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, format!("http listener refused CONNECT {}: {}", authority, String::from_utf8_lossy(&response))));
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut proxy).await?;
+    Ok(())
+}
+
+// serves plain http/1 on client and sends every request to the http listener in absolute form like a proxy aware client would
+// rewrite has to turn the request uri into an absolute one
+pub async fn serve_via_http_listener(
+    proxy_ref: &TelescopeProxyRef,
+    client: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    client_addr: SocketAddr,
+    rewrite: impl Fn(&mut Request<Incoming>) -> std::io::Result<()> + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let http_listener = TcpStream::connect(http_listener_addr(proxy_ref)).await?;
+    let _forwarded = ForwardedConnection::new(proxy_ref.connections.clone(), http_listener.local_addr()?, client_addr);
+    let (sender, connection) = hyper::client::conn::http1::handshake::<_, Incoming>(TokioIo::new(http_listener)).await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("connection to http listener: {}", e);
+        }
+    });
+
+    // http1 serves one request at a time, the lock is never contended
+    let sender = Arc::new(Mutex::new(sender));
+    let rewrite = Arc::new(rewrite);
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        let sender = sender.clone();
+        let rewrite = rewrite.clone();
+        async move {
+            rewrite(&mut req)?;
+            let mut sender = sender.lock().await;
+            sender.ready().await.map_err(std::io::Error::other)?;
+            sender.send_request(req).await.map_err(std::io::Error::other)
+        }
+    });
+    hyper::server::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .serve_connection(TokioIo::new(client), service)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+// for connections that aren't http or tls, recorded as a tunnel flow unless out of scope
+// kind ends up as the method of the flow and the scheme of its url, like SOCKS5 or TCP
+// server is the connection to host:port if the caller already opened one
+pub async fn relay_and_record(proxy_ref: TelescopeProxyRef, client: TcpStream, client_addr: SocketAddr, kind: &str, host: &str, port: u16, server: Option<TcpStream>) -> std::io::Result<()> {
+    let target: Uri = format!("{}://{}", kind.to_lowercase(), authority(host, port)).parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad tunnel target: {}", e)))?;
+    let should_track = proxy_ref.config.borrow().scope.should_record(&target);
+    if !should_track {
+        let server = match server {
+            Some(server) => server,
+            None => {
+                let upstream = proxy_ref.config.borrow().upstream.clone();
+                crate::upstream::connect(&upstream, host, port).await?
+            }
+        };
+        return relay(client, server, Default::default(), |_, _| {}).await;
+    }
+
+    let meta = RequestMeta::new(&target.to_string(), kind, "");
+    let connect = HTTPPair::new_request(RequestOrResponse::new_request(Resource::empty(), Default::default(), meta));
+    let mut handler = TelescopeProxyHandler::new(proxy_ref);
+    handler.add_tunnel_flow(client_addr, connect, &authority(host, port));
+    handler.run_tunnel(client_addr, client, host, port, server).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_lines_with_any_method_are_http() {
+        for line in [
+            "GET / HTTP/1.1\r\n",
+            "PROPFIND /calendars/ HTTP/1.1\r\n",
+            "M-SEARCH * HTTP/1.1\r\n",
+            "POST http://example.com/ HTTP/1.1\r\n",
+        ] {
+            assert!(looks_like_request_line(line.as_bytes()), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn other_protocols_are_not_http() {
+        for peeked in [
+            &b"SSH-2.0-OpenSSH_9.6\r\n"[..],
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03",
+            b"EHLO mail.example.com\r\n",
+            b" / HTTP/1.1\r\n",
+            b"GET",
+            b"",
+        ] {
+            assert!(!looks_like_request_line(peeked), "{:?}", peeked);
+        }
+    }
+
+    #[test]
+    fn only_the_start_is_looked_at() {
+        let mut peeked = vec![b'A'; SNIFF_LEN];
+        peeked.extend_from_slice(b" / HTTP/1.1\r\n");
+        assert!(!looks_like_request_line(&peeked));
+    }
+}
@@ -0,0 +1,446 @@
+use std::{collections::HashMap, future::Future, io::{Error, ErrorKind}, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, time::Instant};
+
+use base64::Engine;
+use hudsucker::{hyper::Uri, rustls::{self, client::WantsClientCert, crypto::CryptoProvider, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName}, ClientConfig, ConfigBuilder}, Body};
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::{client::legacy::{connect::{Connected, Connection}, Client}, rt::{TokioExecutor, TokioIo}};
+use log::warn;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::watch::Receiver};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{config::{Config, UpstreamConfig, UpstreamProxy, UpstreamProxyKind}, connection::TlsInfo, resource::ResolveString, timing::ConnectTimings, verify::UpstreamVerifier};
+
+// a CONNECT response bigger than this is not something we want to keep reading
+const MAX_CONNECT_RESPONSE: usize = 16 * 1024;
+
+// opens a tcp connection to host:port, through the upstream proxy if the config has one for the host
+pub async fn connect(upstream: &UpstreamConfig, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let stream = match upstream.proxy_for(host) {
+        Some(proxy) => {
+            let mut stream = TcpStream::connect(proxy.address.as_str()).await
+                .map_err(|e| Error::new(e.kind(), format!("failed to connect to upstream proxy {}: {}", proxy.address, e)))?;
+            match proxy.kind {
+                UpstreamProxyKind::Http => http_connect(&mut stream, proxy, host, port).await?,
+                UpstreamProxyKind::Socks5 => socks5_connect(&mut stream, proxy, host, port).await?
+            }
+            stream
+        },
+        None => TcpStream::connect((host, port)).await?
+    };
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+async fn http_connect(stream: &mut TcpStream, proxy: &UpstreamProxy, host: &str, port: u16) -> std::io::Result<()> {
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if !proxy.username.is_empty() {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", proxy.username, proxy.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // byte at a time so nothing past the headers gets eaten, those belong to the tunnel
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_CONNECT_RESPONSE {
+            return Err(Error::new(ErrorKind::InvalidData, "upstream proxy sent an oversized CONNECT response"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(Error::new(ErrorKind::ConnectionRefused, format!("upstream proxy refused CONNECT to {}:{}: {}", host, port, status_line)))
+    }
+}
+
+async fn socks5_connect(stream: &mut TcpStream, proxy: &UpstreamProxy, host: &str, port: u16) -> std::io::Result<()> {
+    let has_credentials = !proxy.username.is_empty();
+    // no auth, plus username/password when we have some
+    if has_credentials {
+        stream.write_all(&[5, 2, 0, 2]).await?;
+    } else {
+        stream.write_all(&[5, 1, 0]).await?;
+    }
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0] => {},
+        [5, 2] if has_credentials => {
+            if proxy.username.len() > 255 || proxy.password.len() > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, "socks5 credentials can be at most 255 bytes"));
+            }
+            let mut auth = vec![1, proxy.username.len() as u8];
+            auth.extend_from_slice(proxy.username.as_bytes());
+            auth.push(proxy.password.len() as u8);
+            auth.extend_from_slice(proxy.password.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(Error::new(ErrorKind::PermissionDenied, "socks5 upstream rejected the credentials"));
+            }
+        },
+        _ => return Err(Error::new(ErrorKind::ConnectionRefused, "socks5 upstream has no acceptable auth method"))
+    }
+
+    // always send the name so the upstream does the dns lookup
+    if host.len() > 255 {
+        return Err(Error::new(ErrorKind::InvalidInput, "host name too long for socks5"));
+    }
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(Error::new(ErrorKind::ConnectionRefused, format!("socks5 upstream failed to connect to {}:{} (reply {})", host, port, reply[1])));
+    }
+    // skip the bound address, nothing uses it
+    let address_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        other => return Err(Error::new(ErrorKind::InvalidData, format!("socks5 upstream sent unknown address type {}", other)))
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+// plugs into the hyper client so every upstream request goes through connect above
+// it does the tls handshake itself to time it and to pick the tls config per host, so upstreams asking for
+// mutual tls get the client certificate configured for them. rustls doesn't tell a client cert resolver
+// which server it's talking to, so it's one tls config per rule instead
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    pub config: Receiver<Config>,
+    provider: Arc<CryptoProvider>,
+    verifier: Arc<UpstreamVerifier>,
+    default: Arc<ClientConfig>,
+    // built on first use, keyed by the rule so edits to it build a new one
+    with_certs: Arc<Mutex<HashMap<String, Arc<ClientConfig>>>>,
+}
+
+impl UpstreamConnector {
+    pub fn new(config: Receiver<Config>, provider: CryptoProvider) -> Result<Self, rustls::Error> {
+        let provider = Arc::new(provider);
+        let verifier = Arc::new(UpstreamVerifier::new(config.clone(), provider.clone()));
+        let default = Arc::new(with_alpn(client_config_builder(provider.clone(), verifier.clone())?.with_no_client_auth()));
+        Ok(Self {
+            config,
+            provider,
+            verifier,
+            default,
+            with_certs: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    fn tls_config_for(&self, host: &str) -> std::io::Result<Arc<ClientConfig>> {
+        let config = self.config.borrow();
+        let Some(rule) = config.client_cert_for(host) else {
+            return Ok(self.default.clone());
+        };
+        let key = format!("{:?}", rule);
+        let mut with_certs = self.with_certs.lock().unwrap();
+        if let Some(tls_config) = with_certs.get(&key) {
+            return Ok(tls_config.clone());
+        }
+
+        let certificates = CertificateDer::pem_slice_iter(&config.try_resolve_bytes(&rule.certificate)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("bad client certificate for {}: {}", rule.host, e)))?;
+        let private_key = PrivateKeyDer::from_pem_slice(&config.try_resolve_bytes(&rule.key)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("bad client key for {}: {}", rule.host, e)))?;
+        let tls_config = client_config_builder(self.provider.clone(), self.verifier.clone())
+            .and_then(|builder| builder.with_client_auth_cert(certificates, private_key))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("client certificate for {} rejected: {}", rule.host, e)))?;
+        let tls_config = Arc::new(with_alpn(tls_config));
+        with_certs.insert(key, tls_config.clone());
+        Ok(tls_config)
+    }
+}
+
+impl tower_service::Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        self.open(uri, true)
+    }
+}
+
+impl UpstreamConnector {
+    // websocket upgrades need http/1.1, an upstream that picked h2 couldn't take the upgrade
+    pub async fn connect_http1(&self, uri: Uri) -> std::io::Result<UpstreamStream> {
+        self.open(uri, false).await
+    }
+
+    // for anything that still reaches hudsucker's own websocket client, same verification but no client certificate
+    pub fn websocket_tls_config(&self) -> Arc<ClientConfig> {
+        Arc::new(http1_only(&self.default))
+    }
+
+    fn open(&self, uri: Uri, allow_h2: bool) -> Pin<Box<dyn Future<Output = std::io::Result<UpstreamStream>> + Send>> {
+        let upstream = self.config.borrow().upstream.clone();
+        let Some(host) = uri.host() else {
+            return Box::pin(async move { Err(Error::new(ErrorKind::InvalidInput, format!("no host in {}", uri))) });
+        };
+        // ipv6 hosts come with brackets in uris
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        let tls_config = match uri.scheme_str() {
+            Some("https") => match self.tls_config_for(&host) {
+                Ok(tls_config) if allow_h2 => Some(tls_config),
+                Ok(tls_config) => Some(Arc::new(http1_only(&tls_config))),
+                Err(e) => {
+                    // hyper's connect error doesn't say much, so the reason is logged here
+                    warn!("not connecting to {}: {}", host, e);
+                    return Box::pin(async move { Err(e) });
+                }
+            },
+            Some("http") => None,
+            _ => return Box::pin(async move { Err(Error::new(ErrorKind::InvalidInput, format!("unsupported scheme in {}", uri))) })
+        };
+        let port = uri.port_u16().unwrap_or(if tls_config.is_some() { 443 } else { 80 });
+        Box::pin(async move {
+            let started = Instant::now();
+            let tcp = connect(&upstream, &host, port).await?;
+            let connected = Instant::now();
+            let Some(tls_config) = tls_config else {
+                return Ok(UpstreamStream {
+                    inner: UpstreamIo::Plain(TokioIo::new(tcp)),
+                    tls: None,
+                    timings: ConnectTimings { started, connected, handshaken: None }
+                });
+            };
+            let server_name = ServerName::try_from(host.clone())
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("bad server name {}: {}", host, e)))?;
+            let tls = TlsConnector::from(tls_config).connect(server_name, tcp).await?;
+            let info = TlsInfo::from_connection(&host, tls.get_ref().1);
+            Ok(UpstreamStream {
+                inner: UpstreamIo::Tls(Box::new(TokioIo::new(tls))),
+                tls: Some(Arc::new(info)),
+                timings: ConnectTimings { started, connected, handshaken: Some(Instant::now()) }
+            })
+        })
+    }
+}
+
+enum UpstreamIo {
+    Plain(TokioIo<TcpStream>),
+    Tls(Box<TokioIo<TlsStream<TcpStream>>>),
+}
+
+// the upstream connection with its handshake details and timings, which hyper copies into the extensions of every response over it
+pub struct UpstreamStream {
+    inner: UpstreamIo,
+    pub tls: Option<Arc<TlsInfo>>,
+    pub timings: ConnectTimings,
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let connected = Connected::new().extra(self.timings);
+        match &self.tls {
+            // hyper only speaks h2 on a connection when told the handshake settled on it
+            Some(tls) if tls.alpn.as_deref() == Some("h2") => connected.extra(tls.clone()).negotiated_h2(),
+            Some(tls) => connected.extra(tls.clone()),
+            None => connected
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            UpstreamIo::Tls(io) => Pin::new(io.as_mut()).poll_read(cx, buf)
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            UpstreamIo::Tls(io) => Pin::new(io.as_mut()).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_flush(cx),
+            UpstreamIo::Tls(io) => Pin::new(io.as_mut()).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            UpstreamIo::Tls(io) => Pin::new(io.as_mut()).poll_shutdown(cx)
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.inner {
+            UpstreamIo::Plain(io) => io.is_write_vectored(),
+            UpstreamIo::Tls(io) => io.is_write_vectored()
+        }
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[std::io::IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            UpstreamIo::Tls(io) => Pin::new(io.as_mut()).poll_write_vectored(cx, bufs)
+        }
+    }
+}
+
+fn client_config_builder(provider: Arc<CryptoProvider>, verifier: Arc<UpstreamVerifier>) -> Result<ConfigBuilder<ClientConfig, WantsClientCert>, rustls::Error> {
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier))
+}
+
+// offer h2 first like hudsucker's own connector does, servers that don't do it pick http/1.1
+fn with_alpn(mut tls_config: ClientConfig) -> ClientConfig {
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    tls_config
+}
+
+fn http1_only(tls_config: &ClientConfig) -> ClientConfig {
+    let mut tls_config = tls_config.clone();
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    tls_config
+}
+
+// same client hudsucker builds with with_rustls_client, just connecting through UpstreamConnector
+pub fn build_client(connector: UpstreamConnector) -> Client<UpstreamConnector, Body> {
+    Client::builder(TokioExecutor::new())
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true)
+        .build(connector)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::UpstreamRule;
+
+    fn upstream(kind: UpstreamProxyKind, address: String, username: &str) -> UpstreamConfig {
+        UpstreamConfig {
+            enabled: true,
+            proxy: Some(UpstreamProxy {
+                kind,
+                address,
+                username: username.to_string(),
+                password: "secret".to_string()
+            }),
+            rules: Vec::new()
+        }
+    }
+
+    // a fake upstream proxy, each step reads that many bytes (or up to a blank line) and then answers
+    // hands back everything it read
+    async fn fake_proxy(steps: Vec<(usize, Vec<u8>)>) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            for (read, reply) in steps {
+                let start = received.len();
+                while received.len() - start < read && !received.ends_with(b"\r\n\r\n") {
+                    received.push(stream.read_u8().await.unwrap());
+                }
+                stream.write_all(&reply).await.unwrap();
+            }
+            received
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn rules_pick_the_proxy() {
+        let mut config = upstream(UpstreamProxyKind::Http, "default:8080".to_string(), "");
+        config.rules.push(UpstreamRule {
+            enabled: true,
+            host: "*.internal".to_string(),
+            proxy: None
+        });
+        assert!(config.proxy_for("api.internal").is_none());
+        assert_eq!(config.proxy_for("example.com").unwrap().address, "default:8080");
+        config.enabled = false;
+        assert!(config.proxy_for("example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn http_connect_leaves_tunnel_bytes_alone() {
+        let (address, proxy) = fake_proxy(vec![(usize::MAX, b"HTTP/1.1 200 Connection established\r\n\r\ntunnel".to_vec())]).await;
+        let mut stream = connect(&upstream(UpstreamProxyKind::Http, address, "user"), "example.com", 443).await.unwrap();
+        let mut tunnel = [0u8; 6];
+        stream.read_exact(&mut tunnel).await.unwrap();
+        assert_eq!(&tunnel, b"tunnel");
+
+        let request = String::from_utf8(proxy.await.unwrap()).unwrap();
+        assert!(request.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_connect_refusal_is_an_error() {
+        let (address, _proxy) = fake_proxy(vec![(usize::MAX, b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_vec())]).await;
+        let e = connect(&upstream(UpstreamProxyKind::Http, address, ""), "example.com", 443).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn http_connect_gives_up_on_endless_headers() {
+        let mut reply = b"HTTP/1.1 200 OK\r\n".to_vec();
+        reply.extend(std::iter::repeat(b'a').take(MAX_CONNECT_RESPONSE + 16));
+        let (address, _proxy) = fake_proxy(vec![(usize::MAX, reply)]).await;
+        let e = connect(&upstream(UpstreamProxyKind::Http, address, ""), "example.com", 443).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_with_credentials() {
+        // the reply has a domain as the bound address
+        let mut reply = vec![5, 0, 0, 3, 4];
+        reply.extend_from_slice(b"host");
+        reply.extend_from_slice(&[0, 80]);
+        reply.extend_from_slice(b"tunnel");
+        let greeting = 4;
+        let auth = 3 + "user".len() + "secret".len();
+        let request = 5 + "example.com".len() + 2;
+        let (address, proxy) = fake_proxy(vec![(greeting, vec![5, 2]), (auth, vec![1, 0]), (request, reply)]).await;
+        let mut stream = connect(&upstream(UpstreamProxyKind::Socks5, address, "user"), "example.com", 443).await.unwrap();
+        let mut tunnel = [0u8; 6];
+        stream.read_exact(&mut tunnel).await.unwrap();
+        assert_eq!(&tunnel, b"tunnel");
+
+        let received = proxy.await.unwrap();
+        assert_eq!(&received[..greeting], &[5, 2, 0, 2]);
+        assert_eq!(&received[greeting..greeting + auth], b"\x01\x04user\x06secret");
+        assert_eq!(&received[greeting + auth..], b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+    }
+
+    #[tokio::test]
+    async fn socks5_failure_reply_is_an_error() {
+        let (address, _proxy) = fake_proxy(vec![(3, vec![5, 0]), (5 + "example.com".len() + 2, vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0])]).await;
+        let e = connect(&upstream(UpstreamProxyKind::Socks5, address, ""), "example.com", 443).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    }
+}
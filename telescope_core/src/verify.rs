@@ -0,0 +1,112 @@
+// upstream certificate verification following Config::upstream_tls
+// roots are rebuilt when the config changes them, insecure hosts are checked on every handshake
+use std::{fmt, sync::{Arc, Mutex}};
+
+use hudsucker::rustls::{self, client::{danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, WebPkiServerVerifier}, crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider}, pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime}, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use log::{info, warn};
+use tokio::sync::watch::Receiver;
+
+use crate::{config::{Config, TlsVerificationConfig}, resource::ResolveString};
+
+pub struct UpstreamVerifier {
+    config: Receiver<Config>,
+    provider: Arc<CryptoProvider>,
+    // the roots part of the config it was built from, and the verifier or why it couldn't be built
+    verifier: Mutex<(String, Result<Arc<WebPkiServerVerifier>, String>)>,
+}
+
+impl fmt::Debug for UpstreamVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamVerifier").finish_non_exhaustive()
+    }
+}
+
+impl UpstreamVerifier {
+    pub fn new(config: Receiver<Config>, provider: Arc<CryptoProvider>) -> Self {
+        let (key, verifier) = {
+            let config = config.borrow();
+            (roots_key(&config.upstream_tls), build_verifier(&config, provider.clone()))
+        };
+        Self {
+            config,
+            provider,
+            verifier: Mutex::new((key, verifier))
+        }
+    }
+
+    fn current(&self) -> Result<Arc<WebPkiServerVerifier>, rustls::Error> {
+        let config = self.config.borrow();
+        let key = roots_key(&config.upstream_tls);
+        let mut verifier = self.verifier.lock().unwrap();
+        if verifier.0 != key {
+            *verifier = (key, build_verifier(&config, self.provider.clone()));
+        }
+        verifier.1.clone().map_err(rustls::Error::General)
+    }
+}
+
+fn roots_key(tls: &TlsVerificationConfig) -> String {
+    format!("{} {:?}", tls.system_roots, tls.extra_cas)
+}
+
+fn build_verifier(config: &Config, provider: Arc<CryptoProvider>) -> Result<Arc<WebPkiServerVerifier>, String> {
+    let tls = &config.upstream_tls;
+    let mut roots = RootCertStore::empty();
+    if tls.system_roots {
+        let native = rustls_native_certs::load_native_certs();
+        for error in &native.errors {
+            warn!("failed to load system root certificates: {}", error);
+        }
+        let (added, _) = roots.add_parsable_certificates(native.certs);
+        if added == 0 {
+            info!("no system root certificates, using the bundled ones");
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+    }
+    for ca in &tls.extra_cas {
+        let pem = match config.try_resolve_bytes(ca) {
+            Ok(pem) => pem,
+            Err(e) => {
+                warn!("skipping extra ca: {}", e);
+                continue;
+            }
+        };
+        for certificate in CertificateDer::pem_slice_iter(&pem) {
+            match certificate.map_err(|e| e.to_string()).and_then(|certificate| roots.add(certificate).map_err(|e| e.to_string())) {
+                Ok(()) => {},
+                Err(e) => warn!("skipping extra ca certificate: {}", e)
+            }
+        }
+    }
+    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()
+        .map_err(|e| format!("no usable upstream trust roots: {}", e))
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.config.borrow().upstream_tls.is_insecure(&server_name.to_str()) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.current()?.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    // signatures are still checked for insecure hosts, they only prove the server has the key for the cert it sent
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
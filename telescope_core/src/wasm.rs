@@ -0,0 +1,391 @@
+use std::{path::PathBuf, sync::{Arc, RwLock}, time::{Duration, Instant, SystemTime}};
+
+use async_trait::async_trait;
+use hyper::{header::{HeaderName, HeaderValue}, HeaderMap};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use wasmtime::{Caller, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{encoding::DecodedEdit, plugin::{list_plugin_files, Plugin, PluginAction, PluginContext}, proxy::FlowStorage, resource::{Flow, MemoryResource, RequestOrResponse, RequestOrResponseMeta, Resource}};
+
+// sandboxed plugins live in <data_dir>/plugins/*.wasm
+//
+// a module exports its linear memory as "memory", an allocator "alloc(len: i32) -> i32" and any of
+// "on_request", "on_response", "on_flow_completed" as (ptr: i32, len: i32) -> i64
+// the input is a json WasmFlow written into memory from alloc, the return value is 0 to leave things alone
+// or (ptr << 32) | len of a json WasmResult, e.g. {"action": "drop"}
+// the return value of on_flow_completed is ignored
+// modules may import "telescope" "log" (ptr: i32, len: i32) to write utf-8 to the log
+//
+// every call gets a fresh instance so plugins can't keep state between flows
+
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+// enough for real work on a big body, not enough to hang the proxy on an infinite loop
+const FUEL_PER_CALL: u64 = 200_000_000;
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+// the most a hook may hand back, checked before anything gets allocated for it
+const MAX_OUTPUT: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WasmMessage {
+    pub meta: RequestOrResponseMeta,
+    // in wire order, repeated headers show up more than once
+    pub headers: Vec<(String, String)>,
+    // decoded according to Content-Encoding
+    pub body: Vec<u8>,
+}
+
+impl WasmMessage {
+    pub fn from_message(message: &RequestOrResponse) -> std::io::Result<Self> {
+        Ok(Self {
+            meta: message.meta.clone(),
+            headers: message.headers.iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+                .collect(),
+            body: message.body.as_bytes()?
+        })
+    }
+
+    pub fn into_message(self) -> Result<RequestOrResponse, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::from_str(&value).map_err(|e| format!("invalid header value {}: {}", value, e))?;
+            headers.append(name, value);
+        }
+        let body = Resource::Memory(MemoryResource::new(self.body));
+        Ok(match self.meta {
+            RequestOrResponseMeta::Request(meta) => RequestOrResponse::new_request(body, headers, meta),
+            RequestOrResponseMeta::Response(meta) => RequestOrResponse::new_response(body, headers, meta)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WasmFlow {
+    pub id: Option<String>,
+    pub client_addr: String,
+    pub request: Option<WasmMessage>,
+    pub response: Option<WasmMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum WasmResult {
+    Continue,
+    // replaces the message the hook was called for
+    Modified { message: WasmMessage },
+    Drop,
+    Respond { message: WasmMessage },
+}
+
+struct PluginState {
+    name: String,
+    limits: StoreLimits,
+}
+
+struct LoadedModule {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    // None when the module failed to compile
+    module: Option<Module>,
+}
+
+enum ScannedModule {
+    Unchanged,
+    // None when the module failed to compile
+    Compiled(Option<Module>),
+}
+
+#[derive(Default)]
+struct ModuleCache {
+    modules: Vec<LoadedModule>,
+    last_scan: Option<Instant>,
+}
+
+pub struct WasmPlugin {
+    pub plugins_dir: PathBuf,
+    engine: Engine,
+    linker: Arc<Linker<PluginState>>,
+    cache: RwLock<ModuleCache>,
+    // responses only carry themselves, the request comes from the recorded flow
+    flow_storage: Arc<RwLock<FlowStorage>>,
+}
+
+fn build_linker(engine: &Engine) -> Linker<PluginState> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap("telescope", "log", |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+        let Some(memory) = caller.get_export("memory").and_then(|export| export.into_memory()) else {
+            return;
+        };
+        let data = memory.data(&caller);
+        let start = ptr as u32 as usize;
+        let end = start.saturating_add(len as u32 as usize);
+        if let Some(text) = data.get(start..end) {
+            info!("[{}] {}", caller.data().name, String::from_utf8_lossy(text));
+        }
+    }).expect("failed to define wasm plugin imports");
+    linker
+}
+
+// runs one hook in a fresh instance, Ok(None) means the plugin returned 0
+fn call_hook(engine: &Engine, linker: &Linker<PluginState>, name: &str, module: &Module, hook: &str, input: &[u8]) -> wasmtime::Result<Option<Vec<u8>>> {
+    let mut store = Store::new(engine, PluginState {
+        name: name.to_string(),
+        limits: StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY)
+            .instances(1)
+            .build()
+    });
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(FUEL_PER_CALL)?;
+
+    let instance = linker.instantiate(&mut store, module)?;
+    let memory = instance.get_memory(&mut store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+    let hook_fn = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook)?;
+
+    let input_len = i32::try_from(input.len())?;
+    let input_ptr = alloc.call(&mut store, input_len)?;
+    memory.write(&mut store, input_ptr as u32 as usize, input)?;
+    let packed = hook_fn.call(&mut store, (input_ptr, input_len))? as u64;
+    if packed == 0 {
+        return Ok(None);
+    }
+
+    let output_ptr = (packed >> 32) as usize;
+    let output_len = (packed & 0xffff_ffff) as usize;
+    if output_len > MAX_OUTPUT {
+        return Err(wasmtime::Error::msg(format!("result of {} bytes is over the {} byte limit", output_len, MAX_OUTPUT)));
+    }
+    let output = output_ptr.checked_add(output_len)
+        .filter(|end| *end <= memory.data_size(&store))
+        .map(|end| memory.data(&store)[output_ptr..end].to_vec())
+        .ok_or_else(|| wasmtime::Error::msg(format!("result at {} with {} bytes is outside of memory", output_ptr, output_len)))?;
+    Ok(Some(output))
+}
+
+impl WasmPlugin {
+    pub fn new(plugins_dir: PathBuf, flow_storage: Arc<RwLock<FlowStorage>>) -> Self {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).expect("failed to create wasm engine");
+        Self {
+            plugins_dir,
+            linker: Arc::new(build_linker(&engine)),
+            engine,
+            cache: RwLock::new(ModuleCache::default()),
+            flow_storage
+        }
+    }
+
+    // same reload rules as scripts, at most once per RESCAN_INTERVAL
+    // scanning and compiling happen on a blocking thread, the old modules stay in use until it's done
+    async fn refresh(&self) {
+        let known: Vec<(PathBuf, Option<SystemTime>)> = {
+            let mut cache = self.cache.write().unwrap();
+            if cache.last_scan.is_some_and(|last_scan| last_scan.elapsed() < RESCAN_INTERVAL) {
+                return;
+            }
+            cache.last_scan = Some(Instant::now());
+            cache.modules.iter().map(|loaded| (loaded.path.clone(), loaded.modified)).collect()
+        };
+
+        let plugins_dir = self.plugins_dir.clone();
+        let engine = self.engine.clone();
+        let scanned = tokio::task::spawn_blocking(move || {
+            list_plugin_files(&plugins_dir, "wasm").into_iter()
+                .map(|(path, modified)| {
+                    if known.contains(&(path.clone(), modified)) {
+                        return (path, modified, ScannedModule::Unchanged);
+                    }
+                    let module = match Module::from_file(&engine, &path) {
+                        Ok(module) => {
+                            info!("loaded wasm plugin {}", path.display());
+                            Some(module)
+                        },
+                        Err(e) => {
+                            error!("failed to load wasm plugin {}: {}", path.display(), e);
+                            None
+                        }
+                    };
+                    (path, modified, ScannedModule::Compiled(module))
+                })
+                .collect::<Vec<_>>()
+        }).await;
+        let scanned = match scanned {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                warn!("wasm plugin scan crashed: {}", e);
+                return;
+            }
+        };
+
+        let mut cache = self.cache.write().unwrap();
+        let mut modules = Vec::new();
+        for (path, modified, scanned) in scanned {
+            match scanned {
+                ScannedModule::Unchanged => {
+                    if let Some(index) = cache.modules.iter().position(|loaded| loaded.path == path) {
+                        modules.push(cache.modules.remove(index));
+                    }
+                },
+                ScannedModule::Compiled(module) => modules.push(LoadedModule {
+                    path,
+                    modified,
+                    module
+                })
+            }
+        }
+        cache.modules = modules;
+    }
+
+    async fn modules_with(&self, hook: &str) -> Vec<(PathBuf, Module)> {
+        self.refresh().await;
+        let cache = self.cache.read().unwrap();
+        cache.modules.iter()
+            .filter_map(|loaded| Some((loaded.path.clone(), loaded.module.clone()?)))
+            .filter(|(_, module)| module.exports().any(|export| export.name() == hook))
+            .collect()
+    }
+
+    // wasm calls are cpu bound, keep them off the async workers
+    async fn call(&self, path: PathBuf, module: Module, hook: &'static str, input: Vec<u8>) -> Option<WasmResult> {
+        let engine = self.engine.clone();
+        let linker = self.linker.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            match call_hook(&engine, &linker, &name, &module, hook, &input) {
+                Ok(Some(output)) => match serde_json::from_slice::<WasmResult>(&output) {
+                    Ok(result) => Some(result),
+                    Err(e) => {
+                        error!("wasm plugin {} returned an invalid result from {}: {}", path.display(), hook, e);
+                        None
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    error!("wasm plugin {} failed in {}: {:#}", path.display(), hook, e);
+                    None
+                }
+            }
+        }).await;
+        match result {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("wasm plugin runner crashed: {}", e);
+                None
+            }
+        }
+    }
+
+    fn recorded_request(&self, flow_id: &Option<String>) -> Option<RequestOrResponse> {
+        let storage = self.flow_storage.read().unwrap();
+        Some(storage.get_flow(flow_id.as_ref()?)?.content.http_pair().request.clone())
+    }
+
+    async fn run_hook(&self, ctx: &PluginContext, hook: &'static str, message: &mut RequestOrResponse) -> PluginAction {
+        let modules = self.modules_with(hook).await;
+        if modules.is_empty() {
+            return PluginAction::Continue;
+        }
+
+        // plugins see decoded bodies, same as scripts
+        let (decoded_edit, mut editable) = DecodedEdit::decode(message, ctx.max_body_size).await;
+        let request = match message.is_response {
+            true => match self.recorded_request(&ctx.flow_id) {
+                Some(request) => WasmMessage::from_message(&DecodedEdit::decode(&request, ctx.max_body_size).await.1).ok(),
+                None => None
+            },
+            false => None
+        };
+
+        let mut modified = false;
+        for (path, module) in modules {
+            let current = match WasmMessage::from_message(&editable) {
+                Ok(current) => current,
+                Err(e) => {
+                    warn!("skipping wasm plugins for {}, body unreadable: {}", hook, e);
+                    return PluginAction::Continue;
+                }
+            };
+            let flow = match message.is_response {
+                true => WasmFlow { id: ctx.flow_id.clone(), client_addr: ctx.client_addr.to_string(), request: request.clone(), response: Some(current) },
+                false => WasmFlow { id: ctx.flow_id.clone(), client_addr: ctx.client_addr.to_string(), request: Some(current), response: None }
+            };
+            let input = serde_json::to_vec(&flow).expect("flow serialization failed");
+            match self.call(path.clone(), module, hook, input).await {
+                None | Some(WasmResult::Continue) => {},
+                Some(WasmResult::Drop) => return PluginAction::Drop,
+                Some(WasmResult::Modified { message: edited }) => match edited.into_message() {
+                    Ok(edited) if edited.is_response == message.is_response => {
+                        editable = edited;
+                        modified = true;
+                    },
+                    Ok(_) => error!("wasm plugin {} changed the kind of message in {}, ignoring", path.display(), hook),
+                    Err(e) => error!("wasm plugin {} returned a bad message from {}: {}", path.display(), hook, e)
+                },
+                Some(WasmResult::Respond { message: response }) => match response.into_message() {
+                    Ok(mut response) if response.is_response => {
+                        response.fix_body_framing();
+                        return PluginAction::Respond(Box::new(response));
+                    },
+                    Ok(_) => error!("wasm plugin {} responded with a request in {}, ignoring", path.display(), hook),
+                    Err(e) => error!("wasm plugin {} returned a bad response from {}: {}", path.display(), hook, e)
+                }
+            }
+        }
+
+        if modified {
+            decoded_edit.encode(&mut editable).await;
+            *message = editable;
+            return PluginAction::Modified;
+        }
+        PluginAction::Continue
+    }
+}
+
+#[async_trait]
+impl Plugin for WasmPlugin {
+    fn name(&self) -> &str {
+        "wasm"
+    }
+
+    async fn on_request(&self, ctx: &PluginContext, request: &mut RequestOrResponse) -> PluginAction {
+        self.run_hook(ctx, "on_request", request).await
+    }
+
+    async fn on_response(&self, ctx: &PluginContext, response: &mut RequestOrResponse) -> PluginAction {
+        self.run_hook(ctx, "on_response", response).await
+    }
+
+    async fn on_flow_completed(&self, ctx: &PluginContext, flow: &Flow) {
+        let modules = self.modules_with("on_flow_completed").await;
+        if modules.is_empty() {
+            return;
+        }
+
+        let pair = flow.content.http_pair();
+        let request = match WasmMessage::from_message(&DecodedEdit::decode(&pair.request, ctx.max_body_size).await.1) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("skipping wasm plugins for on_flow_completed, body unreadable: {}", e);
+                return;
+            }
+        };
+        let response = match &pair.response {
+            Some(response) => WasmMessage::from_message(&DecodedEdit::decode(response, ctx.max_body_size).await.1).ok(),
+            None => None
+        };
+        let input = serde_json::to_vec(&WasmFlow {
+            id: Some(flow.get_id()),
+            client_addr: ctx.client_addr.to_string(),
+            request: Some(request),
+            response
+        }).expect("flow serialization failed");
+        for (path, module) in modules {
+            self.call(path, module, "on_flow_completed", input.clone()).await;
+        }
+    }
+}